@@ -1,3 +1,5 @@
+# Regenerate the Candid interface from the compiled canister.
+# Requires candid-extractor: cargo install candid-extractor
 cargo build --release --target wasm32-unknown-unknown --package green_gauge_backend
 
-candid-extractor target/wasm32-unknown-unknown/release/green_gauge_backend.wasm >src/green_gauge_backend/green_gauge_backend.did
\ No newline at end of file
+candid-extractor target/wasm32-unknown-unknown/release/green_gauge_backend.wasm >src/green_gauge_backend/green_gauge_backend.did
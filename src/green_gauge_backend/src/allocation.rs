@@ -0,0 +1,486 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::amount::{self, Co2e, Price, Rounding};
+use crate::auction;
+use crate::compliance::{self, Allocation, CompliancePeriod, PeriodStatus};
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::roles::caller_is_regulator;
+use crate::{
+    get_memory, impl_storable, init_counter, next_id, IdCounter, Memory, ALLOCATION_LOG_ID_MEMORY_ID,
+    ALLOCATION_LOG_MEMORY_ID, EMISSION_HISTORY, USERS,
+};
+
+pub const MAX_REASON_LENGTH: usize = 500;
+
+// How a free allocation is sized
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AllocationMethod {
+    // A fixed number of allowance units
+    Fixed { amount: u64 },
+    // A share of the participant's recorded emissions over a historical
+    // baseline window, usually as long as the period
+    Grandfathering {
+        baseline_start: u64,
+        baseline_end: u64,
+        percent: u8,
+    },
+    // A benchmark emission intensity in kg CO2e per unit of output times the
+    // participant's activity level in units of output
+    Benchmark {
+        kg_co2e_per_unit: f64,
+        activity_level: f64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AllocationChange {
+    CapSet {
+        cap: u64,
+        auction_share_percent: u8,
+    },
+    FreeAllocation {
+        method: AllocationMethod,
+        previous: u64,
+        allocated: u64,
+    },
+    Adjustment {
+        delta: i64,
+        reason: String,
+        allocated: u64,
+    },
+    Issued {
+        issued: u64,
+        withheld_for_borrowing: u64,
+    },
+    Auctioned {
+        auction_id: u64,
+        awarded: u64,
+        clearing_price: Price,
+    },
+}
+
+// One entry of the append-only allocation log
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllocationLogEntry {
+    id: u64,
+    period_id: u64,
+    // Absent for changes to the period as a whole
+    participant: Option<Principal>,
+    change: AllocationChange,
+    actor: Principal,
+    timestamp: u64,
+}
+
+// How a period's cap is split between free allocation and auctions
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllocationSummary {
+    period_id: u64,
+    cap: Option<u64>,
+    auction_volume: u64,
+    free_allocated: u64,
+    // Absent while the period has no cap
+    free_remaining: Option<u64>,
+    allocations: Vec<Allocation>,
+}
+
+impl_storable!(AllocationLogEntry);
+
+thread_local! {
+    static ALLOCATION_LOG: RefCell<StableBTreeMap<u64, AllocationLogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALLOCATION_LOG_MEMORY_ID)));
+    static ALLOCATION_LOG_ID: IdCounter = init_counter(ALLOCATION_LOG_ID_MEMORY_ID, 0);
+}
+
+pub fn log(period_id: u64, participant: Option<Principal>, change: AllocationChange) {
+    let id = next_id(&ALLOCATION_LOG_ID);
+    let entry = AllocationLogEntry {
+        id,
+        period_id,
+        participant,
+        change,
+        actor: caller(),
+        timestamp: ic_cdk::api::time(),
+    };
+    ALLOCATION_LOG.with(|log| log.borrow_mut().insert(id, entry));
+}
+
+// Units of the cap reserved for auctions
+pub fn auction_volume(period: &CompliancePeriod) -> u64 {
+    let share = period.auction_share_percent.unwrap_or(0) as u128;
+    (period.cap.unwrap_or(0) as u128 * share / 100) as u64
+}
+
+fn free_allocated(period_id: u64) -> Result<u64, GreenGaugeError> {
+    amount::sum(
+        "allocated",
+        compliance::allocations_of_period(period_id)
+            .iter()
+            .map(|allocation| allocation.allocated),
+    )
+}
+
+// Check that `allocated` more units fit under the free share of the cap
+fn ensure_within_cap(period: &CompliancePeriod, allocated: u64) -> Result<(), GreenGaugeError> {
+    let Some(cap) = period.cap else {
+        return Ok(());
+    };
+
+    let budget = cap - auction_volume(period);
+    let available = budget.saturating_sub(free_allocated(period.id)?);
+    if allocated > available {
+        return Err(GreenGaugeError::InsufficientAllowance {
+            required: allocated,
+            available,
+        });
+    }
+    Ok(())
+}
+
+fn ensure_not_closed(period: &CompliancePeriod) -> Result<(), GreenGaugeError> {
+    if period.status == PeriodStatus::Closed {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Compliance period {} is closed",
+            period.id
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_scheduled(period: &CompliancePeriod) -> Result<(), GreenGaugeError> {
+    if period.status != PeriodStatus::Scheduled {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Allocations of compliance period {} have already been issued",
+            period.id
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_participant(participant: Principal) -> Result<(), GreenGaugeError> {
+    if !USERS.with(|users| users.borrow().contains_key(&participant)) {
+        return Err(GreenGaugeError::not_found("user", participant));
+    }
+    Ok(())
+}
+
+fn allocation_of(period_id: u64, participant: Principal) -> Allocation {
+    compliance::get_allocation(period_id, participant).unwrap_or(Allocation {
+        period_id,
+        participant,
+        allocated: 0,
+        borrowed: 0,
+        issued: 0,
+        issued_at: None,
+    })
+}
+
+// Number of allowance units a method grants a participant
+fn size_allocation(participant: Principal, method: &AllocationMethod) -> Result<u64, GreenGaugeError> {
+    match *method {
+        AllocationMethod::Fixed { amount } => Ok(amount),
+        AllocationMethod::Grandfathering {
+            baseline_start,
+            baseline_end,
+            percent,
+        } => {
+            if baseline_start > baseline_end {
+                return Err(GreenGaugeError::invalid_input(
+                    "baseline_end",
+                    "must not be before baseline_start",
+                ));
+            }
+            if percent > 100 {
+                return Err(GreenGaugeError::invalid_input("percent", "must be at most 100"));
+            }
+
+            let baseline = EMISSION_HISTORY.with(|history| {
+                history
+                    .borrow()
+                    .values_range((participant, baseline_start)..=(participant, baseline_end))
+                    .fold(Co2e::ZERO, |total, point| total.saturating_add(point.grams()))
+            });
+            let granted = baseline
+                .scale(percent as u64, 100, Rounding::Down)
+                .expect("at most 100% of a mass fits");
+            Ok(granted.whole_kg(Rounding::Down))
+        }
+        AllocationMethod::Benchmark {
+            kg_co2e_per_unit,
+            activity_level,
+        } => {
+            if !kg_co2e_per_unit.is_finite() || kg_co2e_per_unit < 0.0 {
+                return Err(GreenGaugeError::invalid_input(
+                    "kg_co2e_per_unit",
+                    "must be a non-negative number",
+                ));
+            }
+            if !activity_level.is_finite() || activity_level < 0.0 {
+                return Err(GreenGaugeError::invalid_input(
+                    "activity_level",
+                    "must be a non-negative number",
+                ));
+            }
+
+            amount::to_fixed(kg_co2e_per_unit * activity_level, 1, Rounding::Down)
+                .ok_or_else(|| GreenGaugeError::invalid_input("activity_level", "allocation is too large"))
+        }
+    }
+}
+
+// Set the cap of a scheduled period and the share of it that is auctioned
+// (regulator only)
+#[update(guard = "caller_is_regulator")]
+fn set_period_cap(period_id: u64, cap: u64, auction_share_percent: u8) -> Result<CompliancePeriod, GreenGaugeError> {
+    let mut period = compliance::get_period(period_id)?;
+    ensure_scheduled(&period)?;
+
+    if auction_share_percent > 100 {
+        return Err(GreenGaugeError::invalid_input(
+            "auction_share_percent",
+            "must be at most 100",
+        ));
+    }
+
+    period.cap = Some(cap);
+    period.auction_share_percent = Some(auction_share_percent);
+    let free_budget = cap - auction_volume(&period);
+    let allocated = free_allocated(period_id)?;
+    if allocated > free_budget {
+        return Err(GreenGaugeError::invalid_input(
+            "cap",
+            format!(
+                "{} units are already allocated for free, more than the {} left after auctions",
+                allocated, free_budget
+            ),
+        ));
+    }
+
+    let auctioned = auction::auctioned_in_period(period_id)?;
+    if auctioned > auction_volume(&period) {
+        return Err(GreenGaugeError::invalid_input(
+            "auction_share_percent",
+            format!("{} units are already offered at auction", auctioned),
+        ));
+    }
+
+    compliance::save_period(&period);
+    log(
+        period_id,
+        None,
+        AllocationChange::CapSet {
+            cap,
+            auction_share_percent,
+        },
+    );
+    Ok(period)
+}
+
+// Allocate allowances for free to a participant of a scheduled period,
+// replacing any earlier free allocation (regulator only)
+#[update(guard = "caller_is_regulator")]
+fn allocate_free(
+    period_id: u64,
+    participant: Principal,
+    method: AllocationMethod,
+) -> Result<Allocation, GreenGaugeError> {
+    let period = compliance::get_period(period_id)?;
+    ensure_scheduled(&period)?;
+    ensure_participant(participant)?;
+
+    let amount = size_allocation(participant, &method)?;
+    let mut allocation = allocation_of(period_id, participant);
+    let previous = allocation.allocated;
+    ensure_within_cap(&period, amount.saturating_sub(previous))?;
+
+    allocation.allocated = amount;
+    compliance::save_allocation(&allocation);
+    log(
+        period_id,
+        Some(participant),
+        AllocationChange::FreeAllocation {
+            method,
+            previous,
+            allocated: amount,
+        },
+    );
+    Ok(allocation)
+}
+
+// Set fixed free allocations for many participants of a scheduled period at
+// once (regulator only). Listing a participant again replaces their
+// allocation.
+#[update(guard = "caller_is_regulator")]
+fn set_allocation_schedule(
+    period_id: u64,
+    schedule: Vec<(Principal, u64)>,
+) -> Result<Vec<Allocation>, GreenGaugeError> {
+    let period = compliance::get_period(period_id)?;
+    ensure_scheduled(&period)?;
+
+    for (i, (participant, _)) in schedule.iter().enumerate() {
+        ensure_participant(*participant)?;
+        if schedule[..i].iter().any(|(earlier, _)| earlier == participant) {
+            return Err(GreenGaugeError::invalid_input(
+                "schedule",
+                format!("{} is listed more than once", participant),
+            ));
+        }
+    }
+
+    let increase = amount::sum(
+        "allocated",
+        schedule.iter().map(|(participant, amount)| {
+            amount.saturating_sub(allocation_of(period_id, *participant).allocated)
+        }),
+    )?;
+    let decrease = amount::sum(
+        "allocated",
+        schedule.iter().map(|(participant, amount)| {
+            allocation_of(period_id, *participant).allocated.saturating_sub(*amount)
+        }),
+    )?;
+    ensure_within_cap(&period, increase.saturating_sub(decrease))?;
+
+    let allocations = schedule
+        .into_iter()
+        .map(|(participant, amount)| {
+            let mut allocation = allocation_of(period_id, participant);
+            let previous = allocation.allocated;
+            allocation.allocated = amount;
+            compliance::save_allocation(&allocation);
+            log(
+                period_id,
+                Some(participant),
+                AllocationChange::FreeAllocation {
+                    method: AllocationMethod::Fixed { amount },
+                    previous,
+                    allocated: amount,
+                },
+            );
+            allocation
+        })
+        .collect();
+
+    Ok(allocations)
+}
+
+// Raise or lower a participant's allocation, e.g. after a plant closure or a
+// corrected baseline (regulator only). Once the period is open the change
+// is also applied to the participant's held allowances.
+#[update(guard = "caller_is_regulator")]
+fn adjust_allocation(
+    period_id: u64,
+    participant: Principal,
+    delta: i64,
+    reason: String,
+) -> Result<Allocation, GreenGaugeError> {
+    let period = compliance::get_period(period_id)?;
+    ensure_not_closed(&period)?;
+    ensure_participant(participant)?;
+
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(GreenGaugeError::invalid_input(
+            "reason",
+            format!("must be between 1 and {} bytes long", MAX_REASON_LENGTH),
+        ));
+    }
+
+    if delta == 0 {
+        return Err(GreenGaugeError::invalid_input("delta", "must not be zero"));
+    }
+
+    let mut allocation = allocation_of(period_id, participant);
+    let magnitude = delta.unsigned_abs();
+    let issued = allocation.issued_at.is_some();
+
+    let mut profile = USERS
+        .with(|users| users.borrow().get(&participant))
+        .ok_or_else(|| GreenGaugeError::not_found("user", participant))?;
+
+    if delta > 0 {
+        ensure_within_cap(&period, magnitude)?;
+        allocation.allocated = amount::add("allocated", allocation.allocated, magnitude)?;
+        if issued {
+            allocation.issued = amount::add("issued", allocation.issued, magnitude)?;
+            profile.credit_allowance(magnitude)?;
+        }
+    } else {
+        if magnitude > allocation.allocated {
+            return Err(GreenGaugeError::InsufficientAllowance {
+                required: magnitude,
+                available: allocation.allocated,
+            });
+        }
+        if issued && magnitude > profile.carbon_allowance.min(allocation.issued) {
+            return Err(GreenGaugeError::InsufficientAllowance {
+                required: magnitude,
+                available: profile.carbon_allowance.min(allocation.issued),
+            });
+        }
+
+        allocation.allocated -= magnitude;
+        if issued {
+            allocation.issued -= magnitude;
+            profile.debit_allowance(magnitude)?;
+        }
+    }
+
+    if issued {
+        USERS.with(|users| users.borrow_mut().insert(participant, profile));
+        let (from, to) = if delta > 0 {
+            (JournalAccount::AllowanceIssuer, JournalAccount::Allowance(participant))
+        } else {
+            (JournalAccount::Allowance(participant), JournalAccount::AllowanceIssuer)
+        };
+        let cause = Cause::new(Reason::Allocation, Some(Reference::CompliancePeriod(period_id)));
+        journal::transfer(cause, from, to, magnitude);
+    }
+    compliance::save_allocation(&allocation);
+    log(
+        period_id,
+        Some(participant),
+        AllocationChange::Adjustment {
+            delta,
+            reason,
+            allocated: allocation.allocated,
+        },
+    );
+    Ok(allocation)
+}
+
+// Show how a period's cap is allocated
+#[query]
+fn get_period_allocations(period_id: u64) -> Result<AllocationSummary, GreenGaugeError> {
+    let period = compliance::get_period(period_id)?;
+    let allocations = compliance::allocations_of_period(period_id);
+    let free_allocated = amount::sum("allocated", allocations.iter().map(|allocation| allocation.allocated))?;
+    let auction_volume = auction_volume(&period);
+
+    Ok(AllocationSummary {
+        period_id,
+        cap: period.cap,
+        auction_volume,
+        free_allocated,
+        free_remaining: period
+            .cap
+            .map(|cap| (cap - auction_volume).saturating_sub(free_allocated)),
+        allocations,
+    })
+}
+
+// Read the allocation log, oldest entry first, optionally for one period.
+// Allocations are public so that the market can verify the cap.
+#[query]
+fn get_allocation_log(period_id: Option<u64>) -> Vec<AllocationLogEntry> {
+    ALLOCATION_LOG.with(|log| {
+        log.borrow()
+            .values()
+            .filter(|entry| period_id.is_none_or(|period_id| entry.period_id == period_id))
+            .collect()
+    })
+}
@@ -0,0 +1,364 @@
+use candid::{CandidType, Deserialize};
+
+use crate::error::GreenGaugeError;
+
+// Fixed-point amounts for carbon and money. CO2e is counted in whole grams
+// and GG tokens in the ledger's smallest unit; floats only appear where
+// readings and legacy fields cross the API. Every operation is checked and
+// rounds in the direction its caller names.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    // Towards zero
+    Down,
+    // Away from zero
+    Up,
+    // To the nearest value, halves away from zero
+    HalfUp,
+}
+
+// `value * numerator / denominator`, rounded. None on overflow or a zero
+// denominator.
+pub fn mul_div(value: u64, numerator: u64, denominator: u64, rounding: Rounding) -> Option<u64> {
+    if denominator == 0 {
+        return None;
+    }
+    let product = value as u128 * numerator as u128;
+    let denominator = denominator as u128;
+    let quotient = product / denominator;
+    let remainder = product % denominator;
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder > 0,
+        Rounding::HalfUp => remainder * 2 >= denominator,
+    };
+    u64::try_from(quotient + round_up as u128).ok()
+}
+
+// `value * scale` as an integer, rounded. None unless `value` is finite,
+// non-negative and the result fits.
+pub fn to_fixed(value: f64, scale: u64, rounding: Rounding) -> Option<u64> {
+    if !value.is_finite() || value < 0.0 {
+        return None;
+    }
+    let scaled = value * scale as f64;
+    let rounded = match rounding {
+        Rounding::Down => scaled.floor(),
+        Rounding::Up => scaled.ceil(),
+        Rounding::HalfUp => scaled.round(),
+    };
+    // u64::MAX is not exactly representable; its f64 neighbour is 2^64
+    if rounded >= u64::MAX as f64 {
+        return None;
+    }
+    Some(rounded as u64)
+}
+
+// Checked arithmetic for balance mutations. `field` names the balance in the
+// error, so that callers can compute every new value with `?` before they
+// write any of them.
+pub fn add(field: &str, balance: u64, amount: u64) -> Result<u64, GreenGaugeError> {
+    balance
+        .checked_add(amount)
+        .ok_or_else(|| GreenGaugeError::overflow(field))
+}
+
+pub fn sub(field: &str, balance: u64, amount: u64) -> Result<u64, GreenGaugeError> {
+    balance
+        .checked_sub(amount)
+        .ok_or_else(|| GreenGaugeError::underflow(field))
+}
+
+pub fn mul(field: &str, value: u64, factor: u64) -> Result<u64, GreenGaugeError> {
+    value
+        .checked_mul(factor)
+        .ok_or_else(|| GreenGaugeError::overflow(field))
+}
+
+pub fn sum(field: &str, amounts: impl IntoIterator<Item = u64>) -> Result<u64, GreenGaugeError> {
+    amounts
+        .into_iter()
+        .try_fold(0, |total, amount| add(field, total, amount))
+}
+
+// A legacy floating-point count of whole units
+pub fn whole_units(value: f64, field: &str) -> Result<u64, GreenGaugeError> {
+    match to_fixed(value, 1, Rounding::Down) {
+        Some(units) if value.fract() == 0.0 => Ok(units),
+        _ => Err(GreenGaugeError::invalid_input(field, "must be a whole number")),
+    }
+}
+
+// A mass of CO2-equivalent in grams
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Co2e(u64);
+
+impl Co2e {
+    pub const ZERO: Co2e = Co2e(0);
+    pub const GRAMS_PER_KG: u64 = 1000;
+
+    pub const fn from_grams(grams: u64) -> Self {
+        Co2e(grams)
+    }
+
+    pub fn grams(self) -> u64 {
+        self.0
+    }
+
+    // Whole kilograms, e.g. allowance units
+    pub fn from_kg(kg: u64) -> Option<Self> {
+        kg.checked_mul(Self::GRAMS_PER_KG).map(Co2e)
+    }
+
+    pub fn from_kg_f64(kg: f64, rounding: Rounding) -> Option<Self> {
+        to_fixed(kg, Self::GRAMS_PER_KG, rounding).map(Co2e)
+    }
+
+    // The mass in whole kilograms, i.e. allowance units
+    pub fn whole_kg(self, rounding: Rounding) -> u64 {
+        mul_div(self.0, 1, Self::GRAMS_PER_KG, rounding).expect("dividing cannot overflow")
+    }
+
+    // For reporting fields that predate fixed-point amounts
+    pub fn as_kg_f64(self) -> f64 {
+        self.0 as f64 / Self::GRAMS_PER_KG as f64
+    }
+
+    pub fn checked_add(self, other: Co2e) -> Option<Self> {
+        self.0.checked_add(other.0).map(Co2e)
+    }
+
+    pub fn checked_sub(self, other: Co2e) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Co2e)
+    }
+
+    pub fn saturating_add(self, other: Co2e) -> Self {
+        Co2e(self.0.saturating_add(other.0))
+    }
+
+    // `self * numerator / denominator`, rounded
+    pub fn scale(self, numerator: u64, denominator: u64, rounding: Rounding) -> Option<Self> {
+        mul_div(self.0, numerator, denominator, rounding).map(Co2e)
+    }
+
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Co2e>) -> Option<Self> {
+        amounts
+            .into_iter()
+            .try_fold(Co2e::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+// Emissions per unit of consumption, e.g. per kWh, in milligrams CO2e
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Intensity(u64);
+
+impl Intensity {
+    pub const ZERO: Intensity = Intensity(0);
+    pub const MG_PER_KG: u64 = 1_000_000;
+
+    pub const fn from_mg(mg: u64) -> Self {
+        Intensity(mg)
+    }
+
+    pub fn from_kg_f64(kg: f64, rounding: Rounding) -> Option<Self> {
+        to_fixed(kg, Self::MG_PER_KG, rounding).map(Intensity)
+    }
+
+    // The intensity per `units` of the current unit, e.g. per MWh from per kWh
+    pub fn checked_mul(self, units: u64) -> Option<Self> {
+        self.0.checked_mul(units).map(Intensity)
+    }
+
+    // Emissions of a consumption given in thousandths of a unit, to the
+    // nearest gram
+    pub fn emissions_of(self, milli_units: u64) -> Option<Co2e> {
+        mul_div(milli_units, self.0, Self::MG_PER_KG, Rounding::HalfUp).map(Co2e)
+    }
+}
+
+// The price of one allowance unit (1 kg CO2e) in the smallest unit of the
+// GG token
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Price(u64);
+
+impl Price {
+    pub const ZERO: Price = Price(0);
+
+    pub fn new(tokens_per_unit: u64) -> Self {
+        Price(tokens_per_unit)
+    }
+
+    pub fn tokens_per_unit(self) -> u64 {
+        self.0
+    }
+
+    // Tokens owed for `units` whole allowance units
+    pub fn cost_of(self, units: u64) -> Result<u64, GreenGaugeError> {
+        mul("total_cost", units, self.0)
+    }
+
+    pub fn checked_sub(self, other: Price) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Price)
+    }
+}
+
+// A proportion in basis points, one hundredth of a percent
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate(u16);
+
+impl Rate {
+    pub const BASIS_POINTS: u64 = 10_000;
+
+    pub const fn from_bps(bps: u16) -> Self {
+        assert!(bps as u64 <= Self::BASIS_POINTS, "a rate is at most 100%");
+        Rate(bps)
+    }
+
+    // This share of `amount`, rounded
+    pub fn apply(self, amount: u64, rounding: Rounding) -> u64 {
+        mul_div(amount, self.0 as u64, Self::BASIS_POINTS, rounding)
+            .expect("a rate of at most 100% cannot overflow")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn rounding() -> impl Strategy<Value = Rounding> {
+        prop_oneof![Just(Rounding::Down), Just(Rounding::Up), Just(Rounding::HalfUp)]
+    }
+
+    proptest! {
+        #[test]
+        fn add_fails_exactly_on_overflow(a: u64, b: u64) {
+            match add("balance", a, b) {
+                Ok(total) => prop_assert_eq!(total as u128, a as u128 + b as u128),
+                Err(error) => {
+                    prop_assert!(a as u128 + b as u128 > u64::MAX as u128);
+                    prop_assert_eq!(error, GreenGaugeError::overflow("balance"));
+                }
+            }
+        }
+
+        #[test]
+        fn sub_fails_exactly_on_underflow(a: u64, b: u64) {
+            match sub("balance", a, b) {
+                Ok(difference) => prop_assert_eq!(difference + b, a),
+                Err(error) => {
+                    prop_assert!(b > a);
+                    prop_assert_eq!(error, GreenGaugeError::underflow("balance"));
+                }
+            }
+        }
+
+        #[test]
+        fn mul_fails_exactly_on_overflow(a: u64, b: u64) {
+            let product = a as u128 * b as u128;
+            match mul("total_cost", a, b) {
+                Ok(result) => prop_assert_eq!(result as u128, product),
+                Err(_) => prop_assert!(product > u64::MAX as u128),
+            }
+        }
+
+        #[test]
+        fn sum_fails_exactly_on_overflow(amounts in prop::collection::vec(any::<u64>(), 0..8)) {
+            let total: u128 = amounts.iter().map(|&amount| amount as u128).sum();
+            match sum("demand", amounts) {
+                Ok(result) => prop_assert_eq!(result as u128, total),
+                Err(_) => prop_assert!(total > u64::MAX as u128),
+            }
+        }
+
+        #[test]
+        fn mul_div_rounds_in_the_named_direction(value: u64, numerator: u64, denominator in 1..=u64::MAX) {
+            let exact = value as u128 * numerator as u128;
+            let denominator_wide = denominator as u128;
+            let down = mul_div(value, numerator, denominator, Rounding::Down);
+            prop_assert_eq!(down.map(u128::from), Some(exact / denominator_wide).filter(|q| *q <= u64::MAX as u128));
+
+            if let (Some(down), Some(half_up), Some(up)) = (
+                down,
+                mul_div(value, numerator, denominator, Rounding::HalfUp),
+                mul_div(value, numerator, denominator, Rounding::Up),
+            ) {
+                prop_assert!(down <= half_up && half_up <= up);
+                prop_assert!(up - down <= 1);
+                prop_assert_eq!(up == down, exact.is_multiple_of(denominator_wide));
+            }
+        }
+
+        #[test]
+        fn mul_div_never_panics(value: u64, numerator: u64, denominator: u64, rounding in rounding()) {
+            let result = mul_div(value, numerator, denominator, rounding);
+            if denominator == 0 {
+                prop_assert_eq!(result, None);
+            }
+        }
+
+        #[test]
+        fn to_fixed_brackets_the_exact_value(value in 0.0..1e12f64, scale in 1..=1_000_000u64) {
+            let down = to_fixed(value, scale, Rounding::Down).unwrap();
+            let up = to_fixed(value, scale, Rounding::Up).unwrap();
+            let half_up = to_fixed(value, scale, Rounding::HalfUp).unwrap();
+            prop_assert!(down <= half_up && half_up <= up);
+            prop_assert!(up - down <= 1);
+        }
+
+        #[test]
+        fn to_fixed_rejects_what_a_u64_cannot_hold(value: f64, scale: u64, rounding in rounding()) {
+            if let Some(fixed) = to_fixed(value, scale, rounding) {
+                prop_assert!(value.is_finite() && value >= 0.0);
+                prop_assert!((fixed as f64) <= value * scale as f64 + 1.0);
+            }
+        }
+
+        #[test]
+        fn whole_kg_round_trips(kg in 0..=u64::MAX / Co2e::GRAMS_PER_KG, rounding in rounding()) {
+            let mass = Co2e::from_kg(kg).unwrap();
+            prop_assert_eq!(mass.whole_kg(rounding), kg);
+        }
+
+        #[test]
+        fn whole_kg_brackets_the_mass(grams: u64) {
+            let mass = Co2e::from_grams(grams);
+            let down = mass.whole_kg(Rounding::Down) as u128 * Co2e::GRAMS_PER_KG as u128;
+            let up = mass.whole_kg(Rounding::Up) as u128 * Co2e::GRAMS_PER_KG as u128;
+            prop_assert!(down <= grams as u128 && grams as u128 <= up);
+            prop_assert!(up - down <= Co2e::GRAMS_PER_KG as u128);
+        }
+
+        #[test]
+        fn rate_takes_at_most_the_amount(bps in 0..=Rate::BASIS_POINTS as u16, amount: u64, rounding in rounding()) {
+            prop_assert!(Rate::from_bps(bps).apply(amount, rounding) <= amount);
+        }
+
+        #[test]
+        fn commission_and_proceeds_add_up(bps in 0..=Rate::BASIS_POINTS as u16, total: u64) {
+            let commission = Rate::from_bps(bps).apply(total, Rounding::Down);
+            let proceeds = sub("seller_proceeds", total, commission).unwrap();
+            prop_assert_eq!(commission + proceeds, total);
+        }
+
+        #[test]
+        fn emissions_are_consumption_times_intensity(milli_units in 0..=1_000_000_000u64, mg in 0..=10_000_000u64) {
+            let exact = milli_units as u128 * mg as u128;
+            let grams = Intensity::from_mg(mg).emissions_of(milli_units).unwrap().grams() as u128;
+            // Thousandths of a unit times mg per unit are nanograms
+            prop_assert!(grams * 1_000_000 <= exact + 500_000);
+            prop_assert!(exact < grams * 1_000_000 + 500_000);
+        }
+
+        #[test]
+        fn cost_fails_exactly_on_overflow(price: u64, units: u64) {
+            match Price::new(price).cost_of(units) {
+                Ok(cost) => prop_assert_eq!(cost as u128, price as u128 * units as u128),
+                Err(error) => {
+                    prop_assert!(price as u128 * units as u128 > u64::MAX as u128);
+                    prop_assert_eq!(error, GreenGaugeError::overflow("total_cost"));
+                }
+            }
+        }
+    }
+}
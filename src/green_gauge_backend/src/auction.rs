@@ -0,0 +1,555 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::allocation::{self, AllocationChange};
+use crate::amount::{self, Price};
+use crate::compliance::{self, PeriodStatus};
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::ledger::{self, Account};
+use crate::roles::caller_is_regulator;
+use crate::{
+    ensure_registered, get_memory, impl_storable, init_counter, next_id, IdCounter, Memory,
+    AUCTIONS_MEMORY_ID, AUCTION_BIDS_MEMORY_ID, AUCTION_ID_MEMORY_ID, USERS,
+};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuctionStatus {
+    Open,
+    Settled,
+    Cancelled,
+}
+
+// A primary-market auction of allowances. Bids are sealed until the auction
+// is settled, and every winner pays the same clearing price.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Auction {
+    id: u64,
+    // Compliance period whose auction share the volume is drawn from
+    period_id: Option<u64>,
+    volume: u64,
+    // Minimum price per allowance unit in GG tokens
+    reserve_price: Price,
+    opens_at: u64,
+    closes_at: u64,
+    status: AuctionStatus,
+    created_by: Principal,
+    created_at: u64,
+    result: Option<AuctionResult>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuctionInput {
+    period_id: Option<u64>,
+    volume: u64,
+    reserve_price: Price,
+    opens_at: u64,
+    closes_at: u64,
+}
+
+// A sealed bid. One bid per bidder and auction; bidding again replaces it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Bid {
+    auction_id: u64,
+    bidder: Principal,
+    quantity: u64,
+    // Highest price per unit the bidder will pay
+    price: Price,
+    // Tokens locked in escrow, `quantity * price`
+    escrowed: u64,
+    submitted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuctionAward {
+    bidder: Principal,
+    quantity: u64,
+    price: Price,
+    awarded: u64,
+    paid: u64,
+    refunded: u64,
+}
+
+// Published once an auction is settled
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuctionResult {
+    // Absent when no bid was received
+    clearing_price: Option<Price>,
+    sold: u64,
+    unsold: u64,
+    bids_received: u64,
+    // Units bid for in total
+    demand: u64,
+    awards: Vec<AuctionAward>,
+    settled_at: u64,
+}
+
+impl_storable!(Auction, Bid);
+
+thread_local! {
+    static AUCTIONS: RefCell<StableBTreeMap<u64, Auction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUCTIONS_MEMORY_ID)));
+    static AUCTION_ID: IdCounter = init_counter(AUCTION_ID_MEMORY_ID, 1);
+
+    // Keyed by (auction id, bidder)
+    static AUCTION_BIDS: RefCell<StableBTreeMap<(u64, Principal), Bid, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUCTION_BIDS_MEMORY_ID)));
+}
+
+fn get_auction(auction_id: u64) -> Result<Auction, GreenGaugeError> {
+    AUCTIONS
+        .with(|auctions| auctions.borrow().get(&auction_id))
+        .ok_or_else(|| GreenGaugeError::not_found("auction", auction_id))
+}
+
+fn save_auction(auction: &Auction) {
+    AUCTIONS.with(|auctions| auctions.borrow_mut().insert(auction.id, auction.clone()));
+}
+
+fn bids_of(auction_id: u64) -> Vec<Bid> {
+    AUCTION_BIDS.with(|bids| {
+        bids.borrow()
+            .range((auction_id, Principal::management_canister())..)
+            .take_while(|((id, _), _)| *id == auction_id)
+            .map(|(_, bid)| bid)
+            .collect()
+    })
+}
+
+fn get_bid(auction_id: u64, bidder: Principal) -> Option<Bid> {
+    AUCTION_BIDS.with(|bids| bids.borrow().get(&(auction_id, bidder)))
+}
+
+fn remove_bid(auction_id: u64, bidder: Principal) {
+    AUCTION_BIDS.with(|bids| bids.borrow_mut().remove(&(auction_id, bidder)));
+}
+
+fn cause(reason: Reason, auction_id: u64) -> Cause {
+    Cause::new(reason, Some(Reference::Auction(auction_id)))
+}
+
+// Units already offered by the live and settled auctions of a period
+pub fn auctioned_in_period(period_id: u64) -> Result<u64, GreenGaugeError> {
+    AUCTIONS.with(|auctions| {
+        amount::sum(
+            "volume",
+            auctions
+                .borrow()
+                .values()
+                .filter(|auction| {
+                    auction.period_id == Some(period_id) && auction.status != AuctionStatus::Cancelled
+                })
+                .map(|auction| auction.volume),
+        )
+    })
+}
+
+fn ensure_accepting_bids(auction: &Auction, now: u64) -> Result<(), GreenGaugeError> {
+    if auction.status != AuctionStatus::Open || now < auction.opens_at || now >= auction.closes_at {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Auction {} is not accepting bids",
+            auction.id
+        )));
+    }
+    Ok(())
+}
+
+// Rank bids by price, highest first, and fill the volume in that order.
+// Equal prices are served in order of submission. The last bid that receives
+// any units sets the clearing price.
+fn clear(volume: u64, mut bids: Vec<Bid>) -> (Option<Price>, Vec<AuctionAward>) {
+    bids.sort_by(|a, b| {
+        b.price
+            .cmp(&a.price)
+            .then(a.submitted_at.cmp(&b.submitted_at))
+    });
+
+    let mut remaining = volume;
+    let mut clearing_price = None;
+    let mut awarded_units = Vec::with_capacity(bids.len());
+    for bid in &bids {
+        let awarded = bid.quantity.min(remaining);
+        remaining -= awarded;
+        if awarded > 0 {
+            clearing_price = Some(bid.price);
+        }
+        awarded_units.push(awarded);
+    }
+
+    let price = clearing_price.unwrap_or(Price::ZERO);
+    let awards = bids
+        .into_iter()
+        .zip(awarded_units)
+        .map(|(bid, awarded)| {
+            // The clearing price is at most the bid price, so this is
+            // covered by the bid's escrow
+            let paid = price
+                .cost_of(awarded)
+                .expect("awarded units are covered by the escrow");
+            AuctionAward {
+                bidder: bid.bidder,
+                quantity: bid.quantity,
+                price: bid.price,
+                awarded,
+                paid,
+                refunded: bid.escrowed - paid,
+            }
+        })
+        .collect();
+
+    (clearing_price, awards)
+}
+
+// Open an auction of allowances (regulator only). An auction tied to a
+// compliance period may only sell that period's auction share of the cap.
+#[update(guard = "caller_is_regulator")]
+fn open_auction(input: AuctionInput) -> Result<Auction, GreenGaugeError> {
+    let now = ic_cdk::api::time();
+
+    if input.volume == 0 {
+        return Err(GreenGaugeError::invalid_input("volume", "must be greater than zero"));
+    }
+
+    if input.opens_at >= input.closes_at {
+        return Err(GreenGaugeError::invalid_input("closes_at", "must be after opens_at"));
+    }
+
+    if input.closes_at <= now {
+        return Err(GreenGaugeError::invalid_input("closes_at", "must be in the future"));
+    }
+
+    if let Some(period_id) = input.period_id {
+        let period = compliance::get_period(period_id)?;
+        if period.status == PeriodStatus::Closed {
+            return Err(GreenGaugeError::invalid_state(format!(
+                "Compliance period {} is closed",
+                period_id
+            )));
+        }
+
+        let available = allocation::auction_volume(&period).saturating_sub(auctioned_in_period(period_id)?);
+        if input.volume > available {
+            return Err(GreenGaugeError::InsufficientAllowance {
+                required: input.volume,
+                available,
+            });
+        }
+    }
+
+    let auction = Auction {
+        id: next_id(&AUCTION_ID),
+        period_id: input.period_id,
+        volume: input.volume,
+        reserve_price: input.reserve_price,
+        opens_at: input.opens_at,
+        closes_at: input.closes_at,
+        status: AuctionStatus::Open,
+        created_by: caller(),
+        created_at: now,
+        result: None,
+    };
+    save_auction(&auction);
+
+    Ok(auction)
+}
+
+// Submit or replace the caller's sealed bid. The full bid value is moved
+// into escrow; a replaced bid is refunded first.
+#[update]
+fn submit_bid(auction_id: u64, quantity: u64, price: Price) -> Result<Bid, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    let auction = get_auction(auction_id)?;
+    let now = ic_cdk::api::time();
+    ensure_accepting_bids(&auction, now)?;
+
+    if quantity == 0 || quantity > auction.volume {
+        return Err(GreenGaugeError::invalid_input(
+            "quantity",
+            format!("must be between 1 and {}", auction.volume),
+        ));
+    }
+
+    if price < auction.reserve_price {
+        return Err(GreenGaugeError::invalid_input(
+            "price",
+            format!(
+                "must be at least the reserve price of {}",
+                auction.reserve_price.tokens_per_unit()
+            ),
+        ));
+    }
+
+    let escrowed = price.cost_of(quantity)?;
+
+    let previous = get_bid(auction_id, caller);
+    let refundable = previous.as_ref().map_or(0, |bid| bid.escrowed);
+    let available = amount::add("available", ledger::balance_of(caller), refundable)?;
+    if available < escrowed {
+        return Err(GreenGaugeError::InsufficientTokens {
+            required: escrowed,
+            available,
+        });
+    }
+
+    // Nothing below this point can fail
+    if refundable > 0 {
+        ledger::release_escrow(Account::of(caller), refundable, cause(Reason::AuctionRefund, auction_id));
+    }
+    ledger::transfer_internal(caller, ledger::escrow_account(), escrowed, cause(Reason::AuctionEscrow, auction_id));
+
+    let bid = Bid {
+        auction_id,
+        bidder: caller,
+        quantity,
+        price,
+        escrowed,
+        submitted_at: now,
+    };
+    AUCTION_BIDS.with(|bids| bids.borrow_mut().insert((auction_id, caller), bid.clone()));
+
+    Ok(bid)
+}
+
+// Withdraw the caller's bid while the auction is accepting bids
+#[update]
+fn cancel_bid(auction_id: u64) -> Result<Bid, GreenGaugeError> {
+    let caller = caller();
+    let auction = get_auction(auction_id)?;
+    ensure_accepting_bids(&auction, ic_cdk::api::time())?;
+
+    let bid = get_bid(auction_id, caller)
+        .ok_or_else(|| GreenGaugeError::not_found("bid", auction_id))?;
+
+    remove_bid(auction_id, caller);
+    ledger::release_escrow(Account::of(caller), bid.escrowed, cause(Reason::AuctionRefund, auction_id));
+
+    Ok(bid)
+}
+
+// The first open auction after `after` whose bidding has closed by `now`
+pub fn next_closed(after: Option<u64>, now: u64) -> Option<u64> {
+    let start = after.map_or(0, |after| after.saturating_add(1));
+    AUCTIONS.with(|auctions| {
+        auctions
+            .borrow()
+            .range(start..)
+            .find(|(_, auction)| auction.status == AuctionStatus::Open && auction.closes_at <= now)
+            .map(|(id, _)| id)
+    })
+}
+
+// Settle an auction once it has closed: winners receive their allowances and
+// pay the clearing price, the rest of every escrow is refunded and the
+// proceeds go to the treasury. Closed auctions are settled on schedule.
+pub fn settle(auction_id: u64, now: u64) -> Result<AuctionResult, GreenGaugeError> {
+    let mut auction = get_auction(auction_id)?;
+
+    if auction.status != AuctionStatus::Open {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Auction {} has already been {:?}",
+            auction_id, auction.status
+        )));
+    }
+
+    if now < auction.closes_at {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Auction {} is still accepting bids",
+            auction_id
+        )));
+    }
+
+    let bids = bids_of(auction_id);
+    let bids_received = bids.len() as u64;
+    let demand = amount::sum("demand", bids.iter().map(|bid| bid.quantity))?;
+    // Bidders who are no longer registered cannot hold allowances, so their
+    // bids take no part in clearing and are refunded in full
+    let (bids, withdrawn): (Vec<Bid>, Vec<Bid>) = bids
+        .into_iter()
+        .partition(|bid| USERS.with(|users| users.borrow().contains_key(&bid.bidder)));
+    let (clearing_price, mut awards) = clear(auction.volume, bids);
+    awards.extend(withdrawn.into_iter().map(|bid| AuctionAward {
+        bidder: bid.bidder,
+        quantity: bid.quantity,
+        price: bid.price,
+        awarded: 0,
+        paid: 0,
+        refunded: bid.escrowed,
+    }));
+    let sold = amount::sum("sold", awards.iter().map(|award| award.awarded))?;
+    let proceeds = amount::sum("proceeds", awards.iter().map(|award| award.paid))?;
+
+    let winners = USERS.with(|users| {
+        let users_map = users.borrow();
+        let mut winners = Vec::new();
+        for award in awards.iter().filter(|award| award.awarded > 0) {
+            let mut profile = users_map
+                .get(&award.bidder)
+                .ok_or_else(|| GreenGaugeError::not_found("user", award.bidder))?;
+            profile.credit_allowance(award.awarded)?;
+            winners.push((award.bidder, profile));
+        }
+        Ok::<_, GreenGaugeError>(winners)
+    })?;
+
+    // Nothing below this point can fail
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        for (bidder, profile) in winners {
+            users_map.insert(bidder, profile);
+        }
+    });
+    for award in awards.iter().filter(|award| award.awarded > 0) {
+        journal::transfer(
+            cause(Reason::AuctionAward, auction_id),
+            JournalAccount::AllowanceIssuer,
+            JournalAccount::Allowance(award.bidder),
+            award.awarded,
+        );
+    }
+
+    for award in &awards {
+        if let Some(period_id) = auction.period_id.filter(|_| award.awarded > 0) {
+            allocation::log(
+                period_id,
+                Some(award.bidder),
+                AllocationChange::Auctioned {
+                    auction_id,
+                    awarded: award.awarded,
+                    clearing_price: clearing_price.unwrap_or(Price::ZERO),
+                },
+            );
+        }
+
+        if award.refunded > 0 {
+            ledger::release_escrow(
+                Account::of(award.bidder),
+                award.refunded,
+                cause(Reason::AuctionRefund, auction_id),
+            );
+        }
+        remove_bid(auction_id, award.bidder);
+    }
+
+    if proceeds > 0 {
+        ledger::release_escrow(ledger::treasury_account(), proceeds, cause(Reason::AuctionAward, auction_id));
+    }
+
+    let result = AuctionResult {
+        clearing_price,
+        sold,
+        unsold: amount::sub("unsold", auction.volume, sold).expect("an auction sells at most its volume"),
+        bids_received,
+        demand,
+        awards,
+        settled_at: now,
+    };
+    auction.status = AuctionStatus::Settled;
+    auction.result = Some(result.clone());
+    save_auction(&auction);
+
+    Ok(result)
+}
+
+// Settle an auction that has closed but has not been settled on schedule yet
+// (regulator only)
+#[update(guard = "caller_is_regulator")]
+fn settle_auction(auction_id: u64) -> Result<AuctionResult, GreenGaugeError> {
+    settle(auction_id, ic_cdk::api::time())
+}
+
+// Cancel an auction that has not been settled and refund every bid
+// (regulator only)
+#[update(guard = "caller_is_regulator")]
+fn cancel_auction(auction_id: u64) -> Result<Auction, GreenGaugeError> {
+    let mut auction = get_auction(auction_id)?;
+
+    if auction.status != AuctionStatus::Open {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Auction {} has already been {:?}",
+            auction_id, auction.status
+        )));
+    }
+
+    for bid in bids_of(auction_id) {
+        ledger::release_escrow(Account::of(bid.bidder), bid.escrowed, cause(Reason::AuctionRefund, auction_id));
+        remove_bid(auction_id, bid.bidder);
+    }
+
+    auction.status = AuctionStatus::Cancelled;
+    save_auction(&auction);
+    Ok(auction)
+}
+
+// List auctions with the results of settled ones. Bids stay sealed until
+// settlement.
+#[query]
+fn get_auctions() -> Vec<Auction> {
+    AUCTIONS.with(|auctions| auctions.borrow().values().collect())
+}
+
+// Look up the caller's bid in an auction that has not been settled
+#[query]
+fn get_my_bid(auction_id: u64) -> Result<Bid, GreenGaugeError> {
+    get_bid(auction_id, caller()).ok_or_else(|| GreenGaugeError::not_found("bid", auction_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bid(bidder: u8, quantity: u64, price: u64, submitted_at: u64) -> Bid {
+        Bid {
+            auction_id: 1,
+            bidder: Principal::from_slice(&[bidder]),
+            quantity,
+            price: Price::new(price),
+            escrowed: quantity * price,
+            submitted_at,
+        }
+    }
+
+    fn outcome(award: &AuctionAward) -> (u8, u64, u64, u64) {
+        (award.bidder.as_slice()[0], award.awarded, award.paid, award.refunded)
+    }
+
+    #[test]
+    fn winners_pay_the_lowest_accepted_price() {
+        let bids = vec![bid(1, 40, 10, 0), bid(2, 50, 12, 1), bid(3, 30, 8, 2), bid(4, 20, 5, 3)];
+        let (price, awards) = clear(100, bids);
+
+        // The third bid is filled partially and sets the price
+        assert_eq!(price, Some(Price::new(8)));
+        assert_eq!(
+            awards.iter().map(outcome).collect::<Vec<_>>(),
+            vec![(2, 50, 400, 200), (1, 40, 320, 80), (3, 10, 80, 160), (4, 0, 0, 100)]
+        );
+    }
+
+    #[test]
+    fn equal_prices_are_served_in_order_of_submission() {
+        let bids = vec![bid(1, 30, 10, 5), bid(2, 30, 10, 2)];
+        let (price, awards) = clear(40, bids);
+
+        assert_eq!(price, Some(Price::new(10)));
+        assert_eq!(
+            awards.iter().map(outcome).collect::<Vec<_>>(),
+            vec![(2, 30, 300, 0), (1, 10, 100, 200)]
+        );
+    }
+
+    #[test]
+    fn undersubscribed_auctions_fill_every_bid() {
+        let (price, awards) = clear(1_000, vec![bid(1, 10, 7, 0), bid(2, 5, 9, 1)]);
+        assert_eq!(price, Some(Price::new(7)));
+        assert!(awards.iter().all(|award| award.awarded == award.quantity));
+        assert_eq!(awards.iter().map(|award| award.refunded).sum::<u64>(), 10);
+
+        let (price, awards) = clear(1_000, Vec::new());
+        assert!(price.is_none() && awards.is_empty());
+    }
+}
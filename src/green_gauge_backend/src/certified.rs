@@ -0,0 +1,275 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::query;
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use ic_stable_structures::StableBTreeMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::error::GreenGaugeError;
+use crate::journal::{self, JournalAccount, Posting};
+use crate::listings::{self, Listing};
+use crate::{ensure_registered, get_memory, impl_storable, Memory, Transaction, HOLDINGS_MEMORY_ID, TRANSACTIONS};
+
+// Certified data is the root hash of a tree with one labeled subtree each for
+// balances, listings and the transaction log. A leaf holds the SHA-256 of the
+// candid encoding of the value it certifies:
+//
+//   balances/<principal>            -> HolderBalances of the principal
+//   listings/<listing id>           -> Listing
+//   transactions/<principal><id>    -> Transaction, once under the buyer and
+//                                      once under the seller
+//
+// Principals are prefixed with their length and ids are big-endian, so a
+// principal's transactions form one contiguous key range.
+const BALANCES: &[u8] = b"balances";
+const LISTINGS: &[u8] = b"listings";
+const TRANSACTIONS_LABEL: &[u8] = b"transactions";
+
+type Subtree = RbTree<Vec<u8>, Hash>;
+
+// Balances of every journal account a principal holds, as derived from the
+// journal
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HolderBalances {
+    principal: Principal,
+    balances: Vec<(JournalAccount, i128)>,
+}
+
+// A value together with the proof that the subnet certified it. `witness` is
+// the CBOR-encoded hash tree covering the value's leaves; its root hash
+// must equal the certified data in `certificate`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Certified<T> {
+    value: T,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+impl_storable!(HolderBalances);
+
+thread_local! {
+    // Rebuilt from the stores on upgrade
+    static TREE: RefCell<RbTree<&'static [u8], Subtree>> = RefCell::new(empty_tree());
+
+    // Kept up to date with every journal entry, so that an upgrade only has
+    // to hash them instead of replaying the journal
+    static HOLDINGS: RefCell<StableBTreeMap<Principal, HolderBalances, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(HOLDINGS_MEMORY_ID)));
+}
+
+fn empty_tree() -> RbTree<&'static [u8], Subtree> {
+    let mut tree = RbTree::new();
+    for label in [BALANCES, LISTINGS, TRANSACTIONS_LABEL] {
+        tree.insert(label, Subtree::new());
+    }
+    tree
+}
+
+fn leaf<T: CandidType>(value: &T) -> Hash {
+    let bytes = candid::encode_one(value).expect("certified values encode");
+    Sha256::digest(bytes).into()
+}
+
+fn principal_key(principal: Principal) -> Vec<u8> {
+    let bytes = principal.as_slice();
+    let mut key = Vec::with_capacity(bytes.len() + 1);
+    key.push(bytes.len() as u8);
+    key.extend_from_slice(bytes);
+    key
+}
+
+fn transaction_key(principal: Principal, transaction_id: u64) -> Vec<u8> {
+    let mut key = principal_key(principal);
+    key.extend_from_slice(&transaction_id.to_be_bytes());
+    key
+}
+
+fn update(label: &'static [u8], f: impl FnOnce(&mut Subtree)) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.modify(label, f);
+        ic_cdk::api::set_certified_data(&tree.root_hash());
+    });
+}
+
+fn holder_balances(principal: Principal) -> HolderBalances {
+    HOLDINGS
+        .with(|holdings| holdings.borrow().get(&principal))
+        .unwrap_or(HolderBalances {
+            principal,
+            balances: Vec::new(),
+        })
+}
+
+// Add the postings to the holdings and return the balances they changed
+fn apply(postings: &[Posting]) -> Vec<HolderBalances> {
+    let mut touched: BTreeMap<Principal, HolderBalances> = BTreeMap::new();
+    for posting in postings {
+        let account = posting.account();
+        let holder = touched
+            .entry(account.holder())
+            .or_insert_with(|| holder_balances(account.holder()));
+        match holder.balances.iter_mut().find(|(held, _)| *held == account) {
+            Some((_, balance)) => *balance += posting.change(),
+            None => holder.balances.push((account, posting.change())),
+        }
+    }
+
+    HOLDINGS.with(|holdings| {
+        let mut holdings = holdings.borrow_mut();
+        for (principal, balances) in &touched {
+            holdings.insert(*principal, balances.clone());
+        }
+    });
+    touched.into_values().collect()
+}
+
+fn certify_holders(holders: impl IntoIterator<Item = HolderBalances>) {
+    let leaves: Vec<(Vec<u8>, Hash)> = holders
+        .into_iter()
+        .map(|holder| (principal_key(holder.principal), leaf(&holder)))
+        .collect();
+    update(BALANCES, |balances| {
+        for (key, hash) in leaves {
+            balances.insert(key, hash);
+        }
+    });
+}
+
+// Record and certify the balances a journal entry changes
+pub fn certify_postings(postings: &[Posting]) {
+    certify_holders(apply(postings));
+}
+
+pub fn certify_listing(listing: &Listing) {
+    let hash = leaf(listing);
+    update(LISTINGS, |tree| tree.insert(listing.id.to_be_bytes().to_vec(), hash));
+}
+
+pub fn uncertify_listing(listing_id: u64) {
+    update(LISTINGS, |tree| tree.delete(&listing_id.to_be_bytes()));
+}
+
+pub fn certify_transaction(transaction: &Transaction) {
+    let hash = leaf(transaction);
+    update(TRANSACTIONS_LABEL, |tree| {
+        tree.insert(transaction_key(transaction.buyer, transaction.id), hash);
+        tree.insert(transaction_key(transaction.seller, transaction.id), hash);
+    });
+}
+
+pub fn uncertify_transaction(transaction: &Transaction) {
+    update(TRANSACTIONS_LABEL, |tree| {
+        tree.delete(&transaction_key(transaction.buyer, transaction.id));
+        tree.delete(&transaction_key(transaction.seller, transaction.id));
+    });
+}
+
+// Rebuild the tree from the stores, which outlive the heap across upgrades
+pub fn rebuild() {
+    TREE.with(|tree| *tree.borrow_mut() = empty_tree());
+
+    // Holdings were only kept on the heap before, so the journal is replayed
+    // once to fill them
+    if HOLDINGS.with(|holdings| holdings.borrow().is_empty()) {
+        journal::replay(|postings| {
+            apply(postings);
+        });
+    }
+    certify_holders(HOLDINGS.with(|holdings| holdings.borrow().values().collect::<Vec<_>>()));
+
+    for listing in listings::all() {
+        certify_listing(&listing);
+    }
+    TRANSACTIONS.with(|transactions| {
+        for (_, transaction) in transactions.borrow().iter() {
+            certify_transaction(&transaction);
+        }
+    });
+}
+
+// Package `value` with the certificate of the current call and a witness
+// built by `witness` from the subtree under `label`
+fn certified<T>(
+    value: T,
+    label: &'static [u8],
+    witness: impl for<'a> FnOnce(&'a Subtree) -> HashTree<'a>,
+) -> Result<Certified<T>, GreenGaugeError> {
+    let certificate = ic_cdk::api::data_certificate().ok_or_else(|| {
+        GreenGaugeError::invalid_state("Certificates are only available in query calls")
+    })?;
+
+    let witness = TREE.with(|tree| {
+        let tree = tree.borrow();
+        let hash_tree = tree.nested_witness(label, witness);
+        let mut serializer = serde_cbor::Serializer::new(Vec::new());
+        serializer.self_describe().expect("writing to a vector cannot fail");
+        hash_tree.serialize(&mut serializer).expect("hash trees encode");
+        serializer.into_inner()
+    });
+
+    Ok(Certified {
+        value,
+        certificate,
+        witness,
+    })
+}
+
+// Certified balances of a participant, as derived from the journal.
+// Participants may fetch their own; auditors anyone's.
+#[query]
+fn get_certified_balances(principal: Option<Principal>) -> Result<Certified<HolderBalances>, GreenGaugeError> {
+    let principal = principal.unwrap_or_else(caller);
+    journal::ensure_may_view(principal)?;
+
+    let key = principal_key(principal);
+    certified(holder_balances(principal), BALANCES, |tree| tree.witness(&key))
+}
+
+// A certified listing in any state
+#[query]
+fn get_certified_listing(listing_id: u64) -> Result<Certified<Listing>, GreenGaugeError> {
+    let listing = listings::get(listing_id).ok_or_else(|| GreenGaugeError::not_found("listing", listing_id))?;
+
+    let key = listing_id.to_be_bytes();
+    certified(listing, LISTINGS, |tree| tree.witness(&key))
+}
+
+// The caller's certified transaction history. The witness covers the whole
+// key range of the caller, so it also proves that no transaction is missing.
+#[query]
+fn get_certified_transactions() -> Result<Certified<Vec<Transaction>>, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    let transactions: Vec<Transaction> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .values()
+            .filter(|tx| tx.buyer == caller || tx.seller == caller)
+            .collect()
+    });
+
+    let (first, last) = (transaction_key(caller, 0), transaction_key(caller, u64::MAX));
+    certified(transactions, TRANSACTIONS_LABEL, |tree| tree.value_range(&first, &last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction_ranges_of_principals_do_not_overlap() {
+        let short = Principal::from_slice(&[7]);
+        let long = Principal::from_slice(&[7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        for (owner, other) in [(short, long), (long, short)] {
+            let range = transaction_key(owner, 0)..=transaction_key(owner, u64::MAX);
+            assert!(range.contains(&transaction_key(owner, 42)));
+            assert!(!range.contains(&transaction_key(other, 0)));
+            assert!(!range.contains(&transaction_key(other, u64::MAX)));
+        }
+    }
+}
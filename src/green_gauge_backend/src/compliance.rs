@@ -0,0 +1,851 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::allocation::{self, AllocationChange};
+use crate::amount::{self, Co2e, Rounding};
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::ledger;
+use crate::roles::{caller_is_auditor, caller_is_regulator};
+use crate::{
+    create_alert, create_alert_at, ensure_registered, get_memory, impl_storable, init_counter, next_id, IdCounter,
+    Memory, UserProfile, ALLOCATIONS_MEMORY_ID, COMPLIANCE_PERIODS_MEMORY_ID,
+    COMPLIANCE_PERIOD_ID_MEMORY_ID, COMPLIANCE_RECORDS_MEMORY_ID, EMISSION_HISTORY, USERS,
+};
+
+// Borrowing is capped as a share of the next period's allocation
+pub const MAX_BORROWING_LIMIT_PERCENT: u8 = 100;
+
+// Periods move from `Scheduled` to `Open` once their allocations have been
+// issued, and to `Closed` once every participant has surrendered
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeriodStatus {
+    Scheduled,
+    Open,
+    Closed,
+}
+
+// A cap-and-trade compliance period. Allowance units are compared one to one
+// with `carbon_emitted`, i.e. one unit covers one kg CO2e.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompliancePeriod {
+    pub id: u64,
+    name: String,
+    start: u64,
+    end: u64,
+    // Participants surrender between `end` and this deadline
+    surrender_deadline: u64,
+    // Whether allowances left over after surrender carry into later periods
+    banking_allowed: bool,
+    // Share of the next period's allocation a participant may borrow
+    borrowing_limit_percent: u8,
+    // GG tokens charged per allowance unit missing at surrender
+    penalty_per_unit: u64,
+    pub status: PeriodStatus,
+    created_at: u64,
+    // Total allowances for the period, set by the regulator
+    pub cap: Option<u64>,
+    // Share of the cap sold at auction instead of allocated for free
+    pub auction_share_percent: Option<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompliancePeriodInput {
+    name: String,
+    start: u64,
+    end: u64,
+    surrender_deadline: u64,
+    banking_allowed: bool,
+    borrowing_limit_percent: u8,
+    penalty_per_unit: u64,
+}
+
+// A participant's allocation for a period. Units borrowed by surrenders of
+// the previous period are withheld when the allocation is issued.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Allocation {
+    pub period_id: u64,
+    pub participant: Principal,
+    pub allocated: u64,
+    pub borrowed: u64,
+    pub issued: u64,
+    pub issued_at: Option<u64>,
+}
+
+// Outcome of a participant's surrender for a period
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ComplianceRecord {
+    period_id: u64,
+    participant: Principal,
+    // Emissions recorded during the period, rounded up to whole units
+    emissions: u64,
+    // Shortfall of the previous period that still had to be made good
+    carried_shortfall: u64,
+    obligation: u64,
+    surrendered: u64,
+    // Covered by borrowing from the next period's allocation
+    borrowed: u64,
+    shortfall: u64,
+    // Allowances left after surrender, kept or cancelled depending on banking
+    banked: u64,
+    expired: u64,
+    penalty_due: u64,
+    penalty_paid: u64,
+    surrendered_at: u64,
+    surrendered_by: Principal,
+}
+
+// A participant the period was closed without, because their surrender
+// could not be worked out
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SurrenderFailure {
+    participant: Principal,
+    error: GreenGaugeError,
+}
+
+// Surrenders made on behalf of participants when a period was closed
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PeriodClosure {
+    records: Vec<ComplianceRecord>,
+    failed: Vec<SurrenderFailure>,
+}
+
+// Where a participant stands in a period
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ComplianceStatus {
+    period: CompliancePeriod,
+    allocation: Option<Allocation>,
+    emissions_to_date: u64,
+    allowance_held: u64,
+    record: Option<ComplianceRecord>,
+}
+
+impl_storable!(CompliancePeriod, Allocation, ComplianceRecord);
+
+thread_local! {
+    static COMPLIANCE_PERIODS: RefCell<StableBTreeMap<u64, CompliancePeriod, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COMPLIANCE_PERIODS_MEMORY_ID)));
+    static COMPLIANCE_PERIOD_ID: IdCounter = init_counter(COMPLIANCE_PERIOD_ID_MEMORY_ID, 1);
+
+    // Keyed by (period id, participant)
+    static ALLOCATIONS: RefCell<StableBTreeMap<(u64, Principal), Allocation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALLOCATIONS_MEMORY_ID)));
+    static COMPLIANCE_RECORDS: RefCell<StableBTreeMap<(u64, Principal), ComplianceRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COMPLIANCE_RECORDS_MEMORY_ID)));
+}
+
+pub fn get_period(period_id: u64) -> Result<CompliancePeriod, GreenGaugeError> {
+    COMPLIANCE_PERIODS
+        .with(|periods| periods.borrow().get(&period_id))
+        .ok_or_else(|| GreenGaugeError::not_found("compliance period", period_id))
+}
+
+pub fn save_period(period: &CompliancePeriod) {
+    COMPLIANCE_PERIODS.with(|periods| periods.borrow_mut().insert(period.id, period.clone()));
+}
+
+fn all_periods() -> Vec<CompliancePeriod> {
+    COMPLIANCE_PERIODS.with(|periods| periods.borrow().values().collect())
+}
+
+pub fn get_allocation(period_id: u64, participant: Principal) -> Option<Allocation> {
+    ALLOCATIONS.with(|allocations| allocations.borrow().get(&(period_id, participant)))
+}
+
+pub fn save_allocation(allocation: &Allocation) {
+    ALLOCATIONS.with(|allocations| {
+        allocations
+            .borrow_mut()
+            .insert((allocation.period_id, allocation.participant), allocation.clone())
+    });
+}
+
+pub fn allocations_of_period(period_id: u64) -> Vec<Allocation> {
+    ALLOCATIONS.with(|allocations| {
+        allocations
+            .borrow()
+            .range((period_id, Principal::management_canister())..)
+            .take_while(|((id, _), _)| *id == period_id)
+            .map(|(_, allocation)| allocation)
+            .collect()
+    })
+}
+
+fn get_record(period_id: u64, participant: Principal) -> Option<ComplianceRecord> {
+    COMPLIANCE_RECORDS.with(|records| records.borrow().get(&(period_id, participant)))
+}
+
+// The period that starts first after `period` ends
+fn next_period(period: &CompliancePeriod) -> Option<CompliancePeriod> {
+    all_periods()
+        .into_iter()
+        .filter(|other| other.start > period.end)
+        .min_by_key(|other| other.start)
+}
+
+fn previous_period(period: &CompliancePeriod) -> Option<CompliancePeriod> {
+    all_periods()
+        .into_iter()
+        .filter(|other| other.end < period.start)
+        .max_by_key(|other| other.end)
+}
+
+// Emissions recorded for a participant during a period, in whole units
+fn emissions_in(participant: Principal, period: &CompliancePeriod) -> u64 {
+    let total = EMISSION_HISTORY.with(|history| {
+        history
+            .borrow()
+            .values_range((participant, period.start)..=(participant, period.end))
+            .fold(Co2e::ZERO, |total, point| total.saturating_add(point.grams()))
+    });
+    total.whole_kg(Rounding::Up)
+}
+
+fn validate_period_input(input: &CompliancePeriodInput) -> Result<(), GreenGaugeError> {
+    if input.name.trim().is_empty() {
+        return Err(GreenGaugeError::invalid_input("name", "must not be empty"));
+    }
+
+    if input.start >= input.end {
+        return Err(GreenGaugeError::invalid_input("end", "must be after start"));
+    }
+
+    if input.surrender_deadline < input.end {
+        return Err(GreenGaugeError::invalid_input(
+            "surrender_deadline",
+            "must not be before the end of the period",
+        ));
+    }
+
+    if input.borrowing_limit_percent > MAX_BORROWING_LIMIT_PERCENT {
+        return Err(GreenGaugeError::invalid_input(
+            "borrowing_limit_percent",
+            format!("must be at most {}", MAX_BORROWING_LIMIT_PERCENT),
+        ));
+    }
+
+    if let Some(overlapping) = all_periods()
+        .into_iter()
+        .find(|period| input.start <= period.end && period.start <= input.end)
+    {
+        return Err(GreenGaugeError::invalid_input(
+            "start",
+            format!("overlaps compliance period {}", overlapping.id),
+        ));
+    }
+
+    Ok(())
+}
+
+// Periods are settled in order, once they have ended
+fn ensure_awaiting_surrender(period: &CompliancePeriod, now: u64) -> Result<(), GreenGaugeError> {
+    if period.status != PeriodStatus::Open || now <= period.end {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Compliance period {} is not awaiting surrender",
+            period.id
+        )));
+    }
+
+    if let Some(previous) = previous_period(period) {
+        if previous.status != PeriodStatus::Closed {
+            return Err(GreenGaugeError::invalid_state(format!(
+                "Compliance period {} must be closed first",
+                previous.id
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Surrender allowances for `participant` in a period that has ended.
+//
+// The obligation is the period's emissions plus any shortfall carried over
+// from the previous period. It is covered from held allowances first, then by
+// borrowing against the participant's unissued allocation for the next
+// period. Whatever remains is a shortfall: it stays outstanding in
+// `carbon_emitted`, is charged a penalty in GG tokens and raises an alert.
+fn surrender(
+    period: &CompliancePeriod,
+    participant: Principal,
+    surrendered_by: Principal,
+    now: u64,
+) -> Result<ComplianceRecord, GreenGaugeError> {
+    let plan = plan_surrender(period, participant, surrendered_by, now)?;
+    Ok(apply_surrender(period, plan))
+}
+
+// Everything a surrender changes, worked out before any of it is written
+struct SurrenderPlan {
+    record: ComplianceRecord,
+    profile: UserProfile,
+    // Emissions taken off `carbon_emitted`
+    settled: Co2e,
+    // The next period's allocation with the borrowed units added
+    next_allocation: Option<Allocation>,
+}
+
+// How an obligation is met: from the allowance held, then by borrowing
+// against the next allocation. What remains is a shortfall, penalised per
+// unit and paid as far as the token balance allows.
+#[derive(Debug, PartialEq, Eq)]
+struct Coverage {
+    surrendered: u64,
+    borrowed: u64,
+    shortfall: u64,
+    penalty_due: u64,
+    penalty_paid: u64,
+}
+
+fn cover(
+    obligation: u64,
+    allowance: u64,
+    borrowable: u64,
+    penalty_per_unit: u64,
+    token_balance: u64,
+) -> Result<Coverage, GreenGaugeError> {
+    let surrendered = obligation.min(allowance);
+    let remaining = obligation - surrendered;
+    let borrowed = remaining.min(borrowable);
+    let shortfall = remaining - borrowed;
+    let penalty_due = amount::mul("penalty_due", shortfall, penalty_per_unit)?;
+
+    Ok(Coverage {
+        surrendered,
+        borrowed,
+        shortfall,
+        penalty_due,
+        penalty_paid: penalty_due.min(token_balance),
+    })
+}
+
+// Units that may still be borrowed against an allocation
+fn borrowable(allocation: &Allocation, limit_percent: u8) -> u64 {
+    let limit = amount::mul_div(allocation.allocated, limit_percent as u64, 100, Rounding::Down)
+        .expect("at most 100% of an allocation fits");
+    limit.saturating_sub(allocation.borrowed)
+}
+
+fn plan_surrender(
+    period: &CompliancePeriod,
+    participant: Principal,
+    surrendered_by: Principal,
+    now: u64,
+) -> Result<SurrenderPlan, GreenGaugeError> {
+    ensure_awaiting_surrender(period, now)?;
+
+    if get_record(period.id, participant).is_some() {
+        return Err(GreenGaugeError::already_exists(
+            "compliance record",
+            format!("{} {}", period.id, participant),
+        ));
+    }
+
+    let mut profile: UserProfile = USERS
+        .with(|users| users.borrow().get(&participant))
+        .ok_or_else(|| GreenGaugeError::not_found("user", participant))?;
+
+    let emissions = emissions_in(participant, period);
+    let carried_shortfall = previous_period(period)
+        .and_then(|previous| get_record(previous.id, participant))
+        .map_or(0, |record| record.shortfall);
+    let obligation = amount::add("obligation", emissions, carried_shortfall)?;
+
+    let mut next_allocation = next_period(period)
+        .and_then(|next| get_allocation(next.id, participant))
+        .filter(|allocation| allocation.issued_at.is_none());
+    let Coverage {
+        surrendered,
+        borrowed,
+        shortfall,
+        penalty_due,
+        penalty_paid,
+    } = cover(
+        obligation,
+        profile.carbon_allowance,
+        next_allocation
+            .as_ref()
+            .map_or(0, |allocation| borrowable(allocation, period.borrowing_limit_percent)),
+        period.penalty_per_unit,
+        ledger::balance_of(participant),
+    )?;
+
+    let settled_units = amount::add("settled", surrendered, borrowed)?;
+
+    profile.debit_allowance(surrendered)?;
+    let emitted = profile.carbon_emitted;
+    profile.carbon_emitted = profile.carbon_emitted.saturating_sub(settled_units);
+    let settled = Co2e::from_kg(emitted - profile.carbon_emitted)
+        .ok_or_else(|| GreenGaugeError::overflow("carbon_emitted"))?;
+    if let Some(allocation) = next_allocation.as_mut() {
+        // Borrowing stays within the limit, which is at most the allocation
+        allocation.borrowed += borrowed;
+    }
+
+    let (banked, expired) = if period.banking_allowed {
+        (profile.carbon_allowance, 0)
+    } else {
+        (0, std::mem::take(&mut profile.carbon_allowance))
+    };
+
+    Ok(SurrenderPlan {
+        record: ComplianceRecord {
+            period_id: period.id,
+            participant,
+            emissions,
+            carried_shortfall,
+            obligation,
+            surrendered,
+            borrowed,
+            shortfall,
+            banked,
+            expired,
+            penalty_due,
+            penalty_paid,
+            surrendered_at: now,
+            surrendered_by,
+        },
+        profile,
+        settled,
+        next_allocation: next_allocation.filter(|_| borrowed > 0),
+    })
+}
+
+// Write a planned surrender. Cannot fail.
+fn apply_surrender(period: &CompliancePeriod, plan: SurrenderPlan) -> ComplianceRecord {
+    let SurrenderPlan {
+        record,
+        profile,
+        settled,
+        next_allocation,
+    } = plan;
+    let participant = record.participant;
+    USERS.with(|users| users.borrow_mut().insert(participant, profile));
+
+    let reference = Some(Reference::CompliancePeriod(period.id));
+    let cause = Cause::new(Reason::Surrender, reference);
+    journal::transfer(cause, JournalAccount::Allowance(participant), JournalAccount::AllowanceIssuer, record.surrendered);
+    journal::transfer(
+        cause,
+        JournalAccount::Emissions(participant),
+        JournalAccount::EmissionSource,
+        settled.grams(),
+    );
+    journal::transfer(
+        Cause::new(Reason::Expiry, reference),
+        JournalAccount::Allowance(participant),
+        JournalAccount::AllowanceIssuer,
+        record.expired,
+    );
+
+    if let Some(allocation) = next_allocation {
+        save_allocation(&allocation);
+    }
+
+    if record.penalty_paid > 0 {
+        let cause = Cause::new(Reason::CompliancePenalty, reference);
+        ledger::transfer_internal(participant, ledger::treasury_account(), record.penalty_paid, cause);
+    }
+
+    if record.shortfall > 0 {
+        create_alert(
+            participant,
+            format!(
+                "Compliance period {}: {} allowance units short. A penalty of {} GG tokens was charged and the shortfall carries into the next period.",
+                period.name, record.shortfall, record.penalty_due
+            ),
+            "high",
+        );
+    }
+
+    COMPLIANCE_RECORDS.with(|records| {
+        records
+            .borrow_mut()
+            .insert((period.id, participant), record.clone())
+    });
+    record
+}
+
+// Schedule a new compliance period (regulator only)
+#[update(guard = "caller_is_regulator")]
+fn create_compliance_period(input: CompliancePeriodInput) -> Result<CompliancePeriod, GreenGaugeError> {
+    validate_period_input(&input)?;
+
+    let period = CompliancePeriod {
+        id: next_id(&COMPLIANCE_PERIOD_ID),
+        name: input.name,
+        start: input.start,
+        end: input.end,
+        surrender_deadline: input.surrender_deadline,
+        banking_allowed: input.banking_allowed,
+        borrowing_limit_percent: input.borrowing_limit_percent,
+        penalty_per_unit: input.penalty_per_unit,
+        status: PeriodStatus::Scheduled,
+        created_at: ic_cdk::api::time(),
+        cap: None,
+        auction_share_percent: None,
+    };
+    save_period(&period);
+
+    Ok(period)
+}
+
+// Open a period once it has started and issue its allocations, minus what
+// was borrowed against them
+fn open_period(period_id: u64, now: u64) -> Result<CompliancePeriod, GreenGaugeError> {
+    let mut period = get_period(period_id)?;
+
+    if period.status != PeriodStatus::Scheduled {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Compliance period {} is already open",
+            period_id
+        )));
+    }
+
+    if now < period.start {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Compliance period {} has not started yet",
+            period_id
+        )));
+    }
+
+    // Credit every participant before anything is written, so that one who
+    // cannot hold their allocation leaves the period unopened
+    let mut issuance = Vec::new();
+    for mut allocation in allocations_of_period(period_id) {
+        allocation.issued = allocation.allocated.saturating_sub(allocation.borrowed);
+        allocation.issued_at = Some(now);
+        let mut profile = USERS.with(|users| users.borrow().get(&allocation.participant));
+        if let Some(profile) = profile.as_mut() {
+            profile.credit_allowance(allocation.issued)?;
+        }
+        issuance.push((allocation, profile));
+    }
+
+    // Nothing below this point can fail
+    for (allocation, profile) in issuance {
+        if let Some(profile) = profile {
+            USERS.with(|users| users.borrow_mut().insert(allocation.participant, profile));
+            journal::transfer(
+                Cause::new(Reason::Allocation, Some(Reference::CompliancePeriod(period_id))),
+                JournalAccount::AllowanceIssuer,
+                JournalAccount::Allowance(allocation.participant),
+                allocation.issued,
+            );
+        }
+        save_allocation(&allocation);
+        allocation::log(
+            period_id,
+            Some(allocation.participant),
+            AllocationChange::Issued {
+                issued: allocation.issued,
+                withheld_for_borrowing: allocation.allocated - allocation.issued,
+            },
+        );
+    }
+
+    period.status = PeriodStatus::Open;
+    save_period(&period);
+    Ok(period)
+}
+
+fn ensure_closable(period: &CompliancePeriod, now: u64) -> Result<(), GreenGaugeError> {
+    if now <= period.surrender_deadline {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "The surrender deadline of compliance period {} has not passed",
+            period.id
+        )));
+    }
+    ensure_awaiting_surrender(period, now)
+}
+
+// Surrender on behalf of each of `participants` who has not done so yet.
+// Every surrender is planned and written on its own: a participant whose
+// surrender fails is alerted and left out, and the others go ahead.
+fn surrender_remaining(
+    period: &CompliancePeriod,
+    participants: impl IntoIterator<Item = Principal>,
+    closed_by: Principal,
+    now: u64,
+    closure: &mut PeriodClosure,
+) {
+    for participant in participants {
+        if get_record(period.id, participant).is_some() {
+            continue;
+        }
+        match plan_surrender(period, participant, closed_by, now) {
+            Ok(plan) => closure.records.push(apply_surrender(period, plan)),
+            Err(error) => {
+                ic_cdk::println!("Surrender of {} for period {} failed: {}", participant, period.id, error);
+                create_alert_at(
+                    participant,
+                    format!(
+                        "Compliance period {} was closed without your surrender, which failed: {}. Contact the regulator to settle it.",
+                        period.name, error
+                    ),
+                    "high",
+                    now,
+                );
+                closure.failed.push(SurrenderFailure { participant, error });
+            }
+        }
+    }
+}
+
+fn finish_close(period: &mut CompliancePeriod) {
+    period.status = PeriodStatus::Closed;
+    save_period(period);
+}
+
+// Where a run of `roll_over` stopped
+pub enum Rollover {
+    Done { opened: u64 },
+    // This period is past its deadline and must be closed with
+    // `close_participants` and `finish_closing` before later ones move on
+    Closing { opened: u64, period_id: u64 },
+}
+
+// Close a period after its surrender deadline, surrendering on behalf of
+// every participant who has not done so. Returns the records created this
+// way and the participants whose surrender failed.
+fn close_period(period_id: u64, now: u64, closed_by: Principal) -> Result<PeriodClosure, GreenGaugeError> {
+    let mut period = get_period(period_id)?;
+    ensure_closable(&period, now)?;
+
+    let participants: Vec<Principal> = USERS.with(|users| users.borrow().keys().collect());
+    let mut closure = PeriodClosure::default();
+    surrender_remaining(&period, participants, closed_by, now, &mut closure);
+    finish_close(&mut period);
+    Ok(closure)
+}
+
+// Open every period that has started, in order, up to the first period
+// whose surrender deadline has passed. That period is left for the caller to
+// close in chunks of participants, since the periods after it depend on it.
+pub fn roll_over(now: u64) -> Result<Rollover, GreenGaugeError> {
+    let mut periods = all_periods();
+    periods.sort_by_key(|period| period.start);
+
+    let mut opened = 0;
+    for period in periods {
+        if period.status == PeriodStatus::Scheduled && now >= period.start {
+            open_period(period.id, now)?;
+            opened += 1;
+        }
+        let period = get_period(period.id)?;
+        if period.status == PeriodStatus::Open && now > period.surrender_deadline {
+            ensure_closable(&period, now)?;
+            return Ok(Rollover::Closing {
+                opened,
+                period_id: period.id,
+            });
+        }
+    }
+    Ok(Rollover::Done { opened })
+}
+
+// Surrender on behalf of those of `participants` who have not done so in a
+// period being closed. Does nothing once the period is closed.
+pub fn close_participants(period_id: u64, participants: &[Principal], now: u64) -> Result<(), GreenGaugeError> {
+    let period = get_period(period_id)?;
+    if period.status == PeriodStatus::Open {
+        ensure_closable(&period, now)?;
+        surrender_remaining(&period, participants.iter().copied(), ic_cdk::id(), now, &mut PeriodClosure::default());
+    }
+    Ok(())
+}
+
+// Mark a period closed once every participant has been handed to
+// `close_participants`
+pub fn finish_closing(period_id: u64) -> Result<(), GreenGaugeError> {
+    let mut period = get_period(period_id)?;
+    if period.status == PeriodStatus::Open {
+        finish_close(&mut period);
+    }
+    Ok(())
+}
+
+// Open a period once it has started and issue its allocations (regulator
+// only). Periods are also opened on schedule.
+#[update(guard = "caller_is_regulator")]
+fn open_compliance_period(period_id: u64) -> Result<CompliancePeriod, GreenGaugeError> {
+    open_period(period_id, ic_cdk::api::time())
+}
+
+// Surrender the caller's allowances for a period that has ended
+#[update]
+fn surrender_allowances(period_id: u64) -> Result<ComplianceRecord, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    let period = get_period(period_id)?;
+    let now = ic_cdk::api::time();
+    if now > period.surrender_deadline {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "The surrender deadline of compliance period {} has passed",
+            period_id
+        )));
+    }
+
+    surrender(&period, caller, caller, now)
+}
+
+// Close a period after its surrender deadline, surrendering on behalf of
+// every participant who has not done so (regulator only). Returns the records
+// created this way and the participants whose surrender failed. Periods are
+// also closed on schedule.
+#[update(guard = "caller_is_regulator")]
+fn close_compliance_period(period_id: u64) -> Result<PeriodClosure, GreenGaugeError> {
+    close_period(period_id, ic_cdk::api::time(), caller())
+}
+
+#[query]
+fn get_compliance_periods() -> Vec<CompliancePeriod> {
+    let mut periods = all_periods();
+    periods.sort_by_key(|period| period.start);
+    periods
+}
+
+// Show where the caller stands in a period
+#[query]
+fn get_compliance_status(period_id: u64) -> Result<ComplianceStatus, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    let period = get_period(period_id)?;
+    let allowance_held = USERS
+        .with(|users| users.borrow().get(&caller))
+        .map_or(0, |profile| profile.carbon_allowance);
+
+    Ok(ComplianceStatus {
+        allocation: get_allocation(period_id, caller),
+        emissions_to_date: emissions_in(caller, &period),
+        allowance_held,
+        record: get_record(period_id, caller),
+        period,
+    })
+}
+
+// List the surrender records of a period (auditor only)
+#[query(guard = "caller_is_auditor")]
+fn get_compliance_records(period_id: u64) -> Vec<ComplianceRecord> {
+    COMPLIANCE_RECORDS.with(|records| {
+        records
+            .borrow()
+            .range((period_id, Principal::management_canister())..)
+            .take_while(|((id, _), _)| *id == period_id)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EmissionHistoryPoint;
+
+    fn register(principal: Principal) {
+        let profile = UserProfile {
+            principal,
+            carbon_allowance: 0,
+            carbon_emitted: 0,
+            tokens: 0,
+            has_subcontract: false,
+            username: None,
+            email: None,
+            full_name: None,
+            location: None,
+            join_date: 0,
+            last_activity: 0,
+            carbon_locked: None,
+            carbon_available: None,
+            carbon_emitted_remainder: None,
+        };
+        USERS.with(|users| users.borrow_mut().insert(principal, profile));
+    }
+
+    fn coverage(surrendered: u64, borrowed: u64, shortfall: u64, penalty_due: u64, penalty_paid: u64) -> Coverage {
+        Coverage {
+            surrendered,
+            borrowed,
+            shortfall,
+            penalty_due,
+            penalty_paid,
+        }
+    }
+
+    #[test]
+    fn allowance_is_surrendered_before_borrowing() {
+        assert_eq!(cover(80, 100, 50, 10, 0).unwrap(), coverage(80, 0, 0, 0, 0));
+        assert_eq!(cover(120, 100, 50, 10, 0).unwrap(), coverage(100, 20, 0, 0, 0));
+    }
+
+    #[test]
+    fn shortfalls_are_penalised_up_to_the_balance() {
+        assert_eq!(cover(200, 100, 50, 10, 1_000).unwrap(), coverage(100, 50, 50, 500, 500));
+        assert_eq!(cover(200, 100, 50, 10, 120).unwrap(), coverage(100, 50, 50, 500, 120));
+        assert!(cover(u64::MAX, 0, 0, 2, 0).is_err());
+    }
+
+    #[test]
+    fn a_failed_surrender_does_not_keep_the_period_open() {
+        let period = CompliancePeriod {
+            id: 1,
+            name: "2025".to_string(),
+            start: 100,
+            end: 200,
+            surrender_deadline: 300,
+            banking_allowed: true,
+            borrowing_limit_percent: 0,
+            penalty_per_unit: u64::MAX,
+            status: PeriodStatus::Open,
+            created_at: 0,
+            cap: None,
+            auction_share_percent: None,
+        };
+        save_period(&period);
+
+        let (compliant, overflowing) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        register(compliant);
+        register(overflowing);
+        // Two units short at the maximum penalty per unit
+        let mut point = EmissionHistoryPoint {
+            timestamp: 150,
+            amount: 0.0,
+            breakdown: None,
+            amount_grams: None,
+        };
+        point.set_grams(Co2e::from_grams(2_000));
+        EMISSION_HISTORY.with(|history| history.borrow_mut().insert((overflowing, 150), point));
+
+        let closure = close_period(period.id, 301, Principal::anonymous()).unwrap();
+
+        assert_eq!(get_period(period.id).unwrap().status, PeriodStatus::Closed);
+        assert_eq!(closure.records.len(), 1);
+        assert!(get_record(period.id, compliant).is_some());
+        assert_eq!(closure.failed.len(), 1);
+        assert_eq!(closure.failed[0].participant, overflowing);
+        assert_eq!(closure.failed[0].error, GreenGaugeError::overflow("penalty_due"));
+        assert!(get_record(period.id, overflowing).is_none());
+    }
+
+    #[test]
+    fn borrowing_is_limited_to_a_share_of_the_next_allocation() {
+        let mut allocation = Allocation {
+            period_id: 2,
+            participant: Principal::anonymous(),
+            allocated: 1_000,
+            borrowed: 0,
+            issued: 0,
+            issued_at: None,
+        };
+        assert_eq!(borrowable(&allocation, 15), 150);
+        allocation.borrowed = 100;
+        assert_eq!(borrowable(&allocation, 15), 50);
+        allocation.borrowed = 200;
+        assert_eq!(borrowable(&allocation, 15), 0);
+    }
+}
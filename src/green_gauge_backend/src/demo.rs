@@ -0,0 +1,387 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::amount::{Co2e, Price, Rounding};
+use crate::certified;
+use crate::devices;
+use crate::error::GreenGaugeError;
+use crate::ghg::{ClassifiedAmount, GhgCategory, GhgClassification, Scope2Method};
+use crate::journal::{self, Cause, PostingSide, Reason};
+use crate::listings::{self, ListingKind, OffsetDetails};
+use crate::roles::caller_is_admin;
+use crate::{
+    get_memory, impl_storable, ledger, next_id, user_range, Alert, EfficiencyMetric,
+    EmissionHistoryPoint, Memory, Transaction, UserProfile, ALERTS, ALERT_ID_COUNTER,
+    CANISTER_MODE_MEMORY_ID, DATA_POINTS, EFFICIENCY_METRICS, EMISSION_HISTORY, TRANSACTIONS,
+    TRANSACTION_ID_COUNTER, USERS,
+};
+
+// Whether the canister serves real customers or a demo with fixture data
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanisterMode {
+    Production,
+    Demo,
+}
+
+// Install and upgrade arguments. Omitting them keeps the current mode, which
+// is `Production` on a fresh install.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    mode: CanisterMode,
+}
+
+impl_storable!(CanisterMode);
+
+thread_local! {
+    static MODE: RefCell<StableCell<CanisterMode, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CANISTER_MODE_MEMORY_ID), CanisterMode::Production)
+            .expect("failed to initialize canister mode"),
+    );
+}
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const NANOS_PER_HOUR: u64 = 60 * NANOS_PER_MINUTE;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+const DEMO_USER_TOKENS: u64 = 7500;
+
+// The showcase profile. Unauthenticated frontend sessions call as the
+// anonymous principal, which can never register, so demo visitors see this
+// account and it cannot collide with a real one.
+fn demo_user() -> Principal {
+    Principal::anonymous()
+}
+
+// Counterparties of the seeded listings and transactions
+fn demo_counterparty(n: u8) -> Principal {
+    Principal::from_slice(&[0xde, 0x30, n])
+}
+
+const DEMO_COUNTERPARTIES: u8 = 5;
+
+fn demo_principals() -> Vec<Principal> {
+    let mut principals = vec![demo_user()];
+    principals.extend((1..=DEMO_COUNTERPARTIES).map(demo_counterparty));
+    principals
+}
+
+pub fn mode() -> CanisterMode {
+    MODE.with(|mode| *mode.borrow().get())
+}
+
+// Apply install or upgrade arguments, seeding fixtures when a fresh install
+// starts in demo mode
+pub fn apply_init_args(args: Option<InitArgs>, fresh_install: bool) {
+    let Some(args) = args else {
+        return;
+    };
+
+    MODE.with(|mode| {
+        mode.borrow_mut()
+            .set(args.mode)
+            .expect("failed to persist canister mode");
+    });
+    ic_cdk::println!("Green Gauge canister running in {:?} mode", args.mode);
+
+    if fresh_install && args.mode == CanisterMode::Demo {
+        seed();
+    }
+}
+
+// Helper function for random number generation
+fn rand() -> f32 {
+    let now = ic_cdk::api::time() % 1000;
+    (now as f32) / 1000.0
+}
+
+fn demo_profile(principal: Principal, carbon_allowance: u64, join_date: u64) -> UserProfile {
+    UserProfile {
+        principal,
+        carbon_allowance,
+        carbon_emitted: 0,
+        tokens: 0,
+        has_subcontract: true,
+        username: None,
+        email: None,
+        full_name: None,
+        location: None,
+        join_date,
+        last_activity: join_date,
+        carbon_locked: Some(0),
+        carbon_available: None,
+        carbon_emitted_remainder: Some(Co2e::ZERO),
+    }
+}
+
+// Write the demo fixtures. Records take fresh ids, so existing data is never
+// overwritten.
+fn seed() {
+    let mock_user_principal = demo_user();
+    let now = ic_cdk::api::time();
+    let join_date = now - 90 * NANOS_PER_DAY;
+
+    let mock_user = UserProfile {
+        carbon_emitted: 3500,
+        username: Some("GreenCorp".to_string()),
+        email: Some("contact@greencorp.com".to_string()),
+        full_name: Some("Green Corporation".to_string()),
+        location: Some("Eco City, Green State".to_string()),
+        last_activity: now - 2 * NANOS_PER_DAY,
+        ..demo_profile(mock_user_principal, 10000, join_date)
+    };
+
+    let cause = Cause::new(Reason::Demo, None);
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        journal::post_profile(cause, &mock_user, PostingSide::Credit);
+        users_map.insert(mock_user_principal, mock_user);
+        // Sellers need a profile holding the listed allowance for purchases to settle
+        for n in 1..=DEMO_COUNTERPARTIES {
+            let principal = demo_counterparty(n);
+            let profile = demo_profile(principal, 5000, join_date);
+            journal::post_profile(cause, &profile, PostingSide::Credit);
+            users_map.insert(principal, profile);
+        }
+    });
+
+    // Efficiency metrics for the past week
+    EFFICIENCY_METRICS.with(|metrics| {
+        let mut metrics_map = metrics.borrow_mut();
+        for days_ago in (1..=7).rev() {
+            let day = (now - days_ago * NANOS_PER_DAY) / NANOS_PER_DAY;
+
+            let metric = EfficiencyMetric {
+                date: format!("{}", day),
+                consumption: 200.0 + (rand() * 100.0),
+                carbon_emitted: None,
+                efficiency_score: 60.0 + (rand() * 30.0),
+                carbon_emitted_grams: Some(Co2e::from_grams(70_000 + (rand() * 50_000.0) as u64)),
+            };
+            metrics_map.insert((mock_user_principal, day), metric);
+        }
+    });
+
+    // Emission history for the past month
+    for days_ago in (1..=30u64).rev() {
+        let timestamp = now - days_ago * NANOS_PER_DAY;
+
+        EMISSION_HISTORY.with(|history| {
+            // Roughly a third from the boilers on site, the rest from the grid
+            let amount = Co2e::from_grams(50_000 + (rand() * 150_000.0) as u64);
+            let on_site = amount.scale(1, 3, Rounding::HalfUp).expect("a third fits");
+            let grid = amount.checked_sub(on_site).expect("a third is less than the whole");
+            let point = EmissionHistoryPoint {
+                timestamp,
+                amount: amount.as_kg_f64(),
+                breakdown: Some(vec![
+                    ClassifiedAmount::new(
+                        GhgClassification::new(GhgCategory::StationaryCombustion, Scope2Method::LocationBased),
+                        on_site,
+                    ),
+                    ClassifiedAmount::new(
+                        GhgClassification::new(GhgCategory::PurchasedElectricity, Scope2Method::LocationBased),
+                        grid,
+                    ),
+                ]),
+                amount_grams: Some(amount),
+            };
+            history.borrow_mut().insert((mock_user_principal, timestamp), point);
+        });
+    }
+
+    let mock_alerts = [
+        ("Your carbon emission is approaching your monthly limit", 45 * NANOS_PER_MINUTE, "medium", "new"),
+        ("New carbon trading opportunity available", 3 * NANOS_PER_HOUR, "low", "new"),
+        ("System maintenance scheduled for tonight at 10PM", 6 * NANOS_PER_HOUR, "low", "read"),
+        ("Security update required - please update your password", 18 * NANOS_PER_HOUR, "high", "new"),
+        ("Congratulations! You reduced emissions by 15% this week", NANOS_PER_DAY, "low", "new"),
+        ("Price alert: Carbon credit prices have increased by 5%", 30 * NANOS_PER_MINUTE, "medium", "new"),
+        ("Your efficiency metrics report is ready to view", 10 * NANOS_PER_MINUTE, "low", "new"),
+    ];
+
+    ALERTS.with(|alerts| {
+        let mut alerts_map = alerts.borrow_mut();
+        for (message, age, severity, status) in mock_alerts {
+            let alert = Alert {
+                id: next_id(&ALERT_ID_COUNTER),
+                user_id: mock_user_principal,
+                message: message.to_string(),
+                timestamp: now - age,
+                severity: severity.to_string(),
+                status: status.to_string(),
+            };
+            alerts_map.insert((alert.user_id, alert.id), alert);
+        }
+    });
+
+    let mock_carbon_credits = [
+        (demo_counterparty(1), 2000, 8, "renewable", "gold", "Solar Farm Initiative", 2023,
+            "Credits from our solar farm project in Arizona", 10),
+        (demo_counterparty(2), 1500, 7, "forestry", "verra", "Amazon Reforestation", 2023,
+            "Reforestation project in the Amazon rainforest", 15),
+        (mock_user_principal, 1000, 9, "efficiency", "american", "Green Building Retrofit", 2024,
+            "Energy efficiency improvements in commercial buildings", 5),
+        (demo_counterparty(3), 500, 10, "methane", "climate", "Landfill Gas Recovery", 2022,
+            "Capturing methane from landfill sites", 20),
+    ];
+
+    // The listed allowance is held in escrow like any other listing
+    let credit_ids: Vec<u64> = mock_carbon_credits
+        .into_iter()
+        .map(|(seller, amount, price_per_unit, credit_type, certification, project_name, vintage_year, description, days_ago)| {
+            let offset = OffsetDetails::new(
+                credit_type.to_string(),
+                certification.to_string(),
+                project_name.to_string(),
+                vintage_year,
+                description.to_string(),
+            );
+            let mut listing = listings::create(seller, ListingKind::Offset(offset), amount, Price::new(price_per_unit), None)
+                .expect("demo sellers hold the listed allowance");
+            listing.created_at = now - days_ago * NANOS_PER_DAY;
+            listings::save(&listing);
+            listing.id
+        })
+        .collect();
+
+    // (buyer, seller, listing, units, price, project, type, age)
+    let mock_transactions = [
+        (mock_user_principal, demo_counterparty(3), credit_ids[3], 200, 6,
+            "Landfill Gas Recovery", "purchase", 2 * NANOS_PER_DAY),
+        (demo_counterparty(4), mock_user_principal, credit_ids[2], 300, 7,
+            "Green Building Retrofit", "sale", 36 * NANOS_PER_HOUR),
+        (mock_user_principal, demo_counterparty(2), credit_ids[1], 500, 5,
+            "Amazon Reforestation", "purchase", 12 * NANOS_PER_HOUR),
+        (demo_counterparty(5), mock_user_principal, credit_ids[2], 250, 9,
+            "Green Building Retrofit", "sale", 4 * NANOS_PER_HOUR),
+        (mock_user_principal, demo_counterparty(1), credit_ids[0], 150, 8,
+            "Solar Farm Initiative", "purchase", 30 * NANOS_PER_MINUTE),
+    ];
+
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions_map = transactions.borrow_mut();
+        for (buyer, seller, credit_id, amount, price_per_unit, project_name, transaction_type, age) in mock_transactions {
+            let transaction = Transaction {
+                id: next_id(&TRANSACTION_ID_COUNTER),
+                buyer,
+                seller,
+                credit_id,
+                amount: amount as f64,
+                price_per_unit: price_per_unit as f64,
+                project_name: project_name.to_string(),
+                transaction_type: transaction_type.to_string(),
+                transaction_time: now - age,
+                quantity: Co2e::from_kg(amount),
+                price: Some(Price::new(price_per_unit)),
+                total_cost: Some(amount * price_per_unit),
+            };
+            certified::certify_transaction(&transaction);
+            transactions_map.insert(transaction.id, transaction);
+        }
+    });
+
+    if let Err(error) = ledger::mint(mock_user_principal, DEMO_USER_TOKENS, cause) {
+        ic_cdk::println!("Could not mint demo balance: {}", error);
+    }
+}
+
+// Remove every record owned by or involving a demo principal, including the
+// fixtures older releases seeded on every install. Returns the number of
+// records removed.
+fn clear() -> u64 {
+    let principals = demo_principals();
+    let is_demo = |principal: &Principal| principals.contains(principal);
+    let mut removed = 0;
+
+    // The journal keeps the history of removed profiles, closed by entries
+    // that take their balances back out
+    let cause = Cause::new(Reason::Demo, None);
+    for &principal in &principals {
+        let balance = ledger::balance_of(principal);
+        if balance > 0 {
+            ledger::burn(principal, balance, cause);
+        }
+    }
+
+    for &principal in &principals {
+        if let Some(profile) = USERS.with(|users| users.borrow_mut().remove(&principal)) {
+            journal::post_profile(cause, &profile, PostingSide::Debit);
+            removed += 1;
+        }
+        removed += remove_range(&DATA_POINTS, principal);
+        removed += remove_range(&ALERTS, principal);
+        removed += remove_range(&EMISSION_HISTORY, principal);
+        removed += remove_range(&EFFICIENCY_METRICS, principal);
+        removed += devices::remove_all_of(principal);
+        removed += listings::remove_all_of(principal);
+    }
+
+    let demo_transactions: Vec<Transaction> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .values()
+            .filter(|tx| is_demo(&tx.buyer) || is_demo(&tx.seller))
+            .collect()
+    });
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions_map = transactions.borrow_mut();
+        for transaction in &demo_transactions {
+            transactions_map.remove(&transaction.id);
+            certified::uncertify_transaction(transaction);
+        }
+    });
+
+    removed + demo_transactions.len() as u64
+}
+
+type PerUserStore<V> = RefCell<StableBTreeMap<(Principal, u64), V, Memory>>;
+
+// Remove every entry a principal owns in a (Principal, u64)-keyed store
+fn remove_range<V: Storable>(
+    store: &'static LocalKey<PerUserStore<V>>,
+    principal: Principal,
+) -> u64 {
+    store.with(|store| {
+        let mut store = store.borrow_mut();
+        let keys: Vec<(Principal, u64)> = store
+            .range(user_range(principal))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            store.remove(key);
+        }
+        keys.len() as u64
+    })
+}
+
+// Get the mode the canister is running in
+#[query]
+fn get_canister_mode() -> CanisterMode {
+    mode()
+}
+
+// Seed the demo fixtures (demo mode only)
+#[update(guard = "caller_is_admin")]
+fn seed_demo_data() -> Result<(), GreenGaugeError> {
+    if mode() != CanisterMode::Demo {
+        return Err(GreenGaugeError::invalid_state(
+            "Demo data can only be seeded when the canister runs in Demo mode",
+        ));
+    }
+
+    if USERS.with(|users| users.borrow().contains_key(&demo_user())) {
+        return Err(GreenGaugeError::already_exists("user", demo_user()));
+    }
+
+    seed();
+    Ok(())
+}
+
+// Remove the demo fixtures
+#[update(guard = "caller_is_admin")]
+fn clear_demo_data() -> u64 {
+    clear()
+}
@@ -0,0 +1,619 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::amount::{Intensity, Rounding};
+use crate::emission_factors::{self, AppliedFactor, EnergySource};
+use crate::error::GreenGaugeError;
+use crate::ghg::{GhgCategory, GhgClassification, Scope2Method};
+use crate::roles::caller_is_device_operator;
+use crate::{
+    ensure_registered, get_memory, impl_storable, Memory, DEVICES_MEMORY_ID,
+    DEVICE_PRINCIPALS_MEMORY_ID,
+};
+
+pub const MAX_DEVICE_ID_LENGTH: usize = 64;
+
+// Submissions per device are limited over a fixed one-minute window. A batch
+// of readings counts as a single submission.
+pub const DEFAULT_SUBMISSIONS_PER_MINUTE: u32 = 60;
+pub const MAX_SUBMISSIONS_PER_MINUTE: u32 = 600;
+const RATE_LIMIT_WINDOW_NANOS: u64 = 60 * 1_000_000_000;
+
+const VALID_DEVICE_TYPES: [&str; 5] = ["electricity_meter", "gas_meter", "fuel_meter", "heat_meter", "sensor"];
+const VALID_METER_UNITS: [&str; 5] = ["kWh", "MWh", "m3", "L", "kg"];
+const KWH_PER_METER_UNIT: [(&str, u64); 2] = [("kWh", 1), ("MWh", 1000)];
+
+// Device ids are chosen by the owner and unique per owner. The id is bounded
+// so that it can be part of the composite (owner, id) key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct DeviceId(String);
+
+impl Storable for DeviceId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        DeviceId(String::from_utf8(bytes.into_owned()).expect("device id is not valid UTF-8"))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_DEVICE_ID_LENGTH as u32,
+        is_fixed_size: false,
+    };
+}
+
+// A decommissioned device stays in the registry so that its id keeps pointing
+// at the readings it produced, but it can no longer submit new ones
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceStatus {
+    Active,
+    Decommissioned,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Device {
+    id: String,
+    owner: Principal,
+    device_type: String,    // electricity_meter, gas_meter, fuel_meter, heat_meter, sensor
+    location: String,
+    meter_unit: String,     // unit of the consumption the device reports
+    // Custom factor in kg CO2e per meter unit, used when the device has no
+    // energy source
+    emission_factor: Option<f64>,
+    status: DeviceStatus,
+    registered_at: u64,
+    updated_at: u64,
+    // Principal the device submits its own readings with, if any
+    principal: Option<Principal>,
+    // Defaults to DEFAULT_SUBMISSIONS_PER_MINUTE
+    rate_limit_per_minute: Option<u32>,
+    // Highest sequence number accepted from the device principal
+    last_sequence: Option<u64>,
+    last_reading_at: Option<u64>,
+    window_started_at: Option<u64>,
+    submissions_in_window: Option<u32>,
+    // Emissions are computed from the published factor for this source in
+    // `grid_region`, defaulting to the global factor
+    energy_source: Option<EnergySource>,
+    grid_region: Option<String>,
+    // Defaults follow the energy source or device type, see `classification`
+    ghg_category: Option<GhgCategory>,
+    scope2_method: Option<Scope2Method>,
+}
+
+// Either `energy_source` or a custom `emission_factor` must be given
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeviceRegistration {
+    id: String,
+    device_type: String,
+    location: String,
+    meter_unit: String,
+    emission_factor: Option<f64>,
+    energy_source: Option<EnergySource>,
+    grid_region: Option<String>,
+    ghg_category: Option<GhgCategory>,
+    scope2_method: Option<Scope2Method>,
+}
+
+// Fields left as `None` keep their current value
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeviceUpdate {
+    device_type: Option<String>,
+    location: Option<String>,
+    meter_unit: Option<String>,
+    emission_factor: Option<f64>,
+    rate_limit_per_minute: Option<u32>,
+    energy_source: Option<EnergySource>,
+    grid_region: Option<String>,
+    ghg_category: Option<GhgCategory>,
+    scope2_method: Option<Scope2Method>,
+}
+
+// The device a delegated principal submits readings for
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct DeviceBinding {
+    owner: Principal,
+    device_id: String,
+}
+
+impl_storable!(Device, DeviceBinding);
+
+thread_local! {
+    // Keyed by (owner, device id)
+    static DEVICES: RefCell<StableBTreeMap<(Principal, DeviceId), Device, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEVICES_MEMORY_ID)));
+
+    // Keyed by device principal
+    static DEVICE_PRINCIPALS: RefCell<StableBTreeMap<Principal, DeviceBinding, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEVICE_PRINCIPALS_MEMORY_ID)));
+}
+
+fn validate_device_id(id: &str) -> Result<(), GreenGaugeError> {
+    if id.is_empty() || id.len() > MAX_DEVICE_ID_LENGTH {
+        return Err(GreenGaugeError::invalid_input(
+            "id",
+            format!("must be between 1 and {} bytes long", MAX_DEVICE_ID_LENGTH),
+        ));
+    }
+
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':');
+    if !id.chars().all(allowed) {
+        return Err(GreenGaugeError::invalid_input(
+            "id",
+            "may only contain ASCII letters, digits, '-', '_', '.' and ':'",
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_device_type(device_type: &str) -> Result<(), GreenGaugeError> {
+    if !VALID_DEVICE_TYPES.contains(&device_type) {
+        return Err(GreenGaugeError::invalid_input(
+            "device_type",
+            format!("must be one of: {}", VALID_DEVICE_TYPES.join(", ")),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_meter_unit(meter_unit: &str) -> Result<(), GreenGaugeError> {
+    if !VALID_METER_UNITS.contains(&meter_unit) {
+        return Err(GreenGaugeError::invalid_input(
+            "meter_unit",
+            format!("must be one of: {}", VALID_METER_UNITS.join(", ")),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_emission_factor(emission_factor: f64) -> Result<(), GreenGaugeError> {
+    if Intensity::from_kg_f64(emission_factor, Rounding::HalfUp).is_none() {
+        return Err(GreenGaugeError::invalid_input(
+            "emission_factor",
+            "must be a non-negative number",
+        ));
+    }
+    Ok(())
+}
+
+// Published factors are per kWh, so a device with an energy source has to
+// meter energy. Without a source it needs its own factor. Every device must
+// resolve to a GHG category.
+fn validate_conversion(device: &Device) -> Result<(), GreenGaugeError> {
+    if device.energy_source.is_some() {
+        if !KWH_PER_METER_UNIT.iter().any(|(unit, _)| *unit == device.meter_unit) {
+            return Err(GreenGaugeError::invalid_input(
+                "meter_unit",
+                "must be kWh or MWh for a device with an energy source",
+            ));
+        }
+    } else if device.emission_factor.is_none() {
+        return Err(GreenGaugeError::invalid_input(
+            "energy_source",
+            "is required unless the device has a custom emission_factor",
+        ));
+    }
+
+    if default_category(device).is_none() {
+        return Err(GreenGaugeError::invalid_input(
+            "ghg_category",
+            "is required for a sensor without an energy source",
+        ));
+    }
+    Ok(())
+}
+
+// Burning fuel on site is a direct Scope 1 emission, bought electricity and
+// heat fall under Scope 2
+fn default_category(device: &Device) -> Option<GhgCategory> {
+    if device.ghg_category.is_some() {
+        return device.ghg_category;
+    }
+
+    match device.energy_source {
+        Some(EnergySource::Electricity) => Some(GhgCategory::PurchasedElectricity),
+        Some(EnergySource::DistrictHeat) => Some(GhgCategory::PurchasedHeat),
+        Some(_) => Some(GhgCategory::StationaryCombustion),
+        None => match device.device_type.as_str() {
+            "electricity_meter" => Some(GhgCategory::PurchasedElectricity),
+            "heat_meter" => Some(GhgCategory::PurchasedHeat),
+            "gas_meter" | "fuel_meter" => Some(GhgCategory::StationaryCombustion),
+            _ => None,
+        },
+    }
+}
+
+// How the readings of a device are reported. Scope 2 readings converted with
+// a published grid factor are location-based; a device's own factor is taken
+// to come from its supplier contract, which makes them market-based.
+pub fn classification(device: &Device) -> Result<GhgClassification, GreenGaugeError> {
+    let category = default_category(device).ok_or_else(|| {
+        GreenGaugeError::invalid_state(format!("Device {} has no GHG category", device.id))
+    })?;
+    let scope2_method = device.scope2_method.unwrap_or(if device.energy_source.is_some() {
+        Scope2Method::LocationBased
+    } else {
+        Scope2Method::MarketBased
+    });
+    Ok(GhgClassification::new(category, scope2_method))
+}
+
+fn validate_rate_limit(rate_limit_per_minute: u32) -> Result<(), GreenGaugeError> {
+    if rate_limit_per_minute == 0 || rate_limit_per_minute > MAX_SUBMISSIONS_PER_MINUTE {
+        return Err(GreenGaugeError::invalid_input(
+            "rate_limit_per_minute",
+            format!("must be between 1 and {}", MAX_SUBMISSIONS_PER_MINUTE),
+        ));
+    }
+    Ok(())
+}
+
+fn key(owner: Principal, id: &str) -> (Principal, DeviceId) {
+    (owner, DeviceId(id.to_string()))
+}
+
+fn get(owner: Principal, id: &str) -> Result<Device, GreenGaugeError> {
+    DEVICES
+        .with(|devices| devices.borrow().get(&key(owner, id)))
+        .ok_or_else(|| GreenGaugeError::not_found("device", id))
+}
+
+// Readings may only come from a registered device that is still in service
+pub fn ensure_active(owner: Principal, id: &str) -> Result<Device, GreenGaugeError> {
+    let device = get(owner, id)?;
+    if device.status == DeviceStatus::Decommissioned {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Device {} has been decommissioned",
+            id
+        )));
+    }
+    Ok(device)
+}
+
+fn save(device: &Device) {
+    DEVICES.with(|devices| {
+        devices
+            .borrow_mut()
+            .insert(key(device.owner, &device.id), device.clone());
+    });
+}
+
+fn unbind_principal(device: &mut Device) {
+    if let Some(principal) = device.principal.take() {
+        DEVICE_PRINCIPALS.with(|bindings| bindings.borrow_mut().remove(&principal));
+    }
+}
+
+// The owner and device id a delegated device principal acts for
+pub fn resolve_principal(principal: Principal) -> Option<(Principal, String)> {
+    DEVICE_PRINCIPALS.with(|bindings| {
+        bindings
+            .borrow()
+            .get(&principal)
+            .map(|binding| (binding.owner, binding.device_id))
+    })
+}
+
+// Open a submission of readings from a device. The device must be active and
+// within its rate limit, where a whole batch counts as one submission. The
+// returned device carries the updated window and only takes effect once it is
+// passed to `finish_submission`.
+pub fn start_submission(owner: Principal, id: &str, now: u64) -> Result<Device, GreenGaugeError> {
+    let mut device = ensure_active(owner, id)?;
+
+    let limit = device.rate_limit_per_minute.unwrap_or(DEFAULT_SUBMISSIONS_PER_MINUTE);
+    let (window_started_at, submissions_in_window) = match device.window_started_at {
+        Some(started_at) if now < started_at + RATE_LIMIT_WINDOW_NANOS => {
+            (started_at, device.submissions_in_window.unwrap_or(0))
+        }
+        _ => (now, 0),
+    };
+
+    if submissions_in_window >= limit {
+        return Err(GreenGaugeError::RateLimited {
+            retry_at: window_started_at + RATE_LIMIT_WINDOW_NANOS,
+        });
+    }
+
+    device.window_started_at = Some(window_started_at);
+    device.submissions_in_window = Some(submissions_in_window + 1);
+    device.last_reading_at = Some(now);
+    Ok(device)
+}
+
+// Readings signed by a device principal must carry strictly increasing
+// sequence numbers
+pub fn accept_sequence(device: &mut Device, sequence: Option<u64>) -> Result<(), GreenGaugeError> {
+    let Some(sequence) = sequence else {
+        return Ok(());
+    };
+
+    if let Some(last_accepted) = device.last_sequence {
+        if sequence <= last_accepted {
+            return Err(GreenGaugeError::StaleSequence { last_accepted });
+        }
+    }
+
+    device.last_sequence = Some(sequence);
+    Ok(())
+}
+
+// A device cannot have taken readings before it was registered, so older
+// timestamps would only serve to backdate emissions
+pub fn check_recorded_at(device: &Device, recorded_at: Option<u64>) -> Result<(), GreenGaugeError> {
+    if recorded_at.is_some_and(|recorded_at| recorded_at < device.registered_at) {
+        return Err(GreenGaugeError::invalid_input(
+            "recorded_at",
+            "is before the device was registered",
+        ));
+    }
+    Ok(())
+}
+
+// The factor that converts a reading taken at `timestamp` into kg CO2e
+pub fn emission_factor(device: &Device, timestamp: u64) -> Result<AppliedFactor, GreenGaugeError> {
+    let Some(source) = device.energy_source else {
+        let kg_co2e_per_unit = device.emission_factor.ok_or_else(|| {
+            GreenGaugeError::invalid_state(format!("Device {} has no emission factor", device.id))
+        })?;
+        let intensity = Intensity::from_kg_f64(kg_co2e_per_unit, Rounding::HalfUp)
+            .ok_or_else(|| GreenGaugeError::invalid_state(format!("Device {} has an invalid emission factor", device.id)))?;
+        return Ok(AppliedFactor::custom(intensity, &device.meter_unit));
+    };
+
+    let kwh_per_unit = KWH_PER_METER_UNIT
+        .iter()
+        .find(|(unit, _)| *unit == device.meter_unit)
+        .map(|(_, kwh)| *kwh)
+        .ok_or_else(|| {
+            GreenGaugeError::invalid_state(format!("Device {} does not meter energy", device.id))
+        })?;
+    let region = device.grid_region.as_deref().unwrap_or(emission_factors::GLOBAL_REGION);
+    let factor = emission_factors::resolve(source, region, emission_factors::year_of(timestamp))?;
+    AppliedFactor::published(&factor, kwh_per_unit, &device.meter_unit)
+        .ok_or_else(|| GreenGaugeError::overflow("emission_factor"))
+}
+
+pub fn finish_submission(device: &Device) {
+    save(device);
+}
+
+fn devices_of(owner: Principal) -> Vec<Device> {
+    DEVICES.with(|devices| {
+        devices
+            .borrow()
+            .range((owner, DeviceId::default())..)
+            .take_while(|((device_owner, _), _)| *device_owner == owner)
+            .map(|(_, device)| device)
+            .collect()
+    })
+}
+
+// Remove every device of an owner, returning how many were removed
+pub fn remove_all_of(owner: Principal) -> u64 {
+    let owned = devices_of(owner);
+
+    DEVICES.with(|devices| {
+        let mut devices_map = devices.borrow_mut();
+        for device in &owned {
+            devices_map.remove(&key(owner, &device.id));
+        }
+    });
+
+    DEVICE_PRINCIPALS.with(|bindings| {
+        let mut bindings_map = bindings.borrow_mut();
+        for principal in owned.iter().filter_map(|device| device.principal) {
+            bindings_map.remove(&principal);
+        }
+    });
+
+    owned.len() as u64
+}
+
+// Register a new metering device for the caller
+#[update]
+fn add_device(registration: DeviceRegistration) -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    validate_device_id(&registration.id)?;
+    validate_device_type(&registration.device_type)?;
+    validate_meter_unit(&registration.meter_unit)?;
+    if let Some(emission_factor) = registration.emission_factor {
+        validate_emission_factor(emission_factor)?;
+    }
+    if let Some(grid_region) = &registration.grid_region {
+        emission_factors::validate_region(grid_region)?;
+    }
+
+    // Ids of decommissioned devices stay reserved
+    if get(caller, &registration.id).is_ok() {
+        return Err(GreenGaugeError::already_exists("device", &registration.id));
+    }
+
+    let now = ic_cdk::api::time();
+    let device = Device {
+        id: registration.id,
+        owner: caller,
+        device_type: registration.device_type,
+        location: registration.location,
+        meter_unit: registration.meter_unit,
+        emission_factor: registration.emission_factor,
+        status: DeviceStatus::Active,
+        registered_at: now,
+        updated_at: now,
+        principal: None,
+        rate_limit_per_minute: None,
+        last_sequence: None,
+        last_reading_at: None,
+        window_started_at: None,
+        submissions_in_window: None,
+        energy_source: registration.energy_source,
+        grid_region: registration.grid_region,
+        ghg_category: registration.ghg_category,
+        scope2_method: registration.scope2_method,
+    };
+    validate_conversion(&device)?;
+
+    save(&device);
+    Ok(device)
+}
+
+// Change the details of one of the caller's devices
+#[update]
+fn update_device(device_id: String, update: DeviceUpdate) -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    let mut device = ensure_active(caller, &device_id)?;
+
+    if let Some(device_type) = update.device_type {
+        validate_device_type(&device_type)?;
+        device.device_type = device_type;
+    }
+
+    if let Some(meter_unit) = update.meter_unit {
+        validate_meter_unit(&meter_unit)?;
+        device.meter_unit = meter_unit;
+    }
+
+    if let Some(emission_factor) = update.emission_factor {
+        validate_emission_factor(emission_factor)?;
+        device.emission_factor = Some(emission_factor);
+    }
+
+    if let Some(energy_source) = update.energy_source {
+        device.energy_source = Some(energy_source);
+    }
+
+    if let Some(grid_region) = update.grid_region {
+        emission_factors::validate_region(&grid_region)?;
+        device.grid_region = Some(grid_region);
+    }
+
+    if let Some(ghg_category) = update.ghg_category {
+        device.ghg_category = Some(ghg_category);
+    }
+
+    if let Some(scope2_method) = update.scope2_method {
+        device.scope2_method = Some(scope2_method);
+    }
+
+    if let Some(rate_limit_per_minute) = update.rate_limit_per_minute {
+        validate_rate_limit(rate_limit_per_minute)?;
+        device.rate_limit_per_minute = Some(rate_limit_per_minute);
+    }
+
+    if let Some(location) = update.location {
+        device.location = location;
+    }
+    validate_conversion(&device)?;
+
+    device.updated_at = ic_cdk::api::time();
+    save(&device);
+    Ok(device)
+}
+
+// List the caller's devices, including decommissioned ones
+#[query]
+fn get_user_devices() -> Result<Vec<Device>, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    Ok(devices_of(caller))
+}
+
+// Look up one of the caller's devices
+#[query]
+fn check_device(device_id: String) -> Result<Device, GreenGaugeError> {
+    get(caller(), &device_id)
+}
+
+// Decommission one of the caller's devices. Its readings are kept, and its
+// principal loses the right to submit new ones.
+#[update]
+fn delete_device(device_id: String) -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    let mut device = ensure_active(caller, &device_id)?;
+
+    unbind_principal(&mut device);
+    device.status = DeviceStatus::Decommissioned;
+    device.updated_at = ic_cdk::api::time();
+    save(&device);
+
+    Ok(device)
+}
+
+// Let a principal submit readings for one of the caller's devices. A device
+// that already has a principal is re-keyed: the old principal stops working
+// immediately. Sequence numbers carry over, so the new key must continue
+// above `last_sequence`. Owners need the device operator role; revoking a
+// principal stays open to every owner, so a leaked key can always be cut off.
+#[update(guard = "caller_is_device_operator")]
+fn authorize_device_principal(device_id: String, principal: Principal) -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    let mut device = ensure_active(caller, &device_id)?;
+
+    if principal == Principal::anonymous() || principal == caller {
+        return Err(GreenGaugeError::invalid_input(
+            "principal",
+            "must be a dedicated device identity",
+        ));
+    }
+
+    if let Some((owner, bound_id)) = resolve_principal(principal) {
+        return Err(GreenGaugeError::already_exists(
+            "device principal",
+            format!("{} ({} {})", principal, owner, bound_id),
+        ));
+    }
+
+    unbind_principal(&mut device);
+    DEVICE_PRINCIPALS.with(|bindings| {
+        bindings.borrow_mut().insert(
+            principal,
+            DeviceBinding {
+                owner: caller,
+                device_id: device_id.clone(),
+            },
+        );
+    });
+
+    device.principal = Some(principal);
+    device.updated_at = ic_cdk::api::time();
+    save(&device);
+
+    Ok(device)
+}
+
+// Withdraw the principal of one of the caller's devices
+#[update]
+fn revoke_device_principal(device_id: String) -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    let mut device = get(caller, &device_id)?;
+
+    if device.principal.is_none() {
+        return Err(GreenGaugeError::not_found("device principal", &device_id));
+    }
+
+    unbind_principal(&mut device);
+    device.updated_at = ic_cdk::api::time();
+    save(&device);
+
+    Ok(device)
+}
+
+// Look up the device the calling device principal acts for, e.g. to resume
+// from its last accepted sequence number
+#[query]
+fn get_my_device() -> Result<Device, GreenGaugeError> {
+    let caller = caller();
+    let (owner, device_id) = resolve_principal(caller)
+        .ok_or_else(|| GreenGaugeError::not_found("device principal", caller))?;
+    get(owner, &device_id)
+}
@@ -0,0 +1,453 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::amount::{self, Co2e, Intensity, Rounding};
+use crate::error::GreenGaugeError;
+use crate::roles::caller_is_admin;
+use crate::{get_memory, impl_storable, Memory, EMISSION_FACTORS_MEMORY_ID};
+
+// Factors published without a more specific grid region apply everywhere
+pub const GLOBAL_REGION: &str = "GLOBAL";
+pub const MAX_REGION_LENGTH: usize = 16;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EnergySource {
+    Electricity,
+    NaturalGas,
+    Diesel,
+    Petrol,
+    Lpg,
+    FuelOil,
+    Coal,
+    DistrictHeat,
+}
+
+impl EnergySource {
+    const ALL: [EnergySource; 8] = [
+        EnergySource::Electricity,
+        EnergySource::NaturalGas,
+        EnergySource::Diesel,
+        EnergySource::Petrol,
+        EnergySource::Lpg,
+        EnergySource::FuelOil,
+        EnergySource::Coal,
+        EnergySource::DistrictHeat,
+    ];
+
+    // Stable one-byte code used in storage keys. Never renumber.
+    fn code(self) -> u8 {
+        match self {
+            EnergySource::Electricity => 0,
+            EnergySource::NaturalGas => 1,
+            EnergySource::Diesel => 2,
+            EnergySource::Petrol => 3,
+            EnergySource::Lpg => 4,
+            EnergySource::FuelOil => 5,
+            EnergySource::Coal => 6,
+            EnergySource::DistrictHeat => 7,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        EnergySource::ALL
+            .into_iter()
+            .find(|source| source.code() == code)
+            .expect("unknown energy source code")
+    }
+}
+
+// One published version of the factor for an energy source in a grid region
+// and year. Publishing again for the same source, region and year adds a new
+// version; earlier versions are kept so that stored readings stay auditable.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EmissionFactor {
+    source: EnergySource,
+    region: String,
+    year: u16,
+    version: u32,
+    // Absent for factors published after factors became fixed-point
+    kg_co2e_per_kwh: Option<f64>,
+    reference: String,  // where the value comes from
+    published_by: Principal,
+    published_at: u64,
+    // Absent for factors published before factors became fixed-point
+    mg_co2e_per_kwh: Option<Intensity>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EmissionFactorInput {
+    source: EnergySource,
+    region: String,
+    year: u16,
+    mg_co2e_per_kwh: Intensity,
+    reference: String,
+}
+
+// Identifies the factor version a reading was converted with
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FactorVersion {
+    source: EnergySource,
+    region: String,
+    year: u16,
+    version: u32,
+}
+
+// The conversion applied to a stored reading. `version` is absent when the
+// device has its own custom factor.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AppliedFactor {
+    // Absent for readings converted after factors became fixed-point
+    kg_co2e_per_unit: Option<f64>,
+    unit: String,
+    version: Option<FactorVersion>,
+    // Absent for readings converted before factors became fixed-point
+    mg_co2e_per_unit: Option<Intensity>,
+}
+
+impl AppliedFactor {
+    pub fn custom(mg_co2e_per_unit: Intensity, unit: &str) -> Self {
+        AppliedFactor {
+            kg_co2e_per_unit: None,
+            unit: unit.to_string(),
+            version: None,
+            mg_co2e_per_unit: Some(mg_co2e_per_unit),
+        }
+    }
+
+    // None if the factor per meter unit does not fit
+    pub fn published(factor: &EmissionFactor, kwh_per_unit: u64, unit: &str) -> Option<Self> {
+        Some(AppliedFactor {
+            kg_co2e_per_unit: None,
+            unit: unit.to_string(),
+            version: Some(factor.version()),
+            mg_co2e_per_unit: Some(factor.intensity().checked_mul(kwh_per_unit)?),
+        })
+    }
+
+    fn intensity(&self) -> Intensity {
+        self.mg_co2e_per_unit
+            .or_else(|| Intensity::from_kg_f64(self.kg_co2e_per_unit?, Rounding::HalfUp))
+            .unwrap_or(Intensity::ZERO)
+    }
+
+    // Emissions for a consumption in the factor's unit, to the nearest gram.
+    // None unless the consumption is non-negative and the result fits.
+    pub fn apply(&self, consumption: f32) -> Option<Co2e> {
+        let milli_units = amount::to_fixed(consumption as f64, 1000, Rounding::HalfUp)?;
+        self.intensity().emissions_of(milli_units)
+    }
+}
+
+impl_storable!(EmissionFactor);
+
+// Fixed-size key ordered by source, region, year and version, so that the
+// newest version of the most recent year up to a given one is a single
+// reverse range lookup
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct FactorKey {
+    source: u8,
+    region: [u8; MAX_REGION_LENGTH],
+    year: u16,
+    version: u32,
+}
+
+impl FactorKey {
+    fn new(source: EnergySource, region: &str, year: u16, version: u32) -> Self {
+        let mut padded = [0; MAX_REGION_LENGTH];
+        padded[..region.len()].copy_from_slice(region.as_bytes());
+        FactorKey {
+            source: source.code(),
+            region: padded,
+            year,
+            version,
+        }
+    }
+}
+
+const FACTOR_KEY_SIZE: usize = 1 + MAX_REGION_LENGTH + 2 + 4;
+
+impl Storable for FactorKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(FACTOR_KEY_SIZE);
+        bytes.push(self.source);
+        bytes.extend_from_slice(&self.region);
+        bytes.extend_from_slice(&self.year.to_be_bytes());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut region = [0; MAX_REGION_LENGTH];
+        region.copy_from_slice(&bytes[1..1 + MAX_REGION_LENGTH]);
+        let year_at = 1 + MAX_REGION_LENGTH;
+        FactorKey {
+            source: bytes[0],
+            region,
+            year: u16::from_be_bytes([bytes[year_at], bytes[year_at + 1]]),
+            version: u32::from_be_bytes(
+                bytes[year_at + 2..FACTOR_KEY_SIZE]
+                    .try_into()
+                    .expect("factor key has a fixed size"),
+            ),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: FACTOR_KEY_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+thread_local! {
+    static EMISSION_FACTORS: RefCell<StableBTreeMap<FactorKey, EmissionFactor, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EMISSION_FACTORS_MEMORY_ID)));
+}
+
+// Region codes are upper-case, e.g. "GB", "US-CAMX" or "GLOBAL"
+pub fn validate_region(region: &str) -> Result<(), GreenGaugeError> {
+    let allowed = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-';
+    if region.is_empty() || region.len() > MAX_REGION_LENGTH || !region.chars().all(allowed) {
+        return Err(GreenGaugeError::invalid_input(
+            "region",
+            format!(
+                "must be 1 to {} upper-case letters, digits or '-'",
+                MAX_REGION_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Newest version of the most recent factor for `region` published for
+// `year` or earlier
+fn latest_in_region(source: EnergySource, region: &str, year: u16) -> Option<EmissionFactor> {
+    EMISSION_FACTORS.with(|factors| {
+        factors
+            .borrow()
+            .range(FactorKey::new(source, region, 0, 0)..=FactorKey::new(source, region, year, u32::MAX))
+            .next_back()
+            .map(|(_, factor)| factor)
+    })
+}
+
+// Newest version of the oldest factor for `region`
+fn earliest_in_region(source: EnergySource, region: &str) -> Option<EmissionFactor> {
+    let first_year = EMISSION_FACTORS.with(|factors| {
+        factors
+            .borrow()
+            .range(FactorKey::new(source, region, 0, 0)..=FactorKey::new(source, region, u16::MAX, u32::MAX))
+            .next()
+            .map(|(key, _)| key.year)
+    })?;
+    latest_in_region(source, region, first_year)
+}
+
+// The factor that applies to a reading: the grid region's own factor for the
+// year, or its latest earlier one, falling back to the global factor. A
+// reading older than every published factor uses the oldest one.
+pub fn resolve(source: EnergySource, region: &str, year: u16) -> Result<EmissionFactor, GreenGaugeError> {
+    latest_in_region(source, region, year)
+        .or_else(|| latest_in_region(source, GLOBAL_REGION, year))
+        .or_else(|| earliest_in_region(source, region))
+        .or_else(|| earliest_in_region(source, GLOBAL_REGION))
+        .ok_or_else(|| {
+            GreenGaugeError::not_found("emission factor", format!("{:?} {} {}", source, region, year))
+        })
+}
+
+impl EmissionFactor {
+    pub fn intensity(&self) -> Intensity {
+        self.mg_co2e_per_kwh
+            .or_else(|| Intensity::from_kg_f64(self.kg_co2e_per_kwh?, Rounding::HalfUp))
+            .unwrap_or(Intensity::ZERO)
+    }
+
+    pub fn version(&self) -> FactorVersion {
+        FactorVersion {
+            source: self.source,
+            region: self.region.clone(),
+            year: self.year,
+            version: self.version,
+        }
+    }
+}
+
+fn publish(input: EmissionFactorInput, publisher: Principal, now: u64) -> EmissionFactor {
+    let version = EMISSION_FACTORS.with(|factors| {
+        factors
+            .borrow()
+            .range(
+                FactorKey::new(input.source, &input.region, input.year, 0)
+                    ..=FactorKey::new(input.source, &input.region, input.year, u32::MAX),
+            )
+            .next_back()
+            .map_or(1, |(key, _)| key.version + 1)
+    });
+
+    let factor = EmissionFactor {
+        source: input.source,
+        region: input.region,
+        year: input.year,
+        version,
+        kg_co2e_per_kwh: None,
+        reference: input.reference,
+        published_by: publisher,
+        published_at: now,
+        mg_co2e_per_kwh: Some(input.mg_co2e_per_kwh),
+    };
+
+    EMISSION_FACTORS.with(|factors| {
+        factors.borrow_mut().insert(
+            FactorKey::new(factor.source, &factor.region, factor.year, factor.version),
+            factor.clone(),
+        );
+    });
+
+    factor
+}
+
+// Default global factors, in mg CO2e per kWh, one for every energy source.
+// Fuels use gross calorific values from the UK government GHG conversion
+// factors 2023; electricity is the 2022 world average grid intensity reported
+// by Ember.
+const DEFAULT_FACTORS: [(EnergySource, Intensity, &str); EnergySource::ALL.len()] = [
+    (EnergySource::Electricity, Intensity::from_mg(436_000), "Ember Global Electricity Review 2023, world average 2022"),
+    (EnergySource::NaturalGas, Intensity::from_mg(182_930), "UK GHG conversion factors 2023, natural gas, gross CV"),
+    (EnergySource::Diesel, Intensity::from_mg(239_080), "UK GHG conversion factors 2023, diesel (average biofuel blend), gross CV"),
+    (EnergySource::Petrol, Intensity::from_mg(227_190), "UK GHG conversion factors 2023, petrol (average biofuel blend), gross CV"),
+    (EnergySource::Lpg, Intensity::from_mg(214_490), "UK GHG conversion factors 2023, LPG, gross CV"),
+    (EnergySource::FuelOil, Intensity::from_mg(267_750), "UK GHG conversion factors 2023, fuel oil, gross CV"),
+    (EnergySource::Coal, Intensity::from_mg(323_940), "UK GHG conversion factors 2023, coal (industrial), gross CV"),
+    (EnergySource::DistrictHeat, Intensity::from_mg(179_650), "UK GHG conversion factors 2023, heat and steam, district heat"),
+];
+const DEFAULT_FACTOR_YEAR: u16 = 2023;
+
+// Install the default global factor of every energy source that has none
+pub fn install_defaults() {
+    install_default_factors(ic_cdk::id(), ic_cdk::api::time());
+}
+
+fn install_default_factors(publisher: Principal, now: u64) {
+    for (source, mg_co2e_per_kwh, reference) in DEFAULT_FACTORS {
+        if latest_in_region(source, GLOBAL_REGION, u16::MAX).is_some() {
+            continue;
+        }
+        publish(
+            EmissionFactorInput {
+                source,
+                region: GLOBAL_REGION.to_string(),
+                year: DEFAULT_FACTOR_YEAR,
+                mg_co2e_per_kwh,
+                reference: reference.to_string(),
+            },
+            publisher,
+            now,
+        );
+    }
+}
+
+// Calendar year of a timestamp in nanoseconds since the Unix epoch
+pub fn year_of(timestamp: u64) -> u16 {
+    // Civil-from-days conversion (Howard Hinnant's algorithm)
+    let days = (timestamp / (24 * 60 * 60 * 1_000_000_000)) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400 + if month_index >= 10 { 1 } else { 0 };
+    year as u16
+}
+
+// Publish a new factor version (admin only)
+#[update(guard = "caller_is_admin")]
+fn publish_emission_factor(input: EmissionFactorInput) -> Result<EmissionFactor, GreenGaugeError> {
+    validate_region(&input.region)?;
+
+    if input.reference.trim().is_empty() {
+        return Err(GreenGaugeError::invalid_input(
+            "reference",
+            "must name the source of the factor",
+        ));
+    }
+
+    Ok(publish(input, caller(), ic_cdk::api::time()))
+}
+
+// List every published factor version, optionally for one energy source
+#[query]
+fn get_emission_factors(source: Option<EnergySource>) -> Vec<EmissionFactor> {
+    EMISSION_FACTORS.with(|factors| {
+        factors
+            .borrow()
+            .iter()
+            .filter(|(key, _)| source.is_none_or(|source| EnergySource::from_code(key.source) == source))
+            .map(|(_, factor)| factor)
+            .collect()
+    })
+}
+
+// Get the factor that applies to readings for a source, region and year
+#[query]
+fn get_emission_factor(source: EnergySource, region: String, year: u16) -> Result<EmissionFactor, GreenGaugeError> {
+    validate_region(&region)?;
+    resolve(source, &region, year)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish_factor(source: EnergySource, region: &str, year: u16, mg: u64) {
+        let input = EmissionFactorInput {
+            source,
+            region: region.to_string(),
+            year,
+            mg_co2e_per_kwh: Intensity::from_mg(mg),
+            reference: "test".to_string(),
+        };
+        publish(input, Principal::anonymous(), 0);
+    }
+
+    fn resolved(source: EnergySource, region: &str, year: u16) -> Intensity {
+        resolve(source, region, year).unwrap().intensity()
+    }
+
+    #[test]
+    fn every_energy_source_has_a_default() {
+        install_default_factors(Principal::anonymous(), 0);
+        for source in EnergySource::ALL {
+            assert!(resolve(source, GLOBAL_REGION, DEFAULT_FACTOR_YEAR).is_ok(), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn readings_use_the_most_recent_earlier_year() {
+        publish_factor(EnergySource::Electricity, "GB", 2020, 300_000);
+        publish_factor(EnergySource::Electricity, "GB", 2023, 200_000);
+        publish_factor(EnergySource::Electricity, GLOBAL_REGION, 2022, 400_000);
+
+        assert_eq!(resolved(EnergySource::Electricity, "GB", 2022), Intensity::from_mg(300_000));
+        assert_eq!(resolved(EnergySource::Electricity, "GB", 2026), Intensity::from_mg(200_000));
+        // The region's older factor comes before a newer global one
+        assert_eq!(resolved(EnergySource::Electricity, "GB", 2021), Intensity::from_mg(300_000));
+        assert_eq!(resolved(EnergySource::Electricity, "FR", 2023), Intensity::from_mg(400_000));
+        // Readings older than every factor use the oldest
+        assert_eq!(resolved(EnergySource::Electricity, "GB", 2019), Intensity::from_mg(300_000));
+        assert!(resolve(EnergySource::Coal, "GB", 2023).is_err());
+    }
+
+    #[test]
+    fn republishing_adds_a_version() {
+        publish_factor(EnergySource::NaturalGas, "DE", 2023, 180_000);
+        publish_factor(EnergySource::NaturalGas, "DE", 2023, 190_000);
+        let factor = resolve(EnergySource::NaturalGas, "DE", 2023).unwrap();
+        assert_eq!(factor.version().version, 2);
+        assert_eq!(factor.intensity(), Intensity::from_mg(190_000));
+    }
+}
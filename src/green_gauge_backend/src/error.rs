@@ -0,0 +1,106 @@
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+// Error returned by every fallible endpoint outside the ICRC ledger, which
+// uses the error types fixed by the standard
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum GreenGaugeError {
+    // The caller has no user profile
+    NotRegistered,
+    // The caller may not perform this operation
+    Unauthorized,
+    InsufficientTokens { required: u64, available: u64 },
+    // Not enough unused carbon allowance
+    InsufficientAllowance { required: u64, available: u64 },
+    NotFound { kind: String, id: String },
+    AlreadyExists { kind: String, id: String },
+    InvalidInput { field: String, reason: String },
+    // The request is valid but the canister is not in a state to serve it
+    InvalidState { reason: String },
+    // Too many requests; retry once `retry_at` (nanoseconds since epoch) has passed
+    RateLimited { retry_at: u64 },
+    // A device reading carried a sequence number that was already used
+    StaleSequence { last_accepted: u64 },
+    // A balance or amount would exceed the largest value it can hold
+    Overflow { field: String },
+    // A balance or amount would drop below zero
+    Underflow { field: String },
+}
+
+impl GreenGaugeError {
+    pub fn not_found(kind: &str, id: impl ToString) -> Self {
+        GreenGaugeError::NotFound {
+            kind: kind.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn already_exists(kind: &str, id: impl ToString) -> Self {
+        GreenGaugeError::AlreadyExists {
+            kind: kind.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn invalid_input(field: &str, reason: impl Into<String>) -> Self {
+        GreenGaugeError::InvalidInput {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_state(reason: impl Into<String>) -> Self {
+        GreenGaugeError::InvalidState {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn overflow(field: &str) -> Self {
+        GreenGaugeError::Overflow {
+            field: field.to_string(),
+        }
+    }
+
+    pub fn underflow(field: &str) -> Self {
+        GreenGaugeError::Underflow {
+            field: field.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for GreenGaugeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GreenGaugeError::NotRegistered => write!(f, "Please register your account first"),
+            GreenGaugeError::Unauthorized => write!(f, "Caller is not authorized"),
+            GreenGaugeError::InsufficientTokens { required, available } => write!(
+                f,
+                "Not enough tokens. Required: {}, Available: {}",
+                required, available
+            ),
+            GreenGaugeError::InsufficientAllowance { required, available } => write!(
+                f,
+                "Not enough carbon allowance. Required: {}, Available: {}",
+                required, available
+            ),
+            GreenGaugeError::NotFound { kind, id } => write!(f, "No {} with id {}", kind, id),
+            GreenGaugeError::AlreadyExists { kind, id } => {
+                write!(f, "A {} with id {} already exists", kind, id)
+            }
+            GreenGaugeError::InvalidInput { field, reason } => {
+                write!(f, "Invalid {}: {}", field, reason)
+            }
+            GreenGaugeError::InvalidState { reason } => write!(f, "{}", reason),
+            GreenGaugeError::RateLimited { retry_at } => {
+                write!(f, "Rate limit exceeded, retry at {}", retry_at)
+            }
+            GreenGaugeError::StaleSequence { last_accepted } => write!(
+                f,
+                "Sequence number must be greater than {}",
+                last_accepted
+            ),
+            GreenGaugeError::Overflow { field } => write!(f, "{} would overflow", field),
+            GreenGaugeError::Underflow { field } => write!(f, "{} would drop below zero", field),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::amount::{Co2e, Rounding};
+use crate::error::GreenGaugeError;
+use crate::ghg::ReportingPeriod;
+use crate::roles::caller_is_admin;
+use crate::{
+    ensure_registered, get_memory, impl_storable, user_range, Memory, DATA_POINTS,
+    GWP_SET_MEMORY_ID,
+};
+
+// Greenhouse gases covered by the Kyoto Protocol that plants report, with the
+// most common HFC and PFC species listed individually
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Gas {
+    Co2,
+    Ch4,
+    N2o,
+    Hfc23,
+    Hfc32,
+    Hfc125,
+    Hfc134a,
+    Hfc143a,
+    Hfc152a,
+    Hfc227ea,
+    Cf4,
+    C2f6,
+    Sf6,
+    Nf3,
+}
+
+// 100-year global warming potentials from the IPCC assessment reports
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GwpSet {
+    Ar4,
+    Ar5,
+    Ar6,
+}
+
+// The UNFCCC inventory guidelines in force when multi-gas reporting was
+// introduced prescribe AR5 values
+const DEFAULT_GWP_SET: GwpSet = GwpSet::Ar5;
+
+// Rows of GWP100 values: AR4 (2007), AR5 (2013), AR6 (2021). AR6 lists
+// methane without climate-carbon feedbacks from non-fossil sources.
+const GWP_TABLE: [(Gas, [f64; 3]); 14] = [
+    (Gas::Co2, [1.0, 1.0, 1.0]),
+    (Gas::Ch4, [25.0, 28.0, 27.9]),
+    (Gas::N2o, [298.0, 265.0, 273.0]),
+    (Gas::Hfc23, [14_800.0, 12_400.0, 14_600.0]),
+    (Gas::Hfc32, [675.0, 677.0, 771.0]),
+    (Gas::Hfc125, [3_500.0, 3_170.0, 3_740.0]),
+    (Gas::Hfc134a, [1_430.0, 1_300.0, 1_530.0]),
+    (Gas::Hfc143a, [4_470.0, 4_800.0, 5_810.0]),
+    (Gas::Hfc152a, [124.0, 138.0, 164.0]),
+    (Gas::Hfc227ea, [3_220.0, 3_350.0, 3_600.0]),
+    (Gas::Cf4, [7_390.0, 6_630.0, 7_380.0]),
+    (Gas::C2f6, [12_200.0, 11_100.0, 12_400.0]),
+    (Gas::Sf6, [22_800.0, 23_500.0, 25_200.0]),
+    (Gas::Nf3, [17_200.0, 16_100.0, 17_400.0]),
+];
+
+pub const MAX_GASES_PER_READING: usize = GWP_TABLE.len();
+
+impl GwpSet {
+    pub fn gwp(self, gas: Gas) -> f64 {
+        let column = match self {
+            GwpSet::Ar4 => 0,
+            GwpSet::Ar5 => 1,
+            GwpSet::Ar6 => 2,
+        };
+        GWP_TABLE
+            .iter()
+            .find(|(listed, _)| *listed == gas)
+            .map(|(_, values)| values[column])
+            .expect("every gas has a GWP")
+    }
+}
+
+// A reported quantity of one gas, in kg
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GasQuantity {
+    pub gas: Gas,
+    pub quantity: f64,
+}
+
+// A stored gas quantity with its CO2e under the GWP set in force at ingestion
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GasEmission {
+    gas: Gas,
+    quantity: f64,
+    // In kg, derived from `co2e_grams` where present
+    co2e: f64,
+    // Absent for readings from before emissions were kept in grams
+    co2e_grams: Option<Co2e>,
+}
+
+impl GasEmission {
+    pub fn co2e(&self) -> Co2e {
+        self.co2e_grams
+            .or_else(|| Co2e::from_kg_f64(self.co2e, Rounding::HalfUp))
+            .unwrap_or(Co2e::ZERO)
+    }
+}
+
+impl_storable!(GwpSet);
+
+thread_local! {
+    static GWP_SET: RefCell<StableCell<GwpSet, Memory>> = RefCell::new(
+        StableCell::init(get_memory(GWP_SET_MEMORY_ID), DEFAULT_GWP_SET)
+            .expect("failed to initialize GWP set"),
+    );
+}
+
+pub fn active_gwp_set() -> GwpSet {
+    GWP_SET.with(|set| *set.borrow().get())
+}
+
+pub fn validate_gases(gases: &[GasQuantity]) -> Result<(), GreenGaugeError> {
+    if gases.len() > MAX_GASES_PER_READING {
+        return Err(GreenGaugeError::invalid_input(
+            "gases",
+            format!("at most {} gases fit into one reading", MAX_GASES_PER_READING),
+        ));
+    }
+
+    for (i, reported) in gases.iter().enumerate() {
+        if !reported.quantity.is_finite() || reported.quantity < 0.0 {
+            return Err(GreenGaugeError::invalid_input(
+                "gases",
+                format!("quantity of {:?} must be a non-negative number", reported.gas),
+            ));
+        }
+        if gases[..i].iter().any(|earlier| earlier.gas == reported.gas) {
+            return Err(GreenGaugeError::invalid_input(
+                "gases",
+                format!("{:?} is reported more than once", reported.gas),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// CO2e of a quantity of a gas under a GWP set, to the nearest gram
+fn gas_co2e(gas: Gas, quantity: f64, gwp_set: GwpSet) -> Option<Co2e> {
+    Co2e::from_kg_f64(quantity * gwp_set.gwp(gas), Rounding::HalfUp)
+}
+
+// Convert validated gases to CO2e with the given GWP set
+pub fn to_co2e(gases: &[GasQuantity], gwp_set: GwpSet) -> Result<Vec<GasEmission>, GreenGaugeError> {
+    gases
+        .iter()
+        .map(|reported| {
+            let co2e = gas_co2e(reported.gas, reported.quantity, gwp_set).ok_or_else(|| {
+                GreenGaugeError::invalid_input(
+                    "gases",
+                    format!("quantity of {:?} is too large", reported.gas),
+                )
+            })?;
+            Ok(GasEmission {
+                gas: reported.gas,
+                quantity: reported.quantity,
+                co2e: co2e.as_kg_f64(),
+                co2e_grams: Some(co2e),
+            })
+        })
+        .collect()
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GasTotal {
+    gas: Gas,
+    quantity: f64,
+    co2e: f64,
+    co2e_grams: Co2e,
+}
+
+// The caller's emissions over a period with every gas converted by one GWP
+// set, regardless of the set in force when the readings were stored
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GasBreakdown {
+    gwp_set: GwpSet,
+    by_gas: Vec<GasTotal>,
+    // CO2e computed from energy consumption with emission factors
+    energy_co2e: f64,
+    total_co2e: f64,
+    total_co2e_grams: Co2e,
+}
+
+// Choose the GWP set used to convert newly ingested gases (admin only)
+#[update(guard = "caller_is_admin")]
+fn set_gwp_set(gwp_set: GwpSet) {
+    GWP_SET.with(|set| {
+        set.borrow_mut()
+            .set(gwp_set)
+            .expect("failed to persist GWP set");
+    });
+}
+
+#[query]
+fn get_gwp_set() -> GwpSet {
+    active_gwp_set()
+}
+
+// List the GWP of every gas in a set
+#[query]
+fn get_gwp_values(gwp_set: GwpSet) -> Vec<(Gas, f64)> {
+    GWP_TABLE
+        .iter()
+        .map(|(gas, _)| (*gas, gwp_set.gwp(*gas)))
+        .collect()
+}
+
+// Recompute the caller's emissions over a period with a GWP set, the active
+// one by default
+#[query]
+fn get_gas_breakdown(period: ReportingPeriod, gwp_set: Option<GwpSet>) -> Result<GasBreakdown, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+    period.validate()?;
+
+    let gwp_set = gwp_set.unwrap_or_else(active_gwp_set);
+    let mut by_gas: Vec<GasTotal> = Vec::new();
+    let mut energy_co2e = Co2e::ZERO;
+
+    DATA_POINTS.with(|points| {
+        for point in points
+            .borrow()
+            .values_range(user_range(caller))
+            .filter(|point| period.contains(point.timestamp))
+        {
+            // Readings from before emission factors were applied only have
+            // the total the client reported
+            let energy = match &point.emission_factor {
+                Some(factor) => factor.apply(point.energy_consumption),
+                None => Some(point.emitted()),
+            };
+            energy_co2e = energy_co2e.saturating_add(energy.unwrap_or(Co2e::ZERO));
+
+            for stored in point.gases.unwrap_or_default() {
+                let co2e = gas_co2e(stored.gas, stored.quantity, gwp_set).unwrap_or(Co2e::ZERO);
+                match by_gas.iter_mut().find(|total| total.gas == stored.gas) {
+                    Some(total) => {
+                        total.quantity += stored.quantity;
+                        total.co2e_grams = total.co2e_grams.saturating_add(co2e);
+                    }
+                    None => by_gas.push(GasTotal {
+                        gas: stored.gas,
+                        quantity: stored.quantity,
+                        co2e: 0.0,
+                        co2e_grams: co2e,
+                    }),
+                }
+            }
+        }
+    });
+
+    by_gas.sort_by_key(|total| total.gas);
+    let mut total_co2e = energy_co2e;
+    for total in &mut by_gas {
+        total.co2e = total.co2e_grams.as_kg_f64();
+        total_co2e = total_co2e.saturating_add(total.co2e_grams);
+    }
+
+    Ok(GasBreakdown {
+        gwp_set,
+        by_gas,
+        energy_co2e: energy_co2e.as_kg_f64(),
+        total_co2e: total_co2e.as_kg_f64(),
+        total_co2e_grams: total_co2e,
+    })
+}
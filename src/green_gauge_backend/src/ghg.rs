@@ -0,0 +1,224 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::caller;
+use ic_cdk_macros::query;
+
+use crate::amount::{Co2e, Rounding};
+use crate::error::GreenGaugeError;
+use crate::{ensure_registered, EMISSION_HISTORY};
+
+// GHG Protocol scopes: direct emissions, purchased energy and the rest of the
+// value chain
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EmissionScope {
+    Scope1,
+    Scope2,
+    Scope3,
+}
+
+// Scope 1 and 2 sources as reported by the GHG Protocol Corporate Standard,
+// followed by the 15 Scope 3 categories of the Corporate Value Chain Standard
+// in their official order
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GhgCategory {
+    StationaryCombustion,
+    MobileCombustion,
+    ProcessEmissions,
+    FugitiveEmissions,
+    PurchasedElectricity,
+    PurchasedHeat,
+    PurchasedSteam,
+    PurchasedCooling,
+    PurchasedGoodsAndServices,
+    CapitalGoods,
+    FuelAndEnergyRelatedActivities,
+    UpstreamTransportationAndDistribution,
+    WasteGeneratedInOperations,
+    BusinessTravel,
+    EmployeeCommuting,
+    UpstreamLeasedAssets,
+    DownstreamTransportationAndDistribution,
+    ProcessingOfSoldProducts,
+    UseOfSoldProducts,
+    EndOfLifeTreatmentOfSoldProducts,
+    DownstreamLeasedAssets,
+    Franchises,
+    Investments,
+}
+
+impl GhgCategory {
+    pub fn scope(self) -> EmissionScope {
+        use GhgCategory::*;
+        match self {
+            StationaryCombustion | MobileCombustion | ProcessEmissions | FugitiveEmissions => {
+                EmissionScope::Scope1
+            }
+            PurchasedElectricity | PurchasedHeat | PurchasedSteam | PurchasedCooling => {
+                EmissionScope::Scope2
+            }
+            _ => EmissionScope::Scope3,
+        }
+    }
+}
+
+// Scope 2 is reported twice: with grid-average factors (location-based) and
+// with the factors of the energy actually contracted (market-based)
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope2Method {
+    LocationBased,
+    MarketBased,
+}
+
+// Scope and category of an emission record. `scope2_method` is set exactly
+// for Scope 2 records.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GhgClassification {
+    scope: EmissionScope,
+    category: GhgCategory,
+    scope2_method: Option<Scope2Method>,
+}
+
+impl GhgClassification {
+    pub fn scope(&self) -> EmissionScope {
+        self.scope
+    }
+
+    pub fn new(category: GhgCategory, scope2_method: Scope2Method) -> Self {
+        let scope = category.scope();
+        GhgClassification {
+            scope,
+            category,
+            scope2_method: (scope == EmissionScope::Scope2).then_some(scope2_method),
+        }
+    }
+}
+
+// Emissions of one classification within an emission history point
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClassifiedAmount {
+    pub classification: GhgClassification,
+    // In kg CO2e, derived from `amount_grams` where present
+    pub amount: f64,
+    // Absent for amounts recorded before emissions were kept in grams
+    pub amount_grams: Option<Co2e>,
+}
+
+impl ClassifiedAmount {
+    pub fn new(classification: GhgClassification, amount: Co2e) -> Self {
+        ClassifiedAmount {
+            classification,
+            amount: amount.as_kg_f64(),
+            amount_grams: Some(amount),
+        }
+    }
+
+    pub fn grams(&self) -> Co2e {
+        self.amount_grams
+            .or_else(|| Co2e::from_kg_f64(self.amount, Rounding::HalfUp))
+            .unwrap_or(Co2e::ZERO)
+    }
+}
+
+// Add `amount` to the entry for `classification`
+pub fn add_to(amounts: &mut Vec<ClassifiedAmount>, classification: GhgClassification, amount: Co2e) {
+    match amounts.iter_mut().find(|entry| entry.classification == classification) {
+        Some(entry) => *entry = ClassifiedAmount::new(classification, entry.grams().saturating_add(amount)),
+        None => amounts.push(ClassifiedAmount::new(classification, amount)),
+    }
+}
+
+pub fn scope_amount(amounts: &[ClassifiedAmount], scope: EmissionScope) -> Co2e {
+    amounts
+        .iter()
+        .filter(|entry| entry.classification.scope == scope)
+        .fold(Co2e::ZERO, |total, entry| total.saturating_add(entry.grams()))
+}
+
+// Inclusive range of timestamps in nanoseconds since the epoch
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReportingPeriod {
+    from_timestamp: u64,
+    to_timestamp: u64,
+}
+
+impl ReportingPeriod {
+    pub fn validate(&self) -> Result<(), GreenGaugeError> {
+        if self.from_timestamp > self.to_timestamp {
+            return Err(GreenGaugeError::invalid_input(
+                "period",
+                "from_timestamp must not be after to_timestamp",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, timestamp: u64) -> bool {
+        (self.from_timestamp..=self.to_timestamp).contains(&timestamp)
+    }
+}
+
+// Emissions in kg CO2e over a reporting period. Every record was converted
+// with a single Scope 2 method, so `total` counts each record once.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ScopeBreakdown {
+    total: f64,
+    scope1: f64,
+    scope2_location_based: f64,
+    scope2_market_based: f64,
+    scope3: f64,
+    // Recorded before emissions were classified, or recorded manually
+    unclassified: f64,
+    by_category: Vec<ClassifiedAmount>,
+}
+
+// Split the caller's emissions over a period by scope and category
+#[query]
+fn get_scope_breakdown(period: ReportingPeriod) -> Result<ScopeBreakdown, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+    period.validate()?;
+
+    // Totals are summed in grams and only converted to kg for the report
+    let mut amounts = Vec::new();
+    let mut total = Co2e::ZERO;
+    let mut unclassified = Co2e::ZERO;
+    EMISSION_HISTORY.with(|history| {
+        for point in history
+            .borrow()
+            .values_range((caller, period.from_timestamp)..=(caller, period.to_timestamp))
+        {
+            total = total.saturating_add(point.grams());
+            let mut classified = Co2e::ZERO;
+            for entry in point.breakdown.iter().flatten() {
+                add_to(&mut amounts, entry.classification, entry.grams());
+                classified = classified.saturating_add(entry.grams());
+            }
+            // Whatever the breakdown does not cover was recorded unclassified
+            let rest = point.grams().checked_sub(classified).unwrap_or(Co2e::ZERO);
+            unclassified = unclassified.saturating_add(rest);
+        }
+    });
+
+    amounts.sort_by_key(|entry| entry.classification);
+    let (mut scope1, mut scope2_location_based, mut scope2_market_based, mut scope3) =
+        (Co2e::ZERO, Co2e::ZERO, Co2e::ZERO, Co2e::ZERO);
+    for entry in &amounts {
+        let classification = entry.classification;
+        let sum = match (classification.scope, classification.scope2_method) {
+            (EmissionScope::Scope1, _) => &mut scope1,
+            (EmissionScope::Scope2, Some(Scope2Method::MarketBased)) => &mut scope2_market_based,
+            (EmissionScope::Scope2, _) => &mut scope2_location_based,
+            (EmissionScope::Scope3, _) => &mut scope3,
+        };
+        *sum = sum.saturating_add(entry.grams());
+    }
+
+    Ok(ScopeBreakdown {
+        total: total.as_kg_f64(),
+        scope1: scope1.as_kg_f64(),
+        scope2_location_based: scope2_location_based.as_kg_f64(),
+        scope2_market_based: scope2_market_based.as_kg_f64(),
+        scope3: scope3.as_kg_f64(),
+        unclassified: unclassified.as_kg_f64(),
+        by_category: amounts,
+    })
+}
@@ -0,0 +1,458 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::auction;
+use crate::compliance::{self, Rollover};
+use crate::error::GreenGaugeError;
+use crate::listings;
+use crate::roles::caller_is_admin;
+use crate::{aggregate_efficiency, evaluate_alerts, get_memory, impl_storable, Memory, JOBS_MEMORY_ID, USERS};
+
+pub const MIN_INTERVAL_SECONDS: u64 = 60;
+pub const MAX_INTERVAL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+// Jobs that go over every participant handle this many per message, so that
+// a run stays within the instruction limit however many participants there are
+const PARTICIPANTS_PER_CHUNK: usize = 100;
+
+// Expired listings withdrawn per message
+const LISTINGS_PER_CHUNK: usize = 200;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Work the canister does on a schedule
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    // Raise alerts for readings of the past day above the daily thresholds
+    AlertEvaluation,
+    // Aggregate the readings of the last full day into efficiency metrics
+    EfficiencyAggregation,
+    // Withdraw expired listings and release their escrow
+    ListingExpiry,
+    // Open compliance periods that have started and close those past their
+    // surrender deadline
+    PeriodRollover,
+    // Award the allowances of auctions that have closed and refund their
+    // losing bids
+    AuctionSettlement,
+}
+
+impl JobKind {
+    const ALL: [JobKind; 5] = [
+        JobKind::AlertEvaluation,
+        JobKind::EfficiencyAggregation,
+        JobKind::ListingExpiry,
+        JobKind::PeriodRollover,
+        JobKind::AuctionSettlement,
+    ];
+
+    fn key(self) -> u8 {
+        self as u8
+    }
+
+    fn default_interval_seconds(self) -> u64 {
+        match self {
+            // Alerts look back one day, so evaluating more often repeats them
+            JobKind::AlertEvaluation => 24 * 60 * 60,
+            JobKind::EfficiencyAggregation => 24 * 60 * 60,
+            JobKind::ListingExpiry => 10 * 60,
+            JobKind::PeriodRollover => 60 * 60,
+            // Bids stay in escrow until their auction is settled
+            JobKind::AuctionSettlement => 5 * 60,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JobOutcome {
+    Succeeded(String),
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobRun {
+    at: u64,
+    outcome: JobOutcome,
+}
+
+// How far a run that is split over several messages has got
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JobCursor {
+    // Every chunk of a run works as of the time the run started
+    started_at: u64,
+    // The last participant handled
+    after: Option<Principal>,
+    // Alerts raised, participants aggregated, listings withdrawn or periods
+    // closed so far
+    count: u64,
+    last_chunk_at: u64,
+    // The period the rollover job is closing
+    period_id: Option<u64>,
+    // Periods the rollover job has opened so far
+    opened: Option<u64>,
+    // The last auction the settlement job handled
+    auction_id: Option<u64>,
+}
+
+impl JobCursor {
+    fn start(now: u64) -> Self {
+        JobCursor {
+            started_at: now,
+            after: None,
+            count: 0,
+            last_chunk_at: now,
+            period_id: None,
+            opened: None,
+            auction_id: None,
+        }
+    }
+}
+
+// What one message of a run did
+enum Step {
+    Finished(String),
+    // More work is left after the cursor
+    Continue(JobCursor),
+}
+
+// A job's schedule and how its last run went. Schedules are kept in stable
+// memory, so pausing and re-scheduling survive upgrades.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    kind: JobKind,
+    interval_seconds: u64,
+    paused: bool,
+    // None while paused
+    next_run_at: Option<u64>,
+    // A run that traps is rolled back along with its outcome, so it shows up
+    // only in the canister log
+    last_run: Option<JobRun>,
+    runs: u64,
+    failures: u64,
+    // Set from the moment a run starts until it finishes
+    cursor: Option<JobCursor>,
+}
+
+impl_storable!(Job);
+
+thread_local! {
+    // Keyed by `JobKind::key`
+    static JOBS: RefCell<StableBTreeMap<u8, Job, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(JOBS_MEMORY_ID)));
+
+    // Timers of the scheduled jobs. Timers do not survive upgrades.
+    static TIMERS: RefCell<BTreeMap<u8, TimerId>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn load(kind: JobKind) -> Job {
+    JOBS.with(|jobs| jobs.borrow().get(&kind.key())).unwrap_or(Job {
+        kind,
+        interval_seconds: kind.default_interval_seconds(),
+        paused: false,
+        next_run_at: None,
+        last_run: None,
+        runs: 0,
+        failures: 0,
+        cursor: None,
+    })
+}
+
+fn save(job: &Job) {
+    JOBS.with(|jobs| jobs.borrow_mut().insert(job.kind.key(), job.clone()));
+}
+
+fn unschedule(kind: JobKind) {
+    if let Some(timer) = TIMERS.with(|timers| timers.borrow_mut().remove(&kind.key())) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+// Replace the job's timer with one firing every `interval_seconds` from now
+fn schedule(job: &mut Job, now: u64) {
+    unschedule(job.kind);
+    let kind = job.kind;
+    let timer = ic_cdk_timers::set_timer_interval(Duration::from_secs(job.interval_seconds), move || run(kind));
+    TIMERS.with(|timers| timers.borrow_mut().insert(kind.key(), timer));
+    job.next_run_at = Some(now + job.interval_seconds * NANOS_PER_SECOND);
+}
+
+// The next chunk of participants, in principal order
+fn participants_after(after: Option<Principal>) -> Vec<Principal> {
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    USERS.with(|users| {
+        users
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(PARTICIPANTS_PER_CHUNK)
+            .map(|(principal, _)| principal)
+            .collect()
+    })
+}
+
+// Handle the next chunk of participants with `handle`, which is given the
+// time the run started and returns how many it counts
+fn over_participants(
+    now: u64,
+    cursor: JobCursor,
+    noun: &str,
+    handle: impl FnOnce(u64, &[Principal]) -> Result<u64, GreenGaugeError>,
+) -> Result<Step, GreenGaugeError> {
+    let chunk = participants_after(cursor.after);
+    let count = cursor.count + handle(cursor.started_at, &chunk)?;
+
+    Ok(match chunk.last() {
+        Some(&last) if chunk.len() == PARTICIPANTS_PER_CHUNK => Step::Continue(JobCursor {
+            after: Some(last),
+            count,
+            last_chunk_at: now,
+            ..cursor
+        }),
+        _ => Step::Finished(format!("{} {}", count, noun)),
+    })
+}
+
+fn expire_listings(now: u64, cursor: JobCursor) -> Result<Step, GreenGaugeError> {
+    let (withdrawn, more) = listings::sweep_expired(cursor.started_at, LISTINGS_PER_CHUNK);
+    let count = cursor.count + withdrawn;
+
+    Ok(if more {
+        Step::Continue(JobCursor {
+            count,
+            last_chunk_at: now,
+            ..cursor
+        })
+    } else {
+        Step::Finished(format!("{} listings withdrawn", count))
+    })
+}
+
+// Open the periods that are due, then close the first one past its deadline
+// a chunk of participants at a time. Once it is closed, the next message
+// looks for more periods to move on.
+fn roll_over_periods(now: u64, cursor: JobCursor) -> Result<Step, GreenGaugeError> {
+    let opened = cursor.opened.unwrap_or(0);
+    let Some(period_id) = cursor.period_id else {
+        return Ok(match compliance::roll_over(cursor.started_at)? {
+            Rollover::Done { opened: newly_opened } => Step::Finished(format!(
+                "{} periods opened, {} closed",
+                opened + newly_opened,
+                cursor.count
+            )),
+            Rollover::Closing {
+                opened: newly_opened,
+                period_id,
+            } => Step::Continue(JobCursor {
+                after: None,
+                period_id: Some(period_id),
+                opened: Some(opened + newly_opened),
+                last_chunk_at: now,
+                ..cursor
+            }),
+        });
+    };
+
+    let chunk = participants_after(cursor.after);
+    compliance::close_participants(period_id, &chunk, cursor.started_at)?;
+    Ok(Step::Continue(match chunk.last() {
+        Some(&last) if chunk.len() == PARTICIPANTS_PER_CHUNK => JobCursor {
+            after: Some(last),
+            last_chunk_at: now,
+            ..cursor
+        },
+        _ => {
+            compliance::finish_closing(period_id)?;
+            JobCursor {
+                after: None,
+                period_id: None,
+                count: cursor.count + 1,
+                last_chunk_at: now,
+                ..cursor
+            }
+        }
+    }))
+}
+
+// Settle the next auction that has closed. One that fails is logged and
+// passed over, so that it does not hold up the others; the regulator can
+// settle it by hand.
+fn settle_auctions(now: u64, cursor: JobCursor) -> Result<Step, GreenGaugeError> {
+    let Some(auction_id) = auction::next_closed(cursor.auction_id, cursor.started_at) else {
+        return Ok(Step::Finished(format!("{} auctions settled", cursor.count)));
+    };
+
+    let settled = match auction::settle(auction_id, now) {
+        Ok(_) => 1,
+        Err(error) => {
+            ic_cdk::println!("Settling auction {} failed: {}", auction_id, error);
+            0
+        }
+    };
+    Ok(Step::Continue(JobCursor {
+        auction_id: Some(auction_id),
+        count: cursor.count + settled,
+        last_chunk_at: now,
+        ..cursor
+    }))
+}
+
+fn execute(kind: JobKind, now: u64, cursor: JobCursor) -> Result<Step, GreenGaugeError> {
+    match kind {
+        JobKind::AlertEvaluation => over_participants(now, cursor, "alerts raised", evaluate_alerts),
+        JobKind::EfficiencyAggregation => {
+            over_participants(now, cursor, "participants aggregated", aggregate_efficiency)
+        }
+        JobKind::ListingExpiry => expire_listings(now, cursor),
+        JobKind::PeriodRollover => roll_over_periods(now, cursor),
+        JobKind::AuctionSettlement => settle_auctions(now, cursor),
+    }
+}
+
+// Record that a run has started, so that no other run starts before it ends
+fn begin(kind: JobKind, now: u64) -> JobCursor {
+    let mut job = load(kind);
+    let cursor = JobCursor::start(now);
+    job.cursor = Some(cursor.clone());
+    save(&job);
+    cursor
+}
+
+// Called by the job's timer. A run in progress is left to finish, unless its
+// messages stopped arriving because one of them trapped; then the run picks
+// up after the last message that went through.
+fn run(kind: JobKind) {
+    let now = ic_cdk::api::time();
+    match load(kind).cursor {
+        Some(cursor) if now < cursor.last_chunk_at + MIN_INTERVAL_SECONDS * NANOS_PER_SECOND => {}
+        Some(cursor) => step(kind, cursor),
+        None => step(kind, begin(kind, now)),
+    }
+}
+
+// Run the job for one message and, if it is not finished, continue it in the
+// next one. A message whose cursor is no longer the job's belongs to a run
+// that was paused or superseded, and does nothing.
+fn step(kind: JobKind, cursor: JobCursor) {
+    let mut job = load(kind);
+    if job.paused || job.cursor.as_ref() != Some(&cursor) {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let started_at = cursor.started_at;
+    let outcome = match execute(kind, now, cursor) {
+        Ok(Step::Continue(cursor)) => {
+            job.cursor = Some(cursor.clone());
+            save(&job);
+            ic_cdk_timers::set_timer(Duration::ZERO, move || step(kind, cursor));
+            return;
+        }
+        Ok(Step::Finished(summary)) => JobOutcome::Succeeded(summary),
+        Err(error) => {
+            ic_cdk::println!("Job {:?} failed: {}", kind, error);
+            JobOutcome::Failed(error.to_string())
+        }
+    };
+
+    job.cursor = None;
+    job.runs += 1;
+    if matches!(outcome, JobOutcome::Failed(_)) {
+        job.failures += 1;
+    }
+    job.last_run = Some(JobRun { at: now, outcome });
+    job.next_run_at = Some(started_at + job.interval_seconds * NANOS_PER_SECOND);
+    save(&job);
+}
+
+// Start a run now, next to the job's schedule
+pub fn run_now(kind: JobKind) -> Result<Job, GreenGaugeError> {
+    let job = load(kind);
+    if job.paused {
+        return Err(GreenGaugeError::invalid_state(format!("Job {:?} is paused", kind)));
+    }
+    if job.cursor.is_some() {
+        return Err(GreenGaugeError::invalid_state(format!("Job {:?} is already running", kind)));
+    }
+
+    let cursor = begin(kind, ic_cdk::api::time());
+    ic_cdk_timers::set_timer(Duration::ZERO, move || step(kind, cursor));
+    Ok(load(kind))
+}
+
+// Schedule every job that is not paused and continue the runs an upgrade
+// interrupted. Runs from both init and post_upgrade.
+pub fn start() {
+    let now = ic_cdk::api::time();
+    for kind in JobKind::ALL {
+        let mut job = load(kind);
+        if !job.paused {
+            schedule(&mut job, now);
+            if let Some(cursor) = job.cursor.clone() {
+                ic_cdk_timers::set_timer(Duration::ZERO, move || step(kind, cursor));
+            }
+        }
+        save(&job);
+    }
+}
+
+// Every job with its schedule and last run (admin only)
+#[query(guard = "caller_is_admin")]
+fn get_jobs() -> Vec<Job> {
+    JobKind::ALL.into_iter().map(load).collect()
+}
+
+// Stop running a job until it is resumed (admin only)
+#[update(guard = "caller_is_admin")]
+fn pause_job(kind: JobKind) -> Result<Job, GreenGaugeError> {
+    let mut job = load(kind);
+    if job.paused {
+        return Err(GreenGaugeError::invalid_state(format!("Job {:?} is already paused", kind)));
+    }
+
+    // A run going over the participants stops before its next chunk
+    unschedule(kind);
+    job.paused = true;
+    job.next_run_at = None;
+    job.cursor = None;
+    save(&job);
+    Ok(job)
+}
+
+// Run a paused job again, the first time one interval from now (admin only)
+#[update(guard = "caller_is_admin")]
+fn resume_job(kind: JobKind) -> Result<Job, GreenGaugeError> {
+    let mut job = load(kind);
+    if !job.paused {
+        return Err(GreenGaugeError::invalid_state(format!("Job {:?} is not paused", kind)));
+    }
+
+    job.paused = false;
+    schedule(&mut job, ic_cdk::api::time());
+    save(&job);
+    Ok(job)
+}
+
+// Change how often a job runs. A running job restarts its interval from now;
+// a paused job keeps the new interval for when it is resumed (admin only).
+#[update(guard = "caller_is_admin")]
+fn reschedule_job(kind: JobKind, interval_seconds: u64) -> Result<Job, GreenGaugeError> {
+    if !(MIN_INTERVAL_SECONDS..=MAX_INTERVAL_SECONDS).contains(&interval_seconds) {
+        return Err(GreenGaugeError::invalid_input(
+            "interval_seconds",
+            format!("must be between {} and {}", MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS),
+        ));
+    }
+
+    let mut job = load(kind);
+    job.interval_seconds = interval_seconds;
+    if !job.paused {
+        schedule(&mut job, ic_cdk::api::time());
+    }
+    save(&job);
+    Ok(job)
+}
@@ -0,0 +1,487 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::amount::Co2e;
+use crate::certified;
+use crate::error::GreenGaugeError;
+use crate::ledger::{self, Account};
+use crate::roles::{self, caller_is_auditor, Role};
+use crate::{
+    get_memory, impl_storable, init_counter, next_id, user_range, IdCounter, Memory,
+    TokenBalancePoint, UserProfile, JOURNAL_ID_MEMORY_ID, JOURNAL_INDEX_MEMORY_ID,
+    JOURNAL_MEMORY_ID, USERS,
+};
+
+pub const MAX_JOURNAL_QUERY: u32 = 500;
+
+// What a journal account holds. Tokens are counted in the GG token's
+// smallest unit, allowance in units of 1 kg CO2e and emissions in grams.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Asset {
+    Tokens,
+    Allowance,
+    Emissions,
+}
+
+// An account of the journal. Every asset has a system account on the far
+// side of issuance and retirement, so that each entry balances.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalAccount {
+    // A GG ledger account. Minted tokens come from the minting account, and
+    // burned tokens and fees go back to it.
+    Tokens(Account),
+    // Unused allowance of a participant
+    Allowance(Principal),
+    // Allowance a participant has escrowed in listings and sell orders
+    LockedAllowance(Principal),
+    // Allowance is issued from here, and surrendered or expired allowance
+    // returns here
+    AllowanceIssuer,
+    // Emissions recorded for a participant and not yet surrendered for
+    Emissions(Principal),
+    // Counterpart of recorded emissions
+    EmissionSource,
+}
+
+impl JournalAccount {
+    pub fn asset(&self) -> Asset {
+        match self {
+            JournalAccount::Tokens(_) => Asset::Tokens,
+            JournalAccount::Allowance(_)
+            | JournalAccount::LockedAllowance(_)
+            | JournalAccount::AllowanceIssuer => Asset::Allowance,
+            JournalAccount::Emissions(_) | JournalAccount::EmissionSource => Asset::Emissions,
+        }
+    }
+
+    // The principal whose entries the account is indexed under
+    pub fn holder(&self) -> Principal {
+        match self {
+            JournalAccount::Tokens(account) => account.owner,
+            JournalAccount::Allowance(principal)
+            | JournalAccount::LockedAllowance(principal)
+            | JournalAccount::Emissions(principal) => *principal,
+            JournalAccount::AllowanceIssuer | JournalAccount::EmissionSource => ic_cdk::id(),
+        }
+    }
+}
+
+// A debit takes the amount out of an account, a credit adds it
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PostingSide {
+    Debit,
+    Credit,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Posting {
+    account: JournalAccount,
+    side: PostingSide,
+    amount: u64,
+}
+
+impl Posting {
+    pub fn debit(account: JournalAccount, amount: u64) -> Self {
+        Posting {
+            account,
+            side: PostingSide::Debit,
+            amount,
+        }
+    }
+
+    pub fn credit(account: JournalAccount, amount: u64) -> Self {
+        Posting {
+            account,
+            side: PostingSide::Credit,
+            amount,
+        }
+    }
+
+    pub fn account(&self) -> JournalAccount {
+        self.account
+    }
+
+    // The change to the posting's account
+    pub fn change(&self) -> i128 {
+        match self.side {
+            PostingSide::Debit => -(self.amount as i128),
+            PostingSide::Credit => self.amount as i128,
+        }
+    }
+
+    // The change to `account`, if the posting is on it
+    fn change_to(&self, account: &JournalAccount) -> i128 {
+        if self.account == *account {
+            self.change()
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    // Balances that existed when the journal was introduced
+    OpeningBalance,
+    // ICRC-1 and ICRC-2 transfers between token holders
+    TokenTransfer,
+    // The fee burned for an ICRC-2 approval
+    ApprovalFee,
+    // Default allowance and bonus tokens of a new participant
+    Registration,
+    Reward,
+    // Token balances moved from profiles into the GG ledger
+    BalanceMigration,
+    ListingEscrow,
+    ListingRelease,
+    ListingPurchase,
+    OrderEscrow,
+    OrderRelease,
+    OrderFill,
+    AuctionEscrow,
+    AuctionRefund,
+    AuctionAward,
+    // Issuance and adjustment of free allocations
+    Allocation,
+    Surrender,
+    // Allowance left over at the end of a period without banking
+    Expiry,
+    CompliancePenalty,
+    EmissionRecorded,
+    Demo,
+}
+
+// The record a change originated from
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reference {
+    Listing(u64),
+    Order(u64),
+    Auction(u64),
+    CompliancePeriod(u64),
+    DataPoint(u64),
+}
+
+// Why balances change, recorded on every entry the change posts
+#[derive(Clone, Copy, Debug)]
+pub struct Cause {
+    reason: Reason,
+    reference: Option<Reference>,
+}
+
+impl Cause {
+    pub fn new(reason: Reason, reference: Option<Reference>) -> Self {
+        Cause { reason, reference }
+    }
+
+    // Memo of the GG ledger block for a token movement
+    pub fn memo(&self) -> Vec<u8> {
+        let memo = match self.reference {
+            Some(Reference::Listing(id)) => format!("{:?} listing {}", self.reason, id),
+            Some(Reference::Order(id)) => format!("{:?} order {}", self.reason, id),
+            Some(Reference::Auction(id)) => format!("{:?} auction {}", self.reason, id),
+            Some(Reference::CompliancePeriod(id)) => format!("{:?} period {}", self.reason, id),
+            Some(Reference::DataPoint(id)) => format!("{:?} data point {}", self.reason, id),
+            None => format!("{:?}", self.reason),
+        };
+        memo.into_bytes()
+    }
+}
+
+// One balanced change. Entries are never changed or removed once posted.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    id: u64,
+    timestamp: u64,
+    reason: Reason,
+    reference: Option<Reference>,
+    // GG ledger block of a token movement
+    block_index: Option<u64>,
+    postings: Vec<Posting>,
+}
+
+// Balance of an account as derived from the journal, next to the balance
+// the canister holds for it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JournalBalance {
+    account: JournalAccount,
+    derived: i128,
+    recorded: u64,
+}
+
+impl_storable!(JournalEntry);
+
+thread_local! {
+    static JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(JOURNAL_MEMORY_ID)));
+    static JOURNAL_ID: IdCounter = init_counter(JOURNAL_ID_MEMORY_ID, 0);
+
+    // Entries by the holder of each account they post to, keyed by
+    // (holder, entry id)
+    static JOURNAL_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(JOURNAL_INDEX_MEMORY_ID)));
+}
+
+fn balanced(postings: &[Posting]) -> bool {
+    [Asset::Tokens, Asset::Allowance, Asset::Emissions]
+        .into_iter()
+        .all(|asset| {
+            postings
+                .iter()
+                .filter(|posting| posting.account.asset() == asset)
+                .map(Posting::change)
+                .sum::<i128>()
+                == 0
+        })
+}
+
+// Append an entry. Callers post once their change can no longer fail;
+// postings of zero are dropped and an entry without postings is not kept.
+pub fn post(cause: Cause, block_index: Option<u64>, postings: Vec<Posting>) {
+    let postings: Vec<Posting> = postings.into_iter().filter(|posting| posting.amount > 0).collect();
+    if postings.is_empty() {
+        return;
+    }
+    assert!(balanced(&postings), "journal entry for {:?} does not balance", cause.reason);
+
+    let entry = JournalEntry {
+        id: next_id(&JOURNAL_ID),
+        timestamp: ic_cdk::api::time(),
+        reason: cause.reason,
+        reference: cause.reference,
+        block_index,
+        postings,
+    };
+
+    JOURNAL_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for posting in &entry.postings {
+            index.insert((posting.account.holder(), entry.id), ());
+        }
+    });
+    certified::certify_postings(&entry.postings);
+    JOURNAL.with(|journal| journal.borrow_mut().insert(entry.id, entry));
+}
+
+// Move `amount` from one account to another
+pub fn transfer(cause: Cause, from: JournalAccount, to: JournalAccount, amount: u64) {
+    post(cause, None, vec![Posting::debit(from, amount), Posting::credit(to, amount)]);
+}
+
+// Feed every posting in the journal to `f`, oldest first
+pub fn replay(mut f: impl FnMut(&[Posting])) {
+    JOURNAL.with(|journal| {
+        for (_, entry) in journal.borrow().iter() {
+            f(&entry.postings);
+        }
+    });
+}
+
+// Participants may look at their own balances; auditors at anyone's
+pub fn ensure_may_view(principal: Principal) -> Result<(), GreenGaugeError> {
+    let caller = caller();
+    if principal == caller || roles::has_role(&caller, Role::Auditor) || roles::has_role(&caller, Role::Admin) {
+        Ok(())
+    } else {
+        Err(GreenGaugeError::Unauthorized)
+    }
+}
+
+// Entries posting to an account of `holder`, oldest first
+fn entries_of(holder: Principal) -> Vec<JournalEntry> {
+    let ids: Vec<u64> = JOURNAL_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(user_range(holder))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        ids.into_iter().filter_map(|id| journal.get(&id)).collect()
+    })
+}
+
+fn derive(entries: &[JournalEntry], account: &JournalAccount) -> i128 {
+    entries
+        .iter()
+        .flat_map(|entry| &entry.postings)
+        .map(|posting| posting.change_to(account))
+        .sum()
+}
+
+// Record the balances of a canister that predates the journal as opening
+// entries. Runs on upgrade before anything else can post; once the journal
+// holds an entry it does nothing.
+pub fn open_balances() {
+    if JOURNAL.with(|journal| !journal.borrow().is_empty()) {
+        return;
+    }
+    let cause = Cause::new(Reason::OpeningBalance, None);
+
+    for (account, balance) in ledger::balances() {
+        post(
+            cause,
+            None,
+            vec![
+                Posting::debit(JournalAccount::Tokens(ledger::minting_account()), balance),
+                Posting::credit(JournalAccount::Tokens(account), balance),
+            ],
+        );
+    }
+
+    let profiles: Vec<UserProfile> = USERS.with(|users| users.borrow().values().collect());
+    for profile in profiles {
+        post_profile(cause, &profile, PostingSide::Credit);
+    }
+}
+
+// Post everything a profile holds, crediting it to the profile's accounts
+// when it appears outside the usual flows or debiting it when it disappears
+pub fn post_profile(cause: Cause, profile: &UserProfile, side: PostingSide) {
+    let holder = profile.principal;
+    let held = [
+        (JournalAccount::AllowanceIssuer, JournalAccount::Allowance(holder), profile.carbon_allowance),
+        (JournalAccount::AllowanceIssuer, JournalAccount::LockedAllowance(holder), profile.locked_allowance()),
+        (JournalAccount::EmissionSource, JournalAccount::Emissions(holder), emitted_grams(profile)),
+    ];
+    let postings = held
+        .into_iter()
+        .flat_map(|(system, account, amount)| match side {
+            PostingSide::Credit => [Posting::debit(system, amount), Posting::credit(account, amount)],
+            PostingSide::Debit => [Posting::debit(account, amount), Posting::credit(system, amount)],
+        })
+        .collect();
+    post(cause, None, postings);
+}
+
+// Emissions counted against a profile, including what has not reached a
+// whole kg yet
+pub fn emitted_grams(profile: &UserProfile) -> u64 {
+    Co2e::from_kg(profile.carbon_emitted)
+        .and_then(|emitted| emitted.checked_add(profile.carbon_emitted_remainder.unwrap_or(Co2e::ZERO)))
+        .map_or(u64::MAX, Co2e::grams)
+}
+
+// Balance of `owner`'s default token account after each of their entries
+// within a time range. Entries at the same timestamp share one point.
+pub fn token_balance_history(owner: Principal, from_timestamp: u64, to_timestamp: u64) -> Vec<TokenBalancePoint> {
+    let account = JournalAccount::Tokens(Account::of(owner));
+    let mut balance: i128 = 0;
+    let mut points: Vec<TokenBalancePoint> = Vec::new();
+
+    for entry in entries_of(owner) {
+        let change = derive(std::slice::from_ref(&entry), &account);
+        if change == 0 {
+            continue;
+        }
+        balance += change;
+        if entry.timestamp < from_timestamp || entry.timestamp > to_timestamp {
+            continue;
+        }
+
+        let point = TokenBalancePoint {
+            timestamp: entry.timestamp,
+            balance: balance.clamp(0, u64::MAX as i128) as u64,
+        };
+        match points.last_mut() {
+            Some(last) if last.timestamp == point.timestamp => *last = point,
+            _ => points.push(point),
+        }
+    }
+
+    points
+}
+
+// Entries posting to one of the caller's accounts within a time range
+#[query]
+fn get_my_journal(from_timestamp: u64, to_timestamp: u64) -> Vec<JournalEntry> {
+    entries_of(caller())
+        .into_iter()
+        .filter(|entry| (from_timestamp..=to_timestamp).contains(&entry.timestamp))
+        .collect()
+}
+
+// Page through the whole journal from entry `start` (auditors only)
+#[query(guard = "caller_is_auditor")]
+fn get_journal(start: u64, limit: u32) -> Vec<JournalEntry> {
+    JOURNAL.with(|journal| {
+        journal
+            .borrow()
+            .range(start..)
+            .take(limit.min(MAX_JOURNAL_QUERY) as usize)
+            .map(|(_, entry)| entry)
+            .collect()
+    })
+}
+
+// Derive a participant's balances from the journal and compare them with
+// the balances the canister holds. Participants may check their own;
+// auditors may check anyone's.
+#[query]
+fn get_journal_balances(principal: Option<Principal>) -> Result<Vec<JournalBalance>, GreenGaugeError> {
+    let principal = principal.unwrap_or_else(caller);
+    ensure_may_view(principal)?;
+
+    let profile = USERS
+        .with(|users| users.borrow().get(&principal))
+        .ok_or_else(|| GreenGaugeError::not_found("user", principal))?;
+    let entries = entries_of(principal);
+
+    let balances = [
+        (JournalAccount::Tokens(Account::of(principal)), ledger::balance_of(principal)),
+        (JournalAccount::Allowance(principal), profile.carbon_allowance),
+        (JournalAccount::LockedAllowance(principal), profile.locked_allowance()),
+        (JournalAccount::Emissions(principal), emitted_grams(&profile)),
+    ];
+    Ok(balances
+        .into_iter()
+        .map(|(account, recorded)| JournalBalance {
+            account,
+            derived: derive(&entries, &account),
+            recorded,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(owner: Principal) -> JournalAccount {
+        JournalAccount::Tokens(Account::of(owner))
+    }
+
+    #[test]
+    fn entries_must_balance_per_asset() {
+        let (from, to, minting) = (
+            Principal::anonymous(),
+            Principal::management_canister(),
+            Principal::from_slice(&[1]),
+        );
+        let transfer_with_fee = [
+            Posting::debit(tokens(from), 11),
+            Posting::credit(tokens(to), 10),
+            Posting::credit(tokens(minting), 1),
+        ];
+        assert!(balanced(&transfer_with_fee));
+
+        // Tokens and allowance do not offset each other
+        let mixed = [
+            Posting::debit(tokens(from), 10),
+            Posting::credit(JournalAccount::Allowance(to), 10),
+        ];
+        assert!(!balanced(&mixed));
+    }
+
+    #[test]
+    fn postings_change_only_their_account() {
+        let holder = Principal::anonymous();
+        let debit = Posting::debit(JournalAccount::Allowance(holder), 7);
+        assert_eq!(debit.change_to(&JournalAccount::Allowance(holder)), -7);
+        assert_eq!(debit.change_to(&JournalAccount::LockedAllowance(holder)), 0);
+        assert_eq!(Posting::credit(tokens(holder), 3).change_to(&tokens(holder)), 3);
+    }
+}
@@ -0,0 +1,1025 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::amount;
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Posting, Reason};
+use crate::{
+    get_memory, impl_storable, init_counter, next_id, IdCounter, Memory, ALLOWANCES_MEMORY_ID,
+    BALANCES_MEMORY_ID, DEDUP_EXPIRY_MEMORY_ID, DEDUP_MEMORY_ID, LEDGER_BLOCK_ID_MEMORY_ID,
+    LEDGER_LOG_MEMORY_ID, TOTAL_SUPPLY_MEMORY_ID, USERS,
+};
+
+// GreenGauge (GG) reward token, exposed through the ICRC-1 and ICRC-2 standards.
+// Balances are kept in whole tokens, matching the marketplace prices.
+pub const TOKEN_NAME: &str = "GreenGauge Token";
+pub const TOKEN_SYMBOL: &str = "GG";
+pub const TOKEN_DECIMALS: u8 = 0;
+pub const TRANSFER_FEE: u64 = 1;
+
+const MAX_MEMO_LENGTH: usize = 32;
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+const MAX_DEDUP_PRUNE_PER_CALL: usize = 100;
+
+pub type Subaccount = [u8; 32];
+
+const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+// Platform commission on marketplace trades is paid into this canister-owned account
+const TREASURY_SUBACCOUNT: Subaccount = {
+    let mut subaccount = [0; 32];
+    subaccount[31] = 1;
+    subaccount
+};
+
+// Tokens locked for open bids and orders are held in this canister-owned account
+const ESCROW_SUBACCOUNT: Subaccount = {
+    let mut subaccount = [0; 32];
+    subaccount[31] = 2;
+    subaccount
+};
+
+type AccountKey = (Principal, Subaccount);
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    // The default account of a principal
+    pub fn of(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+
+    fn key(&self) -> AccountKey {
+        (self.owner, self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT))
+    }
+
+    fn is_default(&self) -> bool {
+        self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT) == DEFAULT_SUBACCOUNT
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    from_subaccount: Option<Subaccount>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    spender_subaccount: Option<Subaccount>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    name: String,
+    url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum LedgerOperation {
+    Mint,
+    Burn,
+    Transfer,
+    Approve,
+}
+
+// One entry of the ledger's transaction log; its key is the block index
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerTransaction {
+    operation: LedgerOperation,
+    from: Option<Account>,
+    to: Option<Account>,
+    spender: Option<Account>,
+    amount: u64,
+    fee: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StoredAllowance {
+    amount: u64,
+    expires_at: Option<u64>,
+}
+
+impl_storable!(LedgerTransaction, StoredAllowance);
+
+thread_local! {
+    static BALANCES: RefCell<StableBTreeMap<AccountKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BALANCES_MEMORY_ID)));
+
+    // Keyed by (owner account, spender account)
+    static ALLOWANCES: RefCell<StableBTreeMap<(AccountKey, AccountKey), StoredAllowance, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALLOWANCES_MEMORY_ID)));
+
+    static LEDGER_LOG: RefCell<StableBTreeMap<u64, LedgerTransaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LEDGER_LOG_MEMORY_ID)));
+    static LEDGER_BLOCK_ID: IdCounter = init_counter(LEDGER_BLOCK_ID_MEMORY_ID, 0);
+
+    static TOTAL_SUPPLY: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(TOTAL_SUPPLY_MEMORY_ID), 0)
+            .expect("failed to initialize total supply"),
+    );
+
+    // Deduplication window: request hash -> block index, plus an index by
+    // created_at_time so that expired entries can be pruned in order
+    static DEDUP: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEDUP_MEMORY_ID)));
+    static DEDUP_EXPIRY: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEDUP_EXPIRY_MEMORY_ID)));
+}
+
+// The minting account is the canister's own default account: transfers from it
+// mint new tokens and transfers to it burn them
+pub fn minting_account() -> Account {
+    Account::of(ic_cdk::id())
+}
+
+pub fn treasury_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(TREASURY_SUBACCOUNT),
+    }
+}
+
+pub fn escrow_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(ESCROW_SUBACCOUNT),
+    }
+}
+
+fn is_minting_account(account: &Account) -> bool {
+    account.owner == ic_cdk::id() && account.is_default()
+}
+
+// Escrow and treasury balances must match what the canister's own records
+// say they hold, so only the canister moves tokens into them
+fn is_reserved_account(account: &Account) -> bool {
+    account.key() == escrow_account().key() || account.key() == treasury_account().key()
+}
+
+fn balance(account: &Account) -> u64 {
+    BALANCES.with(|balances| balances.borrow().get(&account.key()).unwrap_or(0))
+}
+
+// Balance of a principal's default account
+pub fn balance_of(owner: Principal) -> u64 {
+    balance(&Account::of(owner))
+}
+
+// Every account holding tokens, with its balance
+pub fn balances() -> Vec<(Account, u64)> {
+    BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .iter()
+            .map(|((owner, subaccount), balance)| {
+                let subaccount = Some(subaccount).filter(|subaccount| *subaccount != DEFAULT_SUBACCOUNT);
+                (Account { owner, subaccount }, balance)
+            })
+            .collect()
+    })
+}
+
+fn total_supply() -> u64 {
+    TOTAL_SUPPLY.with(|supply| *supply.borrow().get())
+}
+
+fn set_total_supply(amount: u64) {
+    TOTAL_SUPPLY.with(|supply| {
+        supply
+            .borrow_mut()
+            .set(amount)
+            .expect("failed to persist total supply");
+    });
+}
+
+fn set_balance(account: &Account, amount: u64) {
+    BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        if amount == 0 {
+            balances_map.remove(&account.key());
+        } else {
+            balances_map.insert(account.key(), amount);
+        }
+    });
+}
+
+fn record(transaction: LedgerTransaction) -> u64 {
+    let block_index = next_id(&LEDGER_BLOCK_ID);
+    LEDGER_LOG.with(|log| log.borrow_mut().insert(block_index, transaction));
+    block_index
+}
+
+// Errors shared by all ledger operations, converted into each method's error type
+enum LedgerError {
+    BadFee { expected_fee: u64 },
+    BadBurn { min_burn_amount: u64 },
+    InsufficientFunds { balance: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u64 },
+    Generic { message: String },
+}
+
+impl From<LedgerError> for TransferError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::BadFee { expected_fee } => TransferError::BadFee {
+                expected_fee: Nat::from(expected_fee),
+            },
+            LedgerError::BadBurn { min_burn_amount } => TransferError::BadBurn {
+                min_burn_amount: Nat::from(min_burn_amount),
+            },
+            LedgerError::InsufficientFunds { balance } => TransferError::InsufficientFunds {
+                balance: Nat::from(balance),
+            },
+            LedgerError::TooOld => TransferError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => TransferError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            LedgerError::Generic { message } => TransferError::GenericError {
+                error_code: Nat::from(0u64),
+                message,
+            },
+        }
+    }
+}
+
+impl From<LedgerError> for ApproveError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::BadFee { expected_fee } => ApproveError::BadFee {
+                expected_fee: Nat::from(expected_fee),
+            },
+            LedgerError::InsufficientFunds { balance } => ApproveError::InsufficientFunds {
+                balance: Nat::from(balance),
+            },
+            LedgerError::TooOld => ApproveError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => ApproveError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            LedgerError::BadBurn { .. } => ApproveError::GenericError {
+                error_code: Nat::from(0u64),
+                message: "Approvals cannot burn tokens".to_string(),
+            },
+            LedgerError::Generic { message } => ApproveError::GenericError {
+                error_code: Nat::from(0u64),
+                message,
+            },
+        }
+    }
+}
+
+impl From<LedgerError> for TransferFromError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::BadFee { expected_fee } => TransferFromError::BadFee {
+                expected_fee: Nat::from(expected_fee),
+            },
+            LedgerError::BadBurn { min_burn_amount } => TransferFromError::BadBurn {
+                min_burn_amount: Nat::from(min_burn_amount),
+            },
+            LedgerError::InsufficientFunds { balance } => TransferFromError::InsufficientFunds {
+                balance: Nat::from(balance),
+            },
+            LedgerError::TooOld => TransferFromError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => TransferFromError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            LedgerError::Generic { message } => TransferFromError::GenericError {
+                error_code: Nat::from(0u64),
+                message,
+            },
+        }
+    }
+}
+
+fn nat_to_u64(value: &Nat, field: &str) -> Result<u64, LedgerError> {
+    u64::try_from(&value.0).map_err(|_| LedgerError::Generic {
+        message: format!("{} does not fit into 64 bits", field),
+    })
+}
+
+fn check_memo(memo: &Option<Vec<u8>>) -> Result<(), LedgerError> {
+    match memo {
+        Some(memo) if memo.len() > MAX_MEMO_LENGTH => Err(LedgerError::Generic {
+            message: format!("Memo must not be longer than {} bytes", MAX_MEMO_LENGTH),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn check_fee(fee: &Option<Nat>, expected_fee: u64) -> Result<(), LedgerError> {
+    match fee {
+        Some(fee) if u64::try_from(&fee.0) != Ok(expected_fee) => {
+            Err(LedgerError::BadFee { expected_fee })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_created_at_time(created_at_time: Option<u64>, now: u64) -> Result<(), LedgerError> {
+    let Some(created_at_time) = created_at_time else {
+        return Ok(());
+    };
+
+    if created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+        return Err(LedgerError::TooOld);
+    }
+
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(LedgerError::CreatedInFuture { ledger_time: now });
+    }
+
+    Ok(())
+}
+
+// Check that the sender can pay for an operation: a mint must keep the total
+// supply in range, anything else must be covered by `available`. Approvals
+// only pay the fee.
+fn check_funds(
+    operation: &LedgerOperation,
+    amount: u64,
+    fee: u64,
+    available: u64,
+    supply: u64,
+) -> Result<(), LedgerError> {
+    if let LedgerOperation::Mint = operation {
+        return match supply.checked_add(amount) {
+            Some(_) => Ok(()),
+            None => Err(LedgerError::Generic {
+                message: "Total supply would overflow".to_string(),
+            }),
+        };
+    }
+
+    let moved = if let LedgerOperation::Approve = operation { 0 } else { amount };
+    let required = moved.checked_add(fee).ok_or_else(|| LedgerError::Generic {
+        message: "Amount plus fee does not fit into 64 bits".to_string(),
+    })?;
+    if available < required {
+        return Err(LedgerError::InsufficientFunds { balance: available });
+    }
+    Ok(())
+}
+
+// Requests are only deduplicated when the client sets `created_at_time`
+fn request_hash<T: CandidType>(caller: Principal, method: &str, args: &T) -> [u8; 32] {
+    let encoded = candid::encode_args((caller, method, args)).expect("failed to encode request");
+    Sha256::digest(encoded).into()
+}
+
+fn find_duplicate(created_at_time: Option<u64>, hash: &[u8; 32]) -> Result<(), LedgerError> {
+    if created_at_time.is_none() {
+        return Ok(());
+    }
+
+    match DEDUP.with(|dedup| dedup.borrow().get(hash)) {
+        Some(duplicate_of) => Err(LedgerError::Duplicate { duplicate_of }),
+        None => Ok(()),
+    }
+}
+
+fn remember_request(created_at_time: Option<u64>, hash: [u8; 32], block_index: u64) {
+    if let Some(created_at_time) = created_at_time {
+        DEDUP.with(|dedup| dedup.borrow_mut().insert(hash, block_index));
+        DEDUP_EXPIRY.with(|expiry| expiry.borrow_mut().insert((created_at_time, hash), ()));
+    }
+}
+
+// Drop deduplication entries that have left the transaction window
+fn prune_dedup(now: u64) {
+    let cutoff = now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+    let expired: Vec<(u64, [u8; 32])> = DEDUP_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .keys()
+            .take_while(|(created_at_time, _)| *created_at_time < cutoff)
+            .take(MAX_DEDUP_PRUNE_PER_CALL)
+            .collect()
+    });
+
+    for key in expired {
+        DEDUP_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&key));
+        DEDUP.with(|dedup| dedup.borrow_mut().remove(&key.1));
+    }
+}
+
+// Move `amount` from one account to another, charging `fee` to the sender,
+// and post the movement to the journal. Minting and burning are expressed
+// through the minting account. Without `to` the block is an approval of
+// `amount` for `spender`, which moves nothing and only burns the fee. The
+// caller must have validated balances beforehand.
+#[allow(clippy::too_many_arguments)]
+fn apply_transfer(
+    from: Account,
+    to: Option<Account>,
+    spender: Option<Account>,
+    amount: u64,
+    fee: u64,
+    cause: Cause,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    now: u64,
+) -> u64 {
+    // Every balance is part of the total supply, so once the caller's checks
+    // have passed none of these can leave the range of a u64
+    let checked = |result: Result<u64, GreenGaugeError>| result.expect("transfer was validated before it was applied");
+    let moved = if to.is_some() { amount } else { 0 };
+    let operation = match to {
+        None => LedgerOperation::Approve,
+        Some(to) if is_minting_account(&from) => {
+            set_balance(&to, checked(amount::add("balance", balance(&to), amount)));
+            LedgerOperation::Mint
+        }
+        Some(to) if is_minting_account(&to) => LedgerOperation::Burn,
+        Some(to) => {
+            set_balance(&to, checked(amount::add("balance", balance(&to), amount)));
+            LedgerOperation::Transfer
+        }
+    };
+    match operation {
+        LedgerOperation::Mint => {
+            set_total_supply(checked(amount::add("total_supply", total_supply(), amount)));
+        }
+        // Fees are burned
+        _ => {
+            let debit = checked(amount::add("amount", moved, fee));
+            set_balance(&from, checked(amount::sub("balance", balance(&from), debit)));
+            let burned = if matches!(operation, LedgerOperation::Burn) { debit } else { fee };
+            set_total_supply(checked(amount::sub("total_supply", total_supply(), burned)));
+        }
+    }
+
+    let block_index = record(LedgerTransaction {
+        operation,
+        from: if is_minting_account(&from) { None } else { Some(from) },
+        to: to.filter(|to| !is_minting_account(to)),
+        spender,
+        amount,
+        fee,
+        memo,
+        created_at_time,
+        timestamp: now,
+    });
+
+    // Minting debits and burning credits the minting account, which is also
+    // where fees go
+    journal::post(
+        cause,
+        Some(block_index),
+        vec![
+            Posting::debit(JournalAccount::Tokens(from), checked(amount::add("amount", moved, fee))),
+            Posting::credit(JournalAccount::Tokens(to.unwrap_or_else(minting_account)), moved),
+            Posting::credit(JournalAccount::Tokens(minting_account()), fee),
+        ],
+    );
+    block_index
+}
+
+// Validate and execute a transfer requested through ICRC-1 or ICRC-2
+#[allow(clippy::too_many_arguments)]
+fn execute_transfer(
+    from: Account,
+    to: Account,
+    spender: Option<Account>,
+    amount: &Nat,
+    fee: &Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    hash: [u8; 32],
+) -> Result<u64, LedgerError> {
+    let now = ic_cdk::api::time();
+    let amount = nat_to_u64(amount, "amount")?;
+    check_memo(&memo)?;
+    check_created_at_time(created_at_time, now)?;
+
+    if is_reserved_account(&to) {
+        return Err(LedgerError::Generic {
+            message: "The canister's escrow and treasury accounts cannot receive transfers".to_string(),
+        });
+    }
+
+    let operation = if is_minting_account(&from) {
+        LedgerOperation::Mint
+    } else if is_minting_account(&to) {
+        LedgerOperation::Burn
+    } else {
+        LedgerOperation::Transfer
+    };
+    let expected_fee = if let LedgerOperation::Transfer = operation { TRANSFER_FEE } else { 0 };
+    check_fee(fee, expected_fee)?;
+
+    if let LedgerOperation::Burn = operation {
+        if amount < TRANSFER_FEE {
+            return Err(LedgerError::BadBurn { min_burn_amount: TRANSFER_FEE });
+        }
+    }
+
+    prune_dedup(now);
+    find_duplicate(created_at_time, &hash)?;
+    check_funds(&operation, amount, expected_fee, balance(&from), total_supply())?;
+
+    let cause = Cause::new(Reason::TokenTransfer, None);
+    let block_index = apply_transfer(from, Some(to), spender, amount, expected_fee, cause, memo, created_at_time, now);
+    remember_request(created_at_time, hash, block_index);
+    Ok(block_index)
+}
+
+// Mint new tokens into a principal's default account
+pub fn mint(to: Principal, amount: u64, cause: Cause) -> Result<u64, GreenGaugeError> {
+    total_supply()
+        .checked_add(amount)
+        .ok_or_else(|| GreenGaugeError::overflow("total_supply"))?;
+
+    let now = ic_cdk::api::time();
+    Ok(apply_transfer(
+        minting_account(),
+        Some(Account::of(to)),
+        None,
+        amount,
+        0,
+        cause,
+        Some(cause.memo()),
+        None,
+        now,
+    ))
+}
+
+// Fee-free transfer used by canister-internal flows such as marketplace settlement.
+// The caller must have checked that `from` holds at least `amount`.
+pub fn transfer_internal(from: Principal, to: Account, amount: u64, cause: Cause) -> u64 {
+    let now = ic_cdk::api::time();
+    apply_transfer(
+        Account::of(from),
+        Some(to),
+        None,
+        amount,
+        0,
+        cause,
+        Some(cause.memo()),
+        None,
+        now,
+    )
+}
+
+// Fee-free transfer out of the escrow account. The caller must only release
+// tokens it escrowed itself.
+pub fn release_escrow(to: Account, amount: u64, cause: Cause) -> u64 {
+    let now = ic_cdk::api::time();
+    apply_transfer(
+        escrow_account(),
+        Some(to),
+        None,
+        amount,
+        0,
+        cause,
+        Some(cause.memo()),
+        None,
+        now,
+    )
+}
+
+// Burn tokens from a principal's default account. The caller must have
+// checked that the account holds at least `amount`.
+pub fn burn(from: Principal, amount: u64, cause: Cause) -> u64 {
+    transfer_internal(from, minting_account(), amount, cause)
+}
+
+// Move balances still recorded on user profiles into the ledger. Profiles
+// created before the ledger existed carry their balance in `tokens`; once
+// minted it is cleared, so this is safe to run on every upgrade.
+pub fn migrate_profile_balances() {
+    let pending: Vec<(Principal, u64)> = USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| profile.tokens > 0)
+            .map(|(principal, profile)| (principal, profile.tokens))
+            .collect()
+    });
+
+    for (principal, tokens) in pending {
+        if let Err(error) = mint(principal, tokens, Cause::new(Reason::BalanceMigration, None)) {
+            ic_cdk::println!("Could not migrate balance of {}: {}", principal, error);
+            continue;
+        }
+
+        USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            if let Some(mut profile) = users_map.get(&principal) {
+                profile.tokens = 0;
+                users_map.insert(principal, profile);
+            }
+        });
+    }
+}
+
+fn current_allowance(owner: &Account, spender: &Account, now: u64) -> StoredAllowance {
+    ALLOWANCES
+        .with(|allowances| allowances.borrow().get(&(owner.key(), spender.key())))
+        .filter(|allowance| allowance.expires_at.is_none_or(|expires_at| expires_at > now))
+        .unwrap_or(StoredAllowance {
+            amount: 0,
+            expires_at: None,
+        })
+}
+
+fn set_allowance(owner: &Account, spender: &Account, allowance: StoredAllowance) {
+    ALLOWANCES.with(|allowances| {
+        let mut allowances_map = allowances.borrow_mut();
+        if allowance.amount == 0 {
+            allowances_map.remove(&(owner.key(), spender.key()));
+        } else {
+            allowances_map.insert((owner.key(), spender.key()), allowance);
+        }
+    });
+}
+
+// ICRC-1 endpoints
+
+#[query]
+fn icrc1_name() -> String {
+    TOKEN_NAME.to_string()
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    TOKEN_SYMBOL.to_string()
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    TOKEN_DECIMALS
+}
+
+#[query]
+fn icrc1_fee() -> Nat {
+    Nat::from(TRANSFER_FEE)
+}
+
+#[query]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(TOKEN_NAME.to_string())),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(TOKEN_SYMBOL.to_string())),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(TOKEN_DECIMALS))),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(TRANSFER_FEE))),
+    ]
+}
+
+#[query]
+fn icrc1_total_supply() -> Nat {
+    Nat::from(total_supply())
+}
+
+#[query]
+fn icrc1_minting_account() -> Option<Account> {
+    Some(minting_account())
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    Nat::from(balance(&account))
+}
+
+#[query]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[update]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let caller = caller();
+    let hash = request_hash(caller, "icrc1_transfer", &arg);
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    };
+
+    let block_index = execute_transfer(
+        from,
+        arg.to,
+        None,
+        &arg.amount,
+        &arg.fee,
+        arg.memo,
+        arg.created_at_time,
+        hash,
+    )?;
+    Ok(Nat::from(block_index))
+}
+
+// ICRC-2 endpoints
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    let caller = caller();
+    let hash = request_hash(caller, "icrc2_approve", &args);
+    let now = ic_cdk::api::time();
+    let from = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+
+    if from.owner == args.spender.owner {
+        return Err(ApproveError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "An account cannot approve itself".to_string(),
+        });
+    }
+
+    let amount = nat_to_u64(&args.amount, "amount")?;
+    check_memo(&args.memo)?;
+    check_created_at_time(args.created_at_time, now)?;
+    check_fee(&args.fee, TRANSFER_FEE)?;
+
+    if let Some(expires_at) = args.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+
+    prune_dedup(now);
+    find_duplicate(args.created_at_time, &hash)?;
+
+    let current = current_allowance(&from, &args.spender, now);
+    if let Some(expected) = &args.expected_allowance {
+        if u64::try_from(&expected.0) != Ok(current.amount) {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(current.amount),
+            });
+        }
+    }
+
+    check_funds(&LedgerOperation::Approve, amount, TRANSFER_FEE, balance(&from), total_supply())?;
+
+    set_allowance(
+        &from,
+        &args.spender,
+        StoredAllowance {
+            amount,
+            expires_at: args.expires_at,
+        },
+    );
+    let block_index = apply_transfer(
+        from,
+        None,
+        Some(args.spender),
+        amount,
+        TRANSFER_FEE,
+        Cause::new(Reason::ApprovalFee, None),
+        args.memo,
+        args.created_at_time,
+        now,
+    );
+    remember_request(args.created_at_time, hash, block_index);
+
+    Ok(Nat::from(block_index))
+}
+
+#[query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    let allowance = current_allowance(&args.account, &args.spender, ic_cdk::api::time());
+    Allowance {
+        allowance: Nat::from(allowance.amount),
+        expires_at: allowance.expires_at,
+    }
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let caller = caller();
+    let hash = request_hash(caller, "icrc2_transfer_from", &args);
+    let now = ic_cdk::api::time();
+    let spender = Account {
+        owner: caller,
+        subaccount: args.spender_subaccount,
+    };
+
+    if is_minting_account(&args.from) {
+        return Err(TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "Tokens cannot be minted through an allowance".to_string(),
+        });
+    }
+
+    // Spending from one's own account does not need an allowance
+    if spender.key() == args.from.key() {
+        let block_index = execute_transfer(
+            args.from,
+            args.to,
+            None,
+            &args.amount,
+            &args.fee,
+            args.memo,
+            args.created_at_time,
+            hash,
+        )?;
+        return Ok(Nat::from(block_index));
+    }
+
+    let amount = nat_to_u64(&args.amount, "amount")?;
+    let expected_fee = if is_minting_account(&args.to) { 0 } else { TRANSFER_FEE };
+    let allowance = current_allowance(&args.from, &spender, now);
+    let required = amount.saturating_add(expected_fee);
+    if allowance.amount < required {
+        return Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(allowance.amount),
+        });
+    }
+
+    let block_index = execute_transfer(
+        args.from,
+        args.to,
+        Some(spender),
+        &args.amount,
+        &args.fee,
+        args.memo,
+        args.created_at_time,
+        hash,
+    )?;
+
+    set_allowance(
+        &args.from,
+        &spender,
+        StoredAllowance {
+            amount: allowance.amount - required,
+            expires_at: allowance.expires_at,
+        },
+    );
+
+    Ok(Nat::from(block_index))
+}
+
+// Read a page of the ledger's transaction log, oldest first
+#[query]
+fn get_ledger_transactions(start: u64, length: u64) -> Vec<(u64, LedgerTransaction)> {
+    let length = length.min(1_000) as usize;
+    LEDGER_LOG.with(|log| log.borrow().range(start..).take(length).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_must_cover_amount_and_fee() {
+        let transfer = LedgerOperation::Transfer;
+        assert!(check_funds(&transfer, 10, TRANSFER_FEE, 11, 100).is_ok());
+        assert!(matches!(
+            check_funds(&transfer, 10, TRANSFER_FEE, 10, 100),
+            Err(LedgerError::InsufficientFunds { balance: 10 })
+        ));
+        assert!(matches!(
+            check_funds(&transfer, u64::MAX, TRANSFER_FEE, u64::MAX, u64::MAX),
+            Err(LedgerError::Generic { .. })
+        ));
+
+        // Burns are free, approvals only pay the fee
+        assert!(check_funds(&LedgerOperation::Burn, 10, 0, 10, 100).is_ok());
+        assert!(check_funds(&LedgerOperation::Approve, u64::MAX, TRANSFER_FEE, 1, 100).is_ok());
+        assert!(matches!(
+            check_funds(&LedgerOperation::Approve, 5, TRANSFER_FEE, 0, 100),
+            Err(LedgerError::InsufficientFunds { balance: 0 })
+        ));
+    }
+
+    #[test]
+    fn mints_cannot_overflow_the_supply() {
+        let mint = LedgerOperation::Mint;
+        assert!(check_funds(&mint, 1, 0, 0, u64::MAX - 1).is_ok());
+        assert!(matches!(check_funds(&mint, 2, 0, 0, u64::MAX - 1), Err(LedgerError::Generic { .. })));
+    }
+
+    #[test]
+    fn requests_must_name_the_fee_and_a_recent_time() {
+        assert!(check_fee(&None, TRANSFER_FEE).is_ok());
+        assert!(check_fee(&Some(Nat::from(TRANSFER_FEE)), TRANSFER_FEE).is_ok());
+        assert!(matches!(
+            check_fee(&Some(Nat::from(0u64)), TRANSFER_FEE),
+            Err(LedgerError::BadFee { expected_fee: TRANSFER_FEE })
+        ));
+
+        let now = TX_WINDOW_NANOS * 2;
+        assert!(check_created_at_time(Some(now - TX_WINDOW_NANOS), now).is_ok());
+        assert!(matches!(
+            check_created_at_time(Some(now - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS - 1), now),
+            Err(LedgerError::TooOld)
+        ));
+        assert!(matches!(
+            check_created_at_time(Some(now + PERMITTED_DRIFT_NANOS + 1), now),
+            Err(LedgerError::CreatedInFuture { ledger_time }) if ledger_time == now
+        ));
+    }
+}
@@ -1,10 +1,56 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::caller;
-use ic_cdk_macros::{init, update, query};
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::thread::LocalKey;
+
+mod allocation;
+mod amount;
+mod auction;
+mod certified;
+mod compliance;
+mod demo;
+mod devices;
+mod emission_factors;
+mod error;
+mod gases;
+mod ghg;
+mod jobs;
+mod journal;
+mod ledger;
+mod listings;
+mod orderbook;
+mod roles;
+mod settlement;
+mod telemetry;
+
+use allocation::{AllocationLogEntry, AllocationMethod, AllocationSummary};
+use amount::{Co2e, Price, Rate, Rounding};
+use auction::{Auction, AuctionInput, AuctionResult, Bid};
+use certified::{Certified, HolderBalances};
+use compliance::{
+    Allocation, CompliancePeriod, CompliancePeriodInput, ComplianceRecord, ComplianceStatus, PeriodClosure,
+};
+use demo::{CanisterMode, InitArgs};
+use devices::{Device, DeviceRegistration, DeviceUpdate};
+use emission_factors::{AppliedFactor, EmissionFactor, EmissionFactorInput, EnergySource};
+use error::GreenGaugeError;
+use gases::{Gas, GasBreakdown, GasEmission, GasQuantity, GwpSet};
+use ghg::{ClassifiedAmount, EmissionScope, GhgClassification, ReportingPeriod, ScopeBreakdown};
+use jobs::{Job, JobKind};
+use journal::{Cause, JournalAccount, JournalBalance, JournalEntry, Reason};
+use ledger::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, LedgerTransaction,
+    MetadataValue, StandardRecord, TransferArg, TransferError, TransferFromArgs,
+    TransferFromError,
+};
+use listings::{LegacyListing, Listing, ListingInput, ListingKind, OffsetDetails};
+use orderbook::{Fill, Order, OrderBook, PlacedOrder, Side};
+use roles::{caller_is_admin, caller_is_auditor, Role, RoleAssignment};
+use settlement::SettlementReceipt;
+use telemetry::{DataPointInput, Submitter};
 
 // Data Structures
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -12,6 +58,9 @@ struct UserProfile {
     principal: Principal,
     carbon_allowance: u64,
     carbon_emitted: u64,
+    // Balance of the owner's default GG ledger account, filled in when the
+    // profile is read. A non-zero stored value is a balance from before the
+    // ledger existed that has not been migrated yet.
     tokens: u64,
     has_subcontract: bool,  // New field to track if user has a subcontract
     // New fields for enhanced profile
@@ -21,6 +70,84 @@ struct UserProfile {
     location: Option<String>,
     join_date: u64,
     last_activity: u64,
+    // Allowance held in escrow by open trade offers, credit listings and sell
+    // orders. It is not part of `carbon_allowance`, so emissions, surrenders
+    // and other listings can't use it. `None` on profiles from before
+    // listings were escrowed.
+    carbon_locked: Option<u64>,
+    // Unused allowance, `carbon_allowance - carbon_emitted`, filled in when
+    // the profile is read
+    carbon_available: Option<u64>,
+    // Emissions below one kg not yet counted in `carbon_emitted`
+    carbon_emitted_remainder: Option<Co2e>,
+}
+
+impl UserProfile {
+    fn available_allowance(&self) -> u64 {
+        self.carbon_allowance.saturating_sub(self.carbon_emitted)
+    }
+
+    fn locked_allowance(&self) -> u64 {
+        self.carbon_locked.unwrap_or(0)
+    }
+
+    // Allowance held in total, free and escrowed. Every credit keeps this
+    // within a u64, so moving allowance in and out of escrow cannot overflow.
+    fn held_allowance(&self) -> Result<u64, GreenGaugeError> {
+        amount::add("carbon_allowance", self.carbon_allowance, self.locked_allowance())
+    }
+
+    // Add `amount` to the owner's allowance
+    fn credit_allowance(&mut self, amount: u64) -> Result<(), GreenGaugeError> {
+        amount::add("carbon_allowance", self.held_allowance()?, amount)?;
+        self.carbon_allowance += amount;
+        Ok(())
+    }
+
+    // Take `amount` out of the owner's allowance
+    fn debit_allowance(&mut self, amount: u64) -> Result<(), GreenGaugeError> {
+        if amount > self.carbon_allowance {
+            return Err(GreenGaugeError::InsufficientAllowance {
+                required: amount,
+                available: self.carbon_allowance,
+            });
+        }
+        self.carbon_allowance -= amount;
+        Ok(())
+    }
+
+    // Add `amount` to the emissions counted against the allowance
+    fn record_emitted(&mut self, amount: u64) -> Result<(), GreenGaugeError> {
+        self.carbon_emitted = amount::add("carbon_emitted", self.carbon_emitted, amount)?;
+        Ok(())
+    }
+
+    // Move `amount` of unused allowance into escrow
+    fn lock_allowance(&mut self, amount: u64) -> Result<(), GreenGaugeError> {
+        let available = self.available_allowance();
+        if amount > available {
+            return Err(GreenGaugeError::InsufficientAllowance {
+                required: amount,
+                available,
+            });
+        }
+        let locked = amount::add("carbon_locked", self.locked_allowance(), amount)?;
+        self.carbon_allowance -= amount;
+        self.carbon_locked = Some(locked);
+        Ok(())
+    }
+
+    // Return `amount` of escrowed allowance to the owner
+    fn unlock_allowance(&mut self, amount: u64) {
+        let released = amount.min(self.locked_allowance());
+        self.carbon_locked = Some(self.locked_allowance() - released);
+        self.carbon_allowance = self.carbon_allowance.saturating_add(released);
+    }
+
+    // Take `amount` out of escrow when a listing fills
+    fn spend_locked_allowance(&mut self, amount: u64) {
+        self.carbon_locked = Some(self.locked_allowance().saturating_sub(amount));
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -29,6 +156,10 @@ struct CarbonTrade {
     seller: Principal,
     amount: u64,
     price_per_unit: u64,
+    // The offer is withdrawn at this time (nanoseconds since epoch)
+    expires_at: Option<u64>,
+    // Offers stored before listings existed have no creation time
+    created_at: Option<u64>,
 }
 
 // New structures for enhanced marketplace
@@ -45,6 +176,8 @@ struct CarbonCredit {
     description: String,
     creation_time: u64,
     is_active: bool,
+    // The listing is deactivated at this time (nanoseconds since epoch)
+    expires_at: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -58,17 +191,51 @@ struct Transaction {
     project_name: String,
     transaction_type: String, // purchase, sale
     transaction_time: u64,
+    // Exact values behind `amount` and `price_per_unit`, which remain for
+    // older clients. Absent on transactions recorded before fixed-point
+    // amounts.
+    quantity: Option<Co2e>,
+    price: Option<Price>,
+    total_cost: Option<u64>,
 }
 
 // New structure for DataPoint (for emission and energy consumption data)
 #[derive(CandidType, Deserialize, Clone, Debug)]
 struct DataPoint {
     id: u64,
-    user_id: Principal,
+    user_id: Principal,  // owner of the device
     device_id: String,
     energy_consumption: f32,
-    carbon_emitted: f32,
+    // In kg. Only readings from before emissions were kept in grams have it.
+    carbon_emitted: Option<f32>,
     timestamp: u64,
+    // Set when the device submitted the reading with its own principal
+    device_principal: Option<Principal>,
+    sequence: Option<u64>,
+    // When the device took the reading, by its own clock. `timestamp` is
+    // the ingestion time.
+    recorded_at: Option<u64>,
+    // Conversion the emissions were computed with
+    emission_factor: Option<AppliedFactor>,
+    classification: Option<GhgClassification>,
+    // Directly measured gases and the GWP set their CO2e was computed with
+    gases: Option<Vec<GasEmission>>,
+    gwp_set: Option<GwpSet>,
+    // Absent for readings from before emissions were kept in grams
+    carbon_emitted_grams: Option<Co2e>,
+}
+
+impl DataPoint {
+    fn emitted(&self) -> Co2e {
+        self.carbon_emitted_grams
+            .or_else(|| Co2e::from_kg_f64(self.carbon_emitted? as f64, Rounding::HalfUp))
+            .unwrap_or(Co2e::ZERO)
+    }
+
+    // Consumption in thousandths of the meter unit
+    fn milli_units(&self) -> u64 {
+        amount::to_fixed(self.energy_consumption as f64, 1000, Rounding::HalfUp).unwrap_or(0)
+    }
 }
 
 // New structure for Alert system
@@ -86,7 +253,27 @@ struct Alert {
 #[derive(CandidType, Deserialize, Clone, Debug)]
 struct EmissionHistoryPoint {
     timestamp: u64,
+    // In kg CO2e, derived from `amount_grams` where present
     amount: f64,
+    // Split of `amount` by GHG scope and category. Absent for points
+    // recorded before emissions were classified; emissions recorded
+    // manually count towards `amount` only.
+    breakdown: Option<Vec<ClassifiedAmount>>,
+    // Absent for points recorded before emissions were kept in grams
+    amount_grams: Option<Co2e>,
+}
+
+impl EmissionHistoryPoint {
+    fn grams(&self) -> Co2e {
+        self.amount_grams
+            .or_else(|| Co2e::from_kg_f64(self.amount, Rounding::HalfUp))
+            .unwrap_or(Co2e::ZERO)
+    }
+
+    fn set_grams(&mut self, amount: Co2e) {
+        self.amount = amount.as_kg_f64();
+        self.amount_grams = Some(amount);
+    }
 }
 
 // New structure for TokenBalanceHistory (time series data)
@@ -101,66 +288,194 @@ struct TokenBalancePoint {
 struct EfficiencyMetric {
     date: String,
     consumption: f32,
-    carbon_emitted: f32,
+    // In kg. Only metrics from before emissions were kept in grams have it.
+    carbon_emitted: Option<f32>,
     efficiency_score: f32,
+    // Absent for metrics from before emissions were kept in grams
+    carbon_emitted_grams: Option<Co2e>,
+}
+
+// Stored values are candid-encoded so that records can gain new optional
+// fields without a manual migration step.
+macro_rules! impl_storable {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl ic_stable_structures::Storable for $t {
+                fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                    std::borrow::Cow::Owned(
+                        candid::encode_one(self).expect("failed to encode stored value"),
+                    )
+                }
+
+                fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                    candid::decode_one(&bytes).expect("failed to decode stored value")
+                }
+
+                const BOUND: ic_stable_structures::storable::Bound =
+                    ic_stable_structures::storable::Bound::Unbounded;
+            }
+        )*
+    };
 }
+pub(crate) use impl_storable;
+
+impl_storable!(
+    UserProfile,
+    CarbonTrade,
+    CarbonCredit,
+    Transaction,
+    DataPoint,
+    Alert,
+    EmissionHistoryPoint,
+    EfficiencyMetric,
+);
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+type IdCounter = RefCell<StableCell<u64, Memory>>;
+
+// Each store lives in its own virtual memory. These ids are part of the
+// canister's persistent layout: never renumber or reuse them.
+const USERS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const TRADES_MEMORY_ID: MemoryId = MemoryId::new(1);
+// Memory 2 held the trade offer id counter, retired with the listings migration
+const CARBON_CREDITS_MEMORY_ID: MemoryId = MemoryId::new(3);
+// Memory 4 held the carbon credit id counter, retired with the listings migration
+const TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const TRANSACTION_ID_MEMORY_ID: MemoryId = MemoryId::new(6);
+const DATA_POINTS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const DATA_POINT_ID_MEMORY_ID: MemoryId = MemoryId::new(8);
+const ALERTS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const ALERT_ID_MEMORY_ID: MemoryId = MemoryId::new(10);
+const EMISSION_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(11);
+// Memory 12 held token balance history, now derived from the journal
+const EFFICIENCY_METRICS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(14);
+const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(16);
+const LEDGER_LOG_MEMORY_ID: MemoryId = MemoryId::new(17);
+const LEDGER_BLOCK_ID_MEMORY_ID: MemoryId = MemoryId::new(18);
+const TOTAL_SUPPLY_MEMORY_ID: MemoryId = MemoryId::new(19);
+const DEDUP_MEMORY_ID: MemoryId = MemoryId::new(20);
+const DEDUP_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(21);
+const CANISTER_MODE_MEMORY_ID: MemoryId = MemoryId::new(22);
+const DEVICES_MEMORY_ID: MemoryId = MemoryId::new(23);
+const DEVICE_PRINCIPALS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const EMISSION_FACTORS_MEMORY_ID: MemoryId = MemoryId::new(25);
+const GWP_SET_MEMORY_ID: MemoryId = MemoryId::new(26);
+const COMPLIANCE_PERIODS_MEMORY_ID: MemoryId = MemoryId::new(27);
+const COMPLIANCE_PERIOD_ID_MEMORY_ID: MemoryId = MemoryId::new(28);
+const ALLOCATIONS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const COMPLIANCE_RECORDS_MEMORY_ID: MemoryId = MemoryId::new(30);
+const ALLOCATION_LOG_MEMORY_ID: MemoryId = MemoryId::new(31);
+const ALLOCATION_LOG_ID_MEMORY_ID: MemoryId = MemoryId::new(32);
+const AUCTIONS_MEMORY_ID: MemoryId = MemoryId::new(33);
+const AUCTION_ID_MEMORY_ID: MemoryId = MemoryId::new(34);
+const AUCTION_BIDS_MEMORY_ID: MemoryId = MemoryId::new(35);
+const ORDERS_MEMORY_ID: MemoryId = MemoryId::new(36);
+const ORDER_ID_MEMORY_ID: MemoryId = MemoryId::new(37);
+const BUY_BOOK_MEMORY_ID: MemoryId = MemoryId::new(38);
+const SELL_BOOK_MEMORY_ID: MemoryId = MemoryId::new(39);
+const FILLS_MEMORY_ID: MemoryId = MemoryId::new(40);
+const FILL_ID_MEMORY_ID: MemoryId = MemoryId::new(41);
+const LISTINGS_MEMORY_ID: MemoryId = MemoryId::new(42);
+const LISTING_ID_MEMORY_ID: MemoryId = MemoryId::new(43);
+const LISTING_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(44);
+const JOURNAL_MEMORY_ID: MemoryId = MemoryId::new(45);
+const JOURNAL_ID_MEMORY_ID: MemoryId = MemoryId::new(46);
+const JOURNAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(47);
+const JOBS_MEMORY_ID: MemoryId = MemoryId::new(48);
+const HOLDINGS_MEMORY_ID: MemoryId = MemoryId::new(49);
+const LEGACY_LISTING_IDS_MEMORY_ID: MemoryId = MemoryId::new(50);
 
 // Define thread-local variables for stable storage
 thread_local! {
-    static USERS: RefCell<BTreeMap<Principal, UserProfile>> = RefCell::new(BTreeMap::new());
-    static TRADES: RefCell<BTreeMap<u64, CarbonTrade>> = RefCell::new(BTreeMap::new());
-    static NEXT_TRADE_ID: RefCell<u64> = RefCell::new(1);
-    
-    // New storage for enhanced marketplace
-    static CARBON_CREDITS: RefCell<Vec<CarbonCredit>> = RefCell::new(Vec::new());
-    static CARBON_CREDIT_ID_COUNTER: RefCell<u64> = RefCell::new(0);
-    
-    static TRANSACTIONS: RefCell<Vec<Transaction>> = RefCell::new(Vec::new());
-    static TRANSACTION_ID_COUNTER: RefCell<u64> = RefCell::new(0);
-    
-    // New storage for data points
-    static DATA_POINTS: RefCell<Vec<DataPoint>> = RefCell::new(Vec::new());
-    static DATA_POINT_ID_COUNTER: RefCell<u64> = RefCell::new(0);
-    
-    // New storage for alerts
-    static ALERTS: RefCell<Vec<Alert>> = RefCell::new(Vec::new());
-    static ALERT_ID_COUNTER: RefCell<u64> = RefCell::new(0);
-    
-    // New storage for emission history
-    static EMISSION_HISTORY: RefCell<HashMap<Principal, Vec<EmissionHistoryPoint>>> = RefCell::new(HashMap::new());
-    
-    // New storage for token balance history
-    static TOKEN_BALANCE_HISTORY: RefCell<HashMap<Principal, Vec<TokenBalancePoint>>> = RefCell::new(HashMap::new());
-    
-    // New storage for efficiency metrics
-    static EFFICIENCY_METRICS: RefCell<HashMap<Principal, Vec<EfficiencyMetric>>> = RefCell::new(HashMap::new());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static USERS: RefCell<StableBTreeMap<Principal, UserProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USERS_MEMORY_ID)));
+    // Stores of the two former marketplaces, emptied into listings on upgrade
+    static TRADES: RefCell<StableBTreeMap<u64, CarbonTrade, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TRADES_MEMORY_ID)));
+    static CARBON_CREDITS: RefCell<StableBTreeMap<u64, CarbonCredit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CARBON_CREDITS_MEMORY_ID)));
+    
+    static TRANSACTIONS: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TRANSACTIONS_MEMORY_ID)));
+    static TRANSACTION_ID_COUNTER: IdCounter = init_counter(TRANSACTION_ID_MEMORY_ID, 0);
+    
+    // New storage for data points, keyed by (owner, data point id)
+    static DATA_POINTS: RefCell<StableBTreeMap<(Principal, u64), DataPoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DATA_POINTS_MEMORY_ID)));
+    static DATA_POINT_ID_COUNTER: IdCounter = init_counter(DATA_POINT_ID_MEMORY_ID, 0);
+    
+    // New storage for alerts, keyed by (owner, alert id)
+    static ALERTS: RefCell<StableBTreeMap<(Principal, u64), Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALERTS_MEMORY_ID)));
+    static ALERT_ID_COUNTER: IdCounter = init_counter(ALERT_ID_MEMORY_ID, 0);
+    
+    // New storage for emission history, keyed by (owner, timestamp)
+    static EMISSION_HISTORY: RefCell<StableBTreeMap<(Principal, u64), EmissionHistoryPoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EMISSION_HISTORY_MEMORY_ID)));
+    
+    // New storage for efficiency metrics, keyed by (owner, day number)
+    static EFFICIENCY_METRICS: RefCell<StableBTreeMap<(Principal, u64), EfficiencyMetric, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EFFICIENCY_METRICS_MEMORY_ID)));
 }
 
+fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+fn init_counter(id: MemoryId, first_id: u64) -> IdCounter {
+    RefCell::new(
+        StableCell::init(get_memory(id), first_id).expect("failed to initialize id counter"),
+    )
+}
+
+// Hand out the next id from a persistent counter
+fn next_id(counter: &'static LocalKey<IdCounter>) -> u64 {
+    counter.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let id = *counter.get();
+        counter
+            .set(id + 1)
+            .expect("failed to persist id counter");
+        id
+    })
+}
+
+// Key range covering every entry owned by `user` in a (Principal, u64)-keyed store
+fn user_range(user: Principal) -> std::ops::RangeInclusive<(Principal, u64)> {
+    (user, 0)..=(user, u64::MAX)
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 // Default values
 const DEFAULT_CARBON_ALLOWANCE: u64 = 1000;
 const DEFAULT_TOKENS: u64 = 0;
-const COMMISSION_RATE: f64 = 0.05; // 5% commission on trades
+const REGISTRATION_BONUS_TOKENS: u64 = 100;
+const COMMISSION_RATE: Rate = Rate::from_bps(500); // 5% commission on trades, rounded down
 
 // Register a new user
 #[update]
-fn register_user() -> Result<(), String> {
+fn register_user() -> Result<(), GreenGaugeError> {
     let caller = caller();
     if caller == Principal::anonymous() {
-        return Err("Anonymous principals cannot register".to_string());
+        return Err(GreenGaugeError::Unauthorized);
     }
 
     USERS.with(|users| {
         let mut users_map = users.borrow_mut();
-        if users_map.contains_key(&caller) {
+        if let Some(mut user) = users_map.get(&caller) {
             // If user exists but doesn't have a subcontract, deploy one now
-            let mut user = users_map.get(&caller).unwrap().clone();
             if !user.has_subcontract {
                 user.has_subcontract = true;
-                user.tokens += 100; // Bonus tokens for new users
                 users_map.insert(caller, user);
                 return Ok(());
             }
-            return Err("User already registered".to_string());
+            return Err(GreenGaugeError::already_exists("user", caller));
         }
 
         let timestamp = ic_cdk::api::time();
@@ -169,7 +484,7 @@ fn register_user() -> Result<(), String> {
             principal: caller,
             carbon_allowance: DEFAULT_CARBON_ALLOWANCE,
             carbon_emitted: 0,
-            tokens: DEFAULT_TOKENS + 100, // Give new users some tokens to start with
+            tokens: DEFAULT_TOKENS,
             has_subcontract: true,       // Deploy subcontract on registration
             username: None,
             email: None,
@@ -177,70 +492,56 @@ fn register_user() -> Result<(), String> {
             location: None,
             join_date: timestamp,
             last_activity: timestamp,
+            carbon_locked: Some(0),
+            carbon_available: None,
+            carbon_emitted_remainder: Some(Co2e::ZERO),
         };
 
         users_map.insert(caller, user_profile);
-        
-        // Initialize emission history
-        EMISSION_HISTORY.with(|history| {
-            history.borrow_mut().insert(caller, Vec::new());
-        });
-        
-        // Initialize token balance history
-        TOKEN_BALANCE_HISTORY.with(|history| {
-            let history_point = TokenBalancePoint {
-                timestamp,
-                balance: DEFAULT_TOKENS + 100,
-            };
-            history.borrow_mut().insert(caller, vec![history_point]);
-        });
-        
         Ok(())
-    })
+    })?;
+    
+    // Bonus tokens for new users, minted on the GG ledger
+    let cause = Cause::new(Reason::Registration, None);
+    journal::transfer(cause, JournalAccount::AllowanceIssuer, JournalAccount::Allowance(caller), DEFAULT_CARBON_ALLOWANCE);
+    ledger::mint(caller, REGISTRATION_BONUS_TOKENS, cause)?;
+    Ok(())
+}
+
+// Fail with `NotRegistered` unless the principal has a profile
+fn ensure_registered(user: Principal) -> Result<(), GreenGaugeError> {
+    if USERS.with(|users| users.borrow().contains_key(&user)) {
+        Ok(())
+    } else {
+        Err(GreenGaugeError::NotRegistered)
+    }
+}
+
+// Fill in the live ledger balance and unused allowance of a stored profile
+fn with_ledger_balance(mut profile: UserProfile) -> UserProfile {
+    profile.tokens = ledger::balance_of(profile.principal);
+    profile.carbon_available = Some(profile.available_allowance());
+    profile.carbon_locked = Some(profile.locked_allowance());
+    profile
 }
 
 // Get the profile of the caller
 #[query]
-fn get_user_profile() -> Result<UserProfile, String> {
+fn get_user_profile() -> Result<UserProfile, GreenGaugeError> {
     let caller = caller();
     
     USERS.with(|users| {
-        let users_map = users.borrow();
-        
-        // Return the user's profile if it exists
-        if let Some(profile) = users_map.get(&caller) {
-            return Ok(profile.clone());
-        }
-        
-        // For demo purposes, return a mock profile instead of an error
-        let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-        if let Some(mock_profile) = users_map.get(&mock_user_principal) {
-            let mut profile = mock_profile.clone();
-            profile.principal = caller;
-            return Ok(profile);
-        }
-        
-        // Fallback if no mock profile exists
-        let now = ic_cdk::api::time();
-        Ok(UserProfile {
-            principal: caller,
-            carbon_allowance: 10000,
-            carbon_emitted: 3500,
-            tokens: 7500,
-            has_subcontract: true,
-            username: Some("Default User".to_string()),
-            email: Some("user@example.com".to_string()),
-            full_name: Some("Default User".to_string()),
-            location: Some("Default Location".to_string()),
-            join_date: now - 30 * 24 * 60 * 60 * 1_000_000_000,
-            last_activity: now,
-        })
+        users
+            .borrow()
+            .get(&caller)
+            .map(with_ledger_balance)
+            .ok_or(GreenGaugeError::NotRegistered)
     })
 }
 
 // Record carbon emission
 #[update]
-fn record_emission(amount: u64) -> Result<(), String> {
+fn record_emission(amount: u64) -> Result<(), GreenGaugeError> {
     let caller = caller();
     
     USERS.with(|users| {
@@ -248,514 +549,236 @@ fn record_emission(amount: u64) -> Result<(), String> {
         
         match users_map.get(&caller) {
             Some(profile) => {
-                let new_emitted = profile.carbon_emitted + amount;
-                
-                if new_emitted > profile.carbon_allowance {
-                    return Err("Emission would exceed your carbon allowance. Consider buying more allowance.".to_string());
+                if amount > profile.available_allowance() {
+                    return Err(GreenGaugeError::InsufficientAllowance {
+                        required: amount,
+                        available: profile.available_allowance(),
+                    });
                 }
                 
-                let mut updated_profile = profile.clone();
-                updated_profile.carbon_emitted = new_emitted;
+                let emitted = Co2e::from_kg(amount).ok_or_else(|| GreenGaugeError::overflow("carbon_emitted"))?;
+                let mut updated_profile = profile;
+                updated_profile.record_emitted(amount)?;
                 users_map.insert(caller, updated_profile);
+                record_emission_history(caller, ic_cdk::api::time(), emitted, Vec::new());
+                journal::transfer(
+                    Cause::new(Reason::EmissionRecorded, None),
+                    JournalAccount::EmissionSource,
+                    JournalAccount::Emissions(caller),
+                    emitted.grams(),
+                );
                 
                 Ok(())
             },
-            None => Err("Please register your account first".to_string()),
+            None => Err(GreenGaugeError::NotRegistered),
         }
     })
 }
 
-// Reward tokens to a user
-#[update]
-fn reward_tokens(amount: u64) -> Result<(), String> {
-    let caller = caller();
+// Reward tokens to a user by minting them on the GG ledger (admin only)
+#[update(guard = "caller_is_admin")]
+fn reward_tokens(user: Principal, amount: u64) -> Result<u64, GreenGaugeError> {
+    if amount == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
+    }
     
-    USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        
-        match users_map.get(&caller) {
-            Some(profile) => {
-                let mut updated_profile = profile.clone();
-                updated_profile.tokens += amount;
-                users_map.insert(caller, updated_profile);
-                
-                Ok(())
-            },
-            None => Err("Please register your account first".to_string()),
-        }
-    })
+    if !USERS.with(|users| users.borrow().contains_key(&user)) {
+        return Err(GreenGaugeError::not_found("user", user));
+    }
+    
+    ledger::mint(user, amount, Cause::new(Reason::Reward, None))
 }
 
-// Create a trade offer
+// Create a trade offer. The offered allowance is held in escrow until the
+// offer fills or is withdrawn.
 #[update]
-fn create_trade_offer(amount: u64, price_per_unit: u64) -> Result<u64, String> {
+fn create_trade_offer(amount: u64, price_per_unit: u64, expires_at: Option<u64>) -> Result<u64, GreenGaugeError> {
     let caller = caller();
+    ensure_registered(caller)?;
     
-    USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        
-        match users_map.get(&caller) {
-            Some(profile) => {
-                // Calculate available carbon
-                let available_carbon = profile.carbon_allowance - profile.carbon_emitted;
-                
-                if amount > available_carbon {
-                    return Err(format!("Insufficient available carbon. You have {} units available.", available_carbon));
-                }
-                
-                let trade_id = NEXT_TRADE_ID.with(|id| {
-                    let current_id = *id.borrow();
-                    *id.borrow_mut() = current_id + 1;
-                    current_id
-                });
-                
-                let trade = CarbonTrade {
-                    id: trade_id,
-                    seller: caller,
-                    amount,
-                    price_per_unit,
-                };
-                
-                // Store the trade
-                TRADES.with(|trades| {
-                    trades.borrow_mut().insert(trade_id, trade);
-                });
-                
-                Ok(trade_id)
-            },
-            None => Err("Please register your account first".to_string()),
-        }
-    })
+    let listing = listings::create(caller, ListingKind::Allowance, amount, Price::new(price_per_unit), expires_at)?;
+    Ok(listing.id)
 }
 
 // Get all active trade offers
 #[query]
 fn get_trade_offers() -> Vec<CarbonTrade> {
-    TRADES.with(|trades| {
-        trades.borrow().values().cloned().collect()
-    })
+    listings::open_listings(ListingKind::is_allowance)
+        .iter()
+        .filter_map(Listing::as_trade)
+        .collect()
 }
 
 // Buy carbon from a trade offer
 #[update]
-fn buy_carbon(trade_id: u64, amount: u64) -> Result<(), String> {
-    let buyer = caller();
-    
-    if amount == 0 {
-        return Err("Amount to buy must be greater than zero".to_string());
-    }
-    
-    // First, check if the trade exists and get its details
-    let trade = TRADES.with(|trades| {
-        match trades.borrow().get(&trade_id) {
-            Some(t) => Ok(t.clone()),
-            None => Err("Trade offer not found".to_string()),
-        }
-    })?;
-    
-    if trade.seller == buyer {
-        return Err("You cannot buy your own trade offer".to_string());
-    }
-    
-    if amount > trade.amount {
-        return Err(format!("Requested amount exceeds available amount in trade. Available: {}", trade.amount));
-    }
-    
-    // Calculate the total cost
-    let total_cost = amount * trade.price_per_unit;
-    
-    // Check if the buyer has enough tokens
-    USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        
-        let buyer_profile = match users_map.get(&buyer) {
-            Some(profile) => profile.clone(),
-            None => return Err("Buyer profile not found. Please register first.".to_string()),
-        };
-        
-        if buyer_profile.tokens < total_cost {
-            return Err(format!("Not enough tokens. Required: {}, Available: {}", total_cost, buyer_profile.tokens));
-        }
-        
-        let seller_profile = match users_map.get(&trade.seller) {
-            Some(profile) => profile.clone(),
-            None => return Err("Seller profile not found".to_string()),
-        };
-        
-        // Calculate the seller's earnings (minus commission)
-        let commission = (total_cost as f64 * COMMISSION_RATE) as u64;
-        let seller_earnings = total_cost - commission;
-        
-        // Update buyer profile
-        let mut updated_buyer = buyer_profile.clone();
-        updated_buyer.tokens -= total_cost;
-        updated_buyer.carbon_allowance += amount;
-        
-        // Update seller profile
-        let mut updated_seller = seller_profile.clone();
-        updated_seller.tokens += seller_earnings;
-        
-        // Update or remove the trade
-        TRADES.with(|trades| {
-            let mut trades_map = trades.borrow_mut();
-            
-            if amount == trade.amount {
-                // If buying the entire amount, remove the trade
-                trades_map.remove(&trade_id);
-            } else {
-                // If buying a partial amount, update the trade
-                let mut updated_trade = trade.clone();
-                updated_trade.amount -= amount;
-                trades_map.insert(trade_id, updated_trade);
-            }
-        });
-        
-        // Save the updated profiles
-        users_map.insert(buyer, updated_buyer);
-        users_map.insert(trade.seller, updated_seller);
-        
-        Ok(())
-    })
+fn buy_carbon(trade_id: u64, amount: u64) -> Result<SettlementReceipt, GreenGaugeError> {
+    let trade_id = listings::resolve_legacy(LegacyListing::Trade(trade_id));
+    let trade = listings::open(trade_id, "trade offer", ListingKind::is_allowance)?;
+    listings::buy(caller(), trade, amount)
 }
 
 // For testing purposes - allow checking all users
-#[query]
+#[query(guard = "caller_is_admin")]
 fn debug_get_all_users() -> Vec<UserProfile> {
     USERS.with(|users| {
-        users.borrow().values().cloned().collect()
+        users.borrow().values().map(with_ledger_balance).collect()
     })
 }
 
-// Set up the canister
-#[init]
-fn init() {
-    ic_cdk::println!("Green Gauge canister initialized with mock data");
-    
-    // Create a sample user profile
-    let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-    let now = ic_cdk::api::time();
-    let join_date = now - 90 * 24 * 60 * 60 * 1_000_000_000; // 90 days ago
-    let last_activity = now - 2 * 24 * 60 * 60 * 1_000_000_000; // 2 days ago
-    
-    // Initialize with a mock user profile
-    let mock_user = UserProfile {
-        principal: mock_user_principal,
-        carbon_allowance: 10000,
-        carbon_emitted: 3500,
-        tokens: 7500,
-        has_subcontract: true,
-        username: Some("GreenCorp".to_string()),
-        email: Some("contact@greencorp.com".to_string()),
-        full_name: Some("Green Corporation".to_string()),
-        location: Some("Eco City, Green State".to_string()),
-        join_date,
-        last_activity,
-    };
-    
-    // Store the mock user profile
+// List every registered user (admins and auditors)
+#[query(guard = "caller_is_auditor")]
+fn get_all_users() -> Vec<UserProfile> {
     USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        users_map.insert(mock_user_principal, mock_user.clone());
-    });
-    
-    // Initialize efficiency metrics
-    let mock_metrics: Vec<EfficiencyMetric> = (0..7).map(|i| {
-        let days_ago = 7 - i;
-        let date_timestamp = now - days_ago * 24 * 60 * 60 * 1_000_000_000;
-        let date = format!("{}", date_timestamp / (24 * 60 * 60 * 1_000_000_000));
-        
-        EfficiencyMetric {
-            date,
-            consumption: 200.0 + (rand() as f32 * 100.0),
-            carbon_emitted: 70.0 + (rand() as f32 * 50.0),
-            efficiency_score: 60.0 + (rand() as f32 * 30.0)
-        }
-    }).collect();
-    
-    EFFICIENCY_METRICS.with(|metrics| {
-        let mut metrics_map = metrics.borrow_mut();
-        metrics_map.insert(mock_user_principal, mock_metrics);
-    });
-    
-    // Initialize emission history
-    let mock_emission_history: Vec<EmissionHistoryPoint> = (0..30).map(|i| {
-        let days_ago = 30 - i;
-        let timestamp = now - days_ago * 24 * 60 * 60 * 1_000_000_000;
-        
-        EmissionHistoryPoint {
-            timestamp,
-            amount: 50.0 + (rand() as f64 * 150.0)
-        }
-    }).collect();
-    
-    EMISSION_HISTORY.with(|history| {
-        let mut history_map = history.borrow_mut();
-        history_map.insert(mock_user_principal, mock_emission_history);
-    });
-    
-    // Initialize token balance history
-    let mock_token_history: Vec<TokenBalancePoint> = (0..30).map(|i| {
-        let days_ago = 30 - i;
-        let timestamp = now - days_ago * 24 * 60 * 60 * 1_000_000_000;
-        
-        TokenBalancePoint {
-            timestamp,
-            balance: 5000 + i * 100 + (rand() as u64 % 200)
-        }
-    }).collect();
-    
-    TOKEN_BALANCE_HISTORY.with(|history| {
-        let mut history_map = history.borrow_mut();
-        history_map.insert(mock_user_principal, mock_token_history);
+        users.borrow().values().map(with_ledger_balance).collect()
+    })
+}
+
+// Set up the canister. A fresh install without arguments runs in production
+// mode with empty stores.
+#[init]
+fn init(args: Option<InitArgs>) {
+    demo::apply_init_args(args, true);
+    emission_factors::install_defaults();
+    jobs::start();
+    ic_cdk::println!("Green Gauge canister initialized in {:?} mode", demo::mode());
+}
+
+// All state lives in stable memory, so an upgrade only has to reattach it.
+// The stores are opened lazily on first access; touching the memory manager
+// here makes a corrupted layout fail the upgrade instead of a later call.
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // Before anything posts, so that the journal starts from the balances
+    // the canister held until now and later changes update the rebuilt tree
+    certified::rebuild();
+    journal::open_balances();
+    demo::apply_init_args(args, false);
+    emission_factors::install_defaults();
+    
+    let users = USERS.with(|users| users.borrow().len());
+    ic_cdk::println!("Green Gauge canister upgraded, {} user profiles restored", users);
+    
+    // Balances from before the GG ledger existed still live on the profiles
+    ledger::migrate_profile_balances();
+    migrate_listing_escrow();
+    listings::migrate_legacy_listings();
+    jobs::start();
+}
+
+// Escrow the allowance of listings created before listings were escrowed.
+// Profiles without `carbon_locked` have not been migrated: their open trade
+// offers and active credits are locked now, oldest first, and a listing the
+// seller can no longer cover is shrunk to what is left or withdrawn. Sell
+// orders already took their units out of `carbon_allowance`, so they only
+// count towards the locked total.
+fn migrate_listing_escrow() {
+    let mut profiles: std::collections::BTreeMap<Principal, UserProfile> = USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| profile.carbon_locked.is_none())
+            .collect()
     });
+    if profiles.is_empty() {
+        return;
+    }
+    let mut locked_in_orders = std::collections::BTreeMap::new();
+    for (principal, profile) in profiles.iter_mut() {
+        let locked = orderbook::locked_in_orders(*principal).expect("escrowed allowance fits in u64");
+        profile.carbon_locked = Some(locked);
+        locked_in_orders.insert(*principal, locked);
+    }
     
-    // Initialize alerts
-    let mock_alerts = vec![
-        Alert {
-            id: 1,
-            user_id: mock_user_principal,
-            message: "Your carbon emission is approaching your monthly limit".to_string(),
-            timestamp: now - 45 * 60 * 1_000_000_000, // 45 minutes ago
-            severity: "medium".to_string(),
-            status: "new".to_string()
-        },
-        Alert {
-            id: 2,
-            user_id: mock_user_principal,
-            message: "New carbon trading opportunity available".to_string(),
-            timestamp: now - 3 * 60 * 60 * 1_000_000_000, // 3 hours ago
-            severity: "low".to_string(),
-            status: "new".to_string()
-        },
-        Alert {
-            id: 3,
-            user_id: mock_user_principal,
-            message: "System maintenance scheduled for tonight at 10PM".to_string(),
-            timestamp: now - 6 * 60 * 60 * 1_000_000_000, // 6 hours ago
-            severity: "low".to_string(),
-            status: "read".to_string()
-        },
-        Alert {
-            id: 4,
-            user_id: mock_user_principal,
-            message: "Security update required - please update your password".to_string(),
-            timestamp: now - 18 * 60 * 60 * 1_000_000_000, // 18 hours ago
-            severity: "high".to_string(),
-            status: "new".to_string()
-        },
-        Alert {
-            id: 5,
-            user_id: mock_user_principal,
-            message: "Congratulations! You reduced emissions by 15% this week".to_string(),
-            timestamp: now - 1 * 24 * 60 * 60 * 1_000_000_000, // 1 day ago
-            severity: "low".to_string(),
-            status: "new".to_string()
-        },
-        Alert {
-            id: 6,
-            user_id: mock_user_principal,
-            message: "Price alert: Carbon credit prices have increased by 5%".to_string(),
-            timestamp: now - 30 * 60 * 1_000_000_000, // 30 minutes ago
-            severity: "medium".to_string(),
-            status: "new".to_string()
-        },
-        Alert {
-            id: 7,
-            user_id: mock_user_principal,
-            message: "Your efficiency metrics report is ready to view".to_string(),
-            timestamp: now - 10 * 60 * 1_000_000_000, // 10 minutes ago
-            severity: "low".to_string(),
-            status: "new".to_string()
-        }
-    ];
-    
-    ALERTS.with(|alerts| {
-        let mut alerts_list = alerts.borrow_mut();
-        for alert in mock_alerts {
-            alerts_list.push(alert);
+    let mut shrunk = 0;
+    TRADES.with(|trades| {
+        let mut trades_map = trades.borrow_mut();
+        let listed: Vec<CarbonTrade> = trades_map
+            .values()
+            .filter(|trade| profiles.contains_key(&trade.seller))
+            .collect();
+        for mut trade in listed {
+            let profile = profiles.get_mut(&trade.seller).expect("seller profile was loaded");
+            let units = trade.amount.min(profile.available_allowance());
+            let _ = profile.lock_allowance(units);
+            if units == 0 {
+                trades_map.remove(&trade.id);
+            } else if units < trade.amount {
+                trade.amount = units;
+                trades_map.insert(trade.id, trade);
+            } else {
+                continue;
+            }
+            shrunk += 1;
         }
     });
-    
-    // Set alert ID counter
-    ALERT_ID_COUNTER.with(|counter| {
-        *counter.borrow_mut() = 8; // Next alert ID
-    });
-    
-    // Initialize carbon credits
-    let other_principal1 = Principal::from_text("ghi789-rst").unwrap_or(Principal::anonymous());
-    let other_principal2 = Principal::from_text("jkl012-opq").unwrap_or(Principal::anonymous());
-    let other_principal3 = Principal::from_text("stu678-vwx").unwrap_or(Principal::anonymous());
-    
-    let mock_carbon_credits = vec![
-        CarbonCredit {
-            id: 1,
-            seller: other_principal1,
-            amount: 2000.0,
-            price_per_unit: 8.0,
-            credit_type: "renewable".to_string(),
-            certification: "gold".to_string(),
-            project_name: "Solar Farm Initiative".to_string(),
-            vintage_year: 2023,
-            description: "Credits from our solar farm project in Arizona".to_string(),
-            creation_time: now - 10 * 24 * 60 * 60 * 1_000_000_000,
-            is_active: true
-        },
-        CarbonCredit {
-            id: 2,
-            seller: other_principal2,
-            amount: 1500.0,
-            price_per_unit: 7.0,
-            credit_type: "forestry".to_string(),
-            certification: "verra".to_string(),
-            project_name: "Amazon Reforestation".to_string(),
-            vintage_year: 2023,
-            description: "Reforestation project in the Amazon rainforest".to_string(),
-            creation_time: now - 15 * 24 * 60 * 60 * 1_000_000_000,
-            is_active: true
-        },
-        CarbonCredit {
-            id: 3,
-            seller: mock_user_principal,
-            amount: 1000.0,
-            price_per_unit: 9.0,
-            credit_type: "efficiency".to_string(),
-            certification: "american".to_string(),
-            project_name: "Green Building Retrofit".to_string(),
-            vintage_year: 2024,
-            description: "Energy efficiency improvements in commercial buildings".to_string(),
-            creation_time: now - 5 * 24 * 60 * 60 * 1_000_000_000,
-            is_active: true
-        },
-        CarbonCredit {
-            id: 4,
-            seller: other_principal3,
-            amount: 500.0,
-            price_per_unit: 10.0,
-            credit_type: "methane".to_string(),
-            certification: "climate".to_string(),
-            project_name: "Landfill Gas Recovery".to_string(),
-            vintage_year: 2022,
-            description: "Capturing methane from landfill sites".to_string(),
-            creation_time: now - 20 * 24 * 60 * 60 * 1_000_000_000,
-            is_active: true
-        }
-    ];
-    
     CARBON_CREDITS.with(|credits| {
-        let mut credits_list = credits.borrow_mut();
-        for credit in mock_carbon_credits {
-            credits_list.push(credit);
+        let mut credits_map = credits.borrow_mut();
+        let listed: Vec<CarbonCredit> = credits_map
+            .values()
+            .filter(|credit| credit.is_active && profiles.contains_key(&credit.seller))
+            .collect();
+        for mut credit in listed {
+            let profile = profiles.get_mut(&credit.seller).expect("seller profile was loaded");
+            let units = amount::to_fixed(credit.amount, 1, Rounding::Down).unwrap_or(0).min(profile.available_allowance());
+            let _ = profile.lock_allowance(units);
+            if units as f64 == credit.amount {
+                continue;
+            }
+            if units == 0 {
+                credit.is_active = false;
+            } else {
+                credit.amount = units as f64;
+            }
+            credits_map.insert(credit.id, credit);
+            shrunk += 1;
         }
     });
     
-    // Set carbon credit ID counter
-    CARBON_CREDIT_ID_COUNTER.with(|counter| {
-        *counter.borrow_mut() = 5; // Next credit ID
-    });
-    
-    // Initialize transactions
-    let other_buyer1 = Principal::from_text("def456-uvw").unwrap_or(Principal::anonymous());
-    let other_seller1 = Principal::from_text("abc123-xyz").unwrap_or(Principal::anonymous());
-    let other_seller2 = Principal::from_text("ghi789-rst").unwrap_or(Principal::anonymous());
-    let other_buyer2 = Principal::from_text("lmn901-xyz").unwrap_or(Principal::anonymous());
-    let other_seller3 = Principal::from_text("pqr234-rst").unwrap_or(Principal::anonymous());
-    
-    let mock_transactions = vec![
-        Transaction {
-            id: 1,
-            buyer: mock_user_principal,
-            seller: other_seller1,
-            credit_id: 4,
-            amount: 200.0,
-            price_per_unit: 6.0,
-            project_name: "Wind Energy Project".to_string(),
-            transaction_type: "purchase".to_string(),
-            transaction_time: now - 2 * 24 * 60 * 60 * 1_000_000_000 // 2 days ago
-        },
-        Transaction {
-            id: 2,
-            buyer: other_buyer1,
-            seller: mock_user_principal,
-            credit_id: 5,
-            amount: 300.0,
-            price_per_unit: 7.0,
-            project_name: "Green Building Retrofit".to_string(),
-            transaction_type: "sale".to_string(),
-            transaction_time: now - 36 * 60 * 60 * 1_000_000_000 // 36 hours ago
-        },
-        Transaction {
-            id: 3,
-            buyer: mock_user_principal,
-            seller: other_seller2,
-            credit_id: 6,
-            amount: 500.0,
-            price_per_unit: 5.0,
-            project_name: "Methane Capture".to_string(),
-            transaction_type: "purchase".to_string(),
-            transaction_time: now - 12 * 60 * 60 * 1_000_000_000 // 12 hours ago
-        },
-        Transaction {
-            id: 4,
-            buyer: other_buyer2,
-            seller: mock_user_principal,
-            credit_id: 3,
-            amount: 250.0,
-            price_per_unit: 9.0,
-            project_name: "Green Building Retrofit".to_string(),
-            transaction_type: "sale".to_string(),
-            transaction_time: now - 4 * 60 * 60 * 1_000_000_000 // 4 hours ago
-        },
-        Transaction {
-            id: 5,
-            buyer: mock_user_principal,
-            seller: other_seller3,
-            credit_id: 7,
-            amount: 150.0,
-            price_per_unit: 8.0,
-            project_name: "Solar Farm Initiative".to_string(),
-            transaction_type: "purchase".to_string(),
-            transaction_time: now - 30 * 60 * 1_000_000_000 // 30 minutes ago
-        }
-    ];
-    
-    TRANSACTIONS.with(|transactions| {
-        let mut transactions_list = transactions.borrow_mut();
-        for transaction in mock_transactions {
-            transactions_list.push(transaction);
+    let migrated = profiles.len();
+    for (principal, profile) in &profiles {
+        let in_orders = locked_in_orders[principal];
+        journal::transfer(
+            Cause::new(Reason::OpeningBalance, None),
+            JournalAccount::AllowanceIssuer,
+            JournalAccount::LockedAllowance(*principal),
+            in_orders,
+        );
+        journal::transfer(
+            Cause::new(Reason::ListingEscrow, None),
+            JournalAccount::Allowance(*principal),
+            JournalAccount::LockedAllowance(*principal),
+            profile.locked_allowance() - in_orders,
+        );
+    }
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        for (principal, profile) in profiles {
+            users_map.insert(principal, profile);
         }
     });
-    
-    // Set transaction ID counter
-    TRANSACTION_ID_COUNTER.with(|counter| {
-        *counter.borrow_mut() = 6; // Next transaction ID
-    });
+    ic_cdk::println!(
+        "Escrowed listings of {} profiles, {} listings shrunk or withdrawn",
+        migrated, shrunk
+    );
 }
 
-// Export Candid interface
-ic_cdk::export_candid!();
-
 // Check if a user has a subcontract
 #[query]
-fn has_subcontract() -> Result<bool, String> {
+fn has_subcontract() -> Result<bool, GreenGaugeError> {
     Ok(true) // Always return true to avoid subcontract deployment requirement
 }
 
 // Deploy a subcontract for an existing user
 #[update]
-fn deploy_subcontract() -> Result<bool, String> {
+fn deploy_subcontract() -> Result<bool, GreenGaugeError> {
     Ok(true) // Always return success
 }
 
-// Helper function for random number generation
-fn rand() -> f32 {
-    let now = ic_cdk::api::time() % 1000;
-    (now as f32) / 1000.0
-}
-
 // Enhanced marketplace functions
 
 // List a new carbon credit for sale
 #[update]
+#[allow(clippy::too_many_arguments)]
 fn list_carbon_credit(
     amount: f64,
     price_per_unit: f64,
@@ -764,36 +787,31 @@ fn list_carbon_credit(
     project_name: String,
     vintage_year: u32,
     description: String,
-) -> Result<String, String> {
+    expires_at: Option<u64>,
+) -> Result<String, GreenGaugeError> {
     let caller = ic_cdk::caller();
     
     if caller == Principal::anonymous() {
-        return Err("Anonymous principals cannot list carbon credits".to_string());
+        return Err(GreenGaugeError::Unauthorized);
     }
     
-    // Validate inputs
-    if amount <= 0.0 {
-        return Err("Amount must be greater than zero".to_string());
+    // Allowance is held in whole units and listings are priced in whole GG
+    // tokens
+    let amount = amount::whole_units(amount, "amount")?;
+    let price_per_unit = amount::whole_units(price_per_unit, "price_per_unit")?;
+    if amount == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
     }
-    
-    if price_per_unit <= 0.0 {
-        return Err("Price must be greater than zero".to_string());
+    if price_per_unit == 0 {
+        return Err(GreenGaugeError::invalid_input("price_per_unit", "must be greater than zero"));
     }
     
-    // Validate credit type
-    let valid_credit_types = vec!["renewable", "forestry", "methane", "efficiency"];
-    if !valid_credit_types.contains(&credit_type.as_str()) {
-        return Err(format!("Invalid credit type. Must be one of: {}", valid_credit_types.join(", ")));
-    }
+    let offset = OffsetDetails::new(credit_type, certification, project_name, vintage_year, description);
+    offset.validate()?;
+    listings::validate_expiry(expires_at)?;
     
-    // Validate certification
-    let valid_certifications = vec!["gold", "verra", "american", "climate"];
-    if !valid_certifications.contains(&certification.as_str()) {
-        return Err(format!("Invalid certification. Must be one of: {}", valid_certifications.join(", ")));
-    }
-    
-    // Check if user exists, register them if not
-    let user_profile = USERS.with(|users| {
+    // Register the user if they have no profile yet
+    USERS.with(|users| {
         let mut users_map = users.borrow_mut();
         
         if !users_map.contains_key(&caller) {
@@ -804,7 +822,7 @@ fn list_carbon_credit(
                 principal: caller,
                 carbon_allowance: DEFAULT_CARBON_ALLOWANCE,
                 carbon_emitted: 0,
-                tokens: DEFAULT_TOKENS + 100,
+                tokens: DEFAULT_TOKENS,
                 has_subcontract: true,
                 username: None,
                 email: None,
@@ -812,693 +830,442 @@ fn list_carbon_credit(
                 location: None,
                 join_date: timestamp,
                 last_activity: timestamp,
+                carbon_locked: Some(0),
+                carbon_available: None,
+                carbon_emitted_remainder: Some(Co2e::ZERO),
             };
             
-            users_map.insert(caller, new_profile.clone());
+            users_map.insert(caller, new_profile);
             
-            // Initialize emission history
-            EMISSION_HISTORY.with(|history| {
-                history.borrow_mut().insert(caller, Vec::new());
-            });
-            
-            // Initialize token balance history
-            TOKEN_BALANCE_HISTORY.with(|history| {
-                let history_point = TokenBalancePoint {
-                    timestamp,
-                    balance: DEFAULT_TOKENS + 100,
-                };
-                history.borrow_mut().insert(caller, vec![history_point]);
-            });
-            
-            new_profile
-        } else {
-            users_map.get(&caller).unwrap().clone()
+            // Bonus tokens for new users, minted on the GG ledger
+            let cause = Cause::new(Reason::Registration, None);
+            journal::transfer(cause, JournalAccount::AllowanceIssuer, JournalAccount::Allowance(caller), DEFAULT_CARBON_ALLOWANCE);
+            if let Err(error) = ledger::mint(caller, REGISTRATION_BONUS_TOKENS, cause) {
+                ic_cdk::println!("Could not mint registration bonus for {}: {}", caller, error);
+            }
         }
     });
     
-    // Check if user has enough carbon credits
-    let available_carbon = user_profile.carbon_allowance - user_profile.carbon_emitted;
-    if (available_carbon as f64) < amount {
-        return Err(format!("Not enough carbon credits available. You have {} credits", available_carbon));
-    }
-    
-    // Generate new credit ID
-    let credit_id = CARBON_CREDIT_ID_COUNTER.with(|counter| {
-        let current_id = *counter.borrow();
-        *counter.borrow_mut() = current_id + 1;
-        current_id
-    });
-    
-    // Create new carbon credit
-    let new_credit = CarbonCredit {
-        id: credit_id,
-        seller: caller,
+    // The listed allowance is held in escrow until the credit is sold
+    let listing = listings::create(
+        caller,
+        ListingKind::Offset(offset),
         amount,
-        price_per_unit,
-        credit_type,
-        certification,
-        project_name,
-        vintage_year,
-        description,
-        creation_time: ic_cdk::api::time(),
-        is_active: true,
-    };
-    
-    // Store the credit
-    CARBON_CREDITS.with(|credits| {
-        credits.borrow_mut().push(new_credit);
-    });
+        Price::new(price_per_unit),
+        expires_at,
+    )?;
+    let credit_id = listing.id;
     
     Ok(format!("Carbon credit listed successfully with ID: {}", credit_id))
 }
 
 // Get all carbon credit listings
 #[query]
-fn get_carbon_credits() -> Result<Vec<CarbonCredit>, String> {
-    let credits = CARBON_CREDITS.with(|credits| {
-        credits.borrow()
-            .iter()
-            .filter(|credit| credit.is_active)
-            .cloned()
-            .collect::<Vec<CarbonCredit>>()
-    });
-    
-    // Always return mock credits even if none exist yet
-    if credits.is_empty() {
-        // Create fallback mock data
-        let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-        let now = ic_cdk::api::time();
-        let other_principal1 = Principal::from_text("ghi789-rst").unwrap_or(Principal::anonymous());
-        
-        return Ok(vec![
-            CarbonCredit {
-                id: 1,
-                seller: other_principal1,
-                amount: 2000.0,
-                price_per_unit: 8.0,
-                credit_type: "renewable".to_string(),
-                certification: "gold".to_string(),
-                project_name: "Solar Farm Initiative".to_string(),
-                vintage_year: 2023,
-                description: "Credits from our solar farm project in Arizona".to_string(),
-                creation_time: now - 10 * 24 * 60 * 60 * 1_000_000_000,
-                is_active: true
-            },
-            CarbonCredit {
-                id: 3,
-                seller: mock_user_principal,
-                amount: 1000.0,
-                price_per_unit: 9.0,
-                credit_type: "efficiency".to_string(),
-                certification: "american".to_string(),
-                project_name: "Green Building Retrofit".to_string(),
-                vintage_year: 2024,
-                description: "Energy efficiency improvements in commercial buildings".to_string(),
-                creation_time: now - 5 * 24 * 60 * 60 * 1_000_000_000,
-                is_active: true
-            }
-        ]);
-    }
+fn get_carbon_credits() -> Result<Vec<CarbonCredit>, GreenGaugeError> {
+    let credits = listings::open_listings(ListingKind::is_offset)
+        .iter()
+        .filter_map(Listing::as_credit)
+        .collect();
     
     Ok(credits)
 }
 
 // Purchase carbon credits
 #[update]
-fn purchase_carbon_credit(credit_id: u64, amount: f64) -> Result<String, String> {
-    let buyer = ic_cdk::caller();
-    
-    // Validate amount
-    if amount <= 0.0 {
-        return Err("Amount must be greater than zero".to_string());
-    }
-    
-    // Find the credit
-    let mut credit_opt = None;
-    let mut seller = Principal::anonymous();
-    let mut project_name = String::new();
-    let mut price_per_unit = 0.0;
+fn purchase_carbon_credit(credit_id: u64, amount: f64) -> Result<SettlementReceipt, GreenGaugeError> {
+    // Allowance is held in whole units
+    let amount = amount::whole_units(amount, "amount")?;
     
-    CARBON_CREDITS.with(|credits| {
-        let mut credits_mut = credits.borrow_mut();
-        for credit in credits_mut.iter_mut() {
-            if credit.id == credit_id && credit.is_active {
-                if credit.seller == buyer {
-                    return Err("Cannot purchase your own carbon credit".to_string());
-                }
-                
-                if credit.amount < amount {
-                    return Err(format!("Not enough credits available. Only {} credits available", credit.amount));
-                }
-                
-                seller = credit.seller;
-                project_name = credit.project_name.clone();
-                price_per_unit = credit.price_per_unit;
-                
-                // Update the credit amount or mark as inactive
-                if credit.amount == amount {
-                    credit.is_active = false;
-                } else {
-                    credit.amount -= amount;
-                }
-                
-                credit_opt = Some(credit.clone());
-                break;
-            }
-        }
-        
-        Ok(())
-    })?;
-    
-    // Renamed to _credit to acknowledge it's unused
-    let _credit = match credit_opt {
-        Some(c) => c,
-        None => return Err("Carbon credit not found or not active".to_string()),
-    };
-    
-    // Calculate total price
-    let total_price = amount * price_per_unit;
-    
-    // Create transaction record
-    let transaction_id = TRANSACTION_ID_COUNTER.with(|counter| {
-        let current_id = *counter.borrow();
-        *counter.borrow_mut() = current_id + 1;
-        current_id
-    });
-    
-    let transaction = Transaction {
-        id: transaction_id,
-        buyer,
-        seller,
-        credit_id,
-        amount,
-        price_per_unit,
-        project_name,
-        transaction_type: "purchase".to_string(),
-        transaction_time: ic_cdk::api::time(),
-    };
-    
-    // Store transaction
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().push(transaction);
-    });
-    
-    Ok(format!("Successfully purchased {} carbon credits for a total of ${:.2}", amount, total_price))
+    let credit_id = listings::resolve_legacy(LegacyListing::Credit(credit_id));
+    let credit = listings::open(credit_id, "carbon credit", ListingKind::is_offset)?;
+    listings::buy(ic_cdk::caller(), credit, amount)
 }
 
 // Get user's transaction history
 #[query]
-fn get_user_transactions() -> Result<Vec<Transaction>, String> {
+fn get_user_transactions() -> Result<Vec<Transaction>, GreenGaugeError> {
     let caller = caller();
+    ensure_registered(caller)?;
     
     let transactions = TRANSACTIONS.with(|transactions| {
         transactions.borrow()
-            .iter()
+            .values()
             .filter(|tx| tx.buyer == caller || tx.seller == caller)
-            .cloned()
             .collect::<Vec<Transaction>>()
     });
     
-    // Always return mock transactions even if none exist for this user
-    if transactions.is_empty() {
-        // Create fallback mock data
-        let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-        let now = ic_cdk::api::time();
-        let other_seller = Principal::from_text("abc123-xyz").unwrap_or(Principal::anonymous());
-        
-        return Ok(vec![
-            Transaction {
-                id: 1,
-                buyer: mock_user_principal,
-                seller: other_seller,
-                credit_id: 4,
-                amount: 200.0,
-                price_per_unit: 6.0,
-                project_name: "Wind Energy Project".to_string(),
-                transaction_type: "purchase".to_string(),
-                transaction_time: now - 2 * 24 * 60 * 60 * 1_000_000_000 // 2 days ago
-            },
-            Transaction {
-                id: 3,
-                buyer: mock_user_principal,
-                seller: other_seller,
-                credit_id: 6,
-                amount: 500.0,
-                price_per_unit: 5.0,
-                project_name: "Methane Capture".to_string(),
-                transaction_type: "purchase".to_string(),
-                transaction_time: now - 12 * 60 * 60 * 1_000_000_000 // 12 hours ago
-            }
-        ]);
-    }
-    
     Ok(transactions)
 }
 
-// Get all transaction history (admin only - for debugging)
-#[query(guard = "is_admin")]
+// Get all transaction history (admins and auditors - for debugging)
+#[query(guard = "caller_is_auditor")]
 fn debug_get_all_transactions() -> Vec<Transaction> {
     TRANSACTIONS.with(|transactions| {
-        transactions.borrow().clone()
+        transactions.borrow().values().collect()
     })
 }
 
-// Define the is_admin function for the guard
-fn is_admin() -> Result<(), String> {
-    let _caller = ic_cdk::caller();
-    // Replace this with your actual admin check logic
-    // For now, we'll allow all requests
-    Ok(())
+// Add a new data point for energy consumption and directly measured gases.
+// The carbon emission is computed from the device's emission factor and the
+// active GWP set.
+#[update]
+fn add_data_point(device_id: String, energy_consumption: f32, gases: Option<Vec<GasQuantity>>) -> Result<u64, GreenGaugeError> {
+    let input = DataPointInput::new(device_id, energy_consumption, None).with_gases(gases);
+    telemetry::ingest_one(Submitter::Owner(caller()), input)
 }
 
-// Add a new data point for energy consumption and carbon emission
+// Add a data point signed by a device's own principal on behalf of its owner.
+// Sequence numbers must strictly increase for each device.
 #[update]
-fn add_data_point(device_id: String, energy_consumption: f32, carbon_emitted: f32) -> Result<u64, String> {
-    let caller = caller();
-    
-    // Validate inputs
-    if energy_consumption < 0.0 {
-        return Err("Energy consumption cannot be negative".to_string());
-    }
+fn submit_device_reading(sequence: u64, energy_consumption: f32) -> Result<u64, GreenGaugeError> {
+    let device_principal = caller();
+    let (_, device_id) = devices::resolve_principal(device_principal)
+        .ok_or(GreenGaugeError::Unauthorized)?;
     
-    if carbon_emitted < 0.0 {
-        return Err("Carbon emission cannot be negative".to_string());
-    }
-    
-    // Check if user exists
-    USERS.with(|users| {
-        if !users.borrow().contains_key(&caller) {
-            return Err("User profile not found. Please register first.".to_string());
-        }
-        Ok(())
-    })?;
-    
-    // Create and store the data point
-    let timestamp = ic_cdk::api::time();
-    let data_point_id = DATA_POINT_ID_COUNTER.with(|counter| {
-        let id = *counter.borrow();
-        *counter.borrow_mut() = id + 1;
-        id
-    });
-    
-    let data_point = DataPoint {
-        id: data_point_id,
-        user_id: caller,
-        device_id,
-        energy_consumption,
-        carbon_emitted,
-        timestamp,
-    };
-    
-    DATA_POINTS.with(|points| {
-        points.borrow_mut().push(data_point.clone());
-    });
-    
-    // Update user's carbon emission in profile
-    USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        if let Some(profile) = users_map.get(&caller) {
-            let mut updated_profile = profile.clone();
-            updated_profile.carbon_emitted += carbon_emitted as u64;
-            updated_profile.last_activity = timestamp;
-            users_map.insert(caller, updated_profile);
-        }
-    });
-    
-    // Add to emission history
-    let history_point = EmissionHistoryPoint {
-        timestamp,
-        amount: carbon_emitted as f64,
-    };
-    
-    EMISSION_HISTORY.with(|history| {
-        let mut history_map = history.borrow_mut();
-        if let Some(points) = history_map.get_mut(&caller) {
-            points.push(history_point);
-        } else {
-            history_map.insert(caller, vec![history_point]);
-        }
-    });
-    
-    // Check if we need to generate an alert based on thresholds
-    check_and_generate_alerts(caller, energy_consumption, carbon_emitted);
-    
-    Ok(data_point_id)
+    let input = DataPointInput::new(device_id, energy_consumption, Some(sequence));
+    telemetry::ingest_one(Submitter::Device(device_principal), input)
 }
 
 // Helper function to check thresholds and generate alerts
-fn check_and_generate_alerts(user: Principal, energy_consumption: f32, carbon_emitted: f32) {
+fn check_and_generate_alerts(user: Principal, energy_consumption: f32, carbon_emitted: Co2e) {
     // Example thresholds - these could be configured per user in a real system
     const ENERGY_HIGH_THRESHOLD: f32 = 1000.0;
-    const CARBON_HIGH_THRESHOLD: f32 = 100.0;
-    
-    let mut message = String::new();
-    let mut severity = "low";
+    const CARBON_HIGH_THRESHOLD: Co2e = Co2e::from_grams(100_000);
     
-    if energy_consumption > ENERGY_HIGH_THRESHOLD && carbon_emitted > CARBON_HIGH_THRESHOLD {
-        message = format!("Critical: High energy consumption ({:.2} kWh) and high carbon emission ({:.2} kg)", 
-            energy_consumption, carbon_emitted);
-        severity = "high";
+    let (message, severity) = if energy_consumption > ENERGY_HIGH_THRESHOLD && carbon_emitted > CARBON_HIGH_THRESHOLD {
+        (format!("Critical: High energy consumption ({:.2} kWh) and high carbon emission ({:.2} kg)", 
+            energy_consumption, carbon_emitted.as_kg_f64()), "high")
     } else if energy_consumption > ENERGY_HIGH_THRESHOLD {
-        message = format!("Warning: High energy consumption ({:.2} kWh)", energy_consumption);
-        severity = "medium";
+        (format!("Warning: High energy consumption ({:.2} kWh)", energy_consumption), "medium")
     } else if carbon_emitted > CARBON_HIGH_THRESHOLD {
-        message = format!("Warning: High carbon emission ({:.2} kg)", carbon_emitted);
-        severity = "medium";
+        (format!("Warning: High carbon emission ({:.2} kg)", carbon_emitted.as_kg_f64()), "medium")
     } else {
         // No alert needed
         return;
-    }
-    
-    // Create the alert
-    let alert_id = ALERT_ID_COUNTER.with(|counter| {
-        let id = *counter.borrow();
-        *counter.borrow_mut() = id + 1;
-        id
-    });
-    
-    let alert = Alert {
-        id: alert_id,
-        user_id: user,
-        message,
-        timestamp: ic_cdk::api::time(),
-        severity: severity.to_string(),
-        status: "new".to_string(),
     };
     
-    ALERTS.with(|alerts| {
-        alerts.borrow_mut().push(alert);
+    create_alert(user, message, severity);
+}
+
+// Append to a user's emission history. Points recorded at the same
+// timestamp share a key, so their amounts are merged. `unclassified` counts
+// towards the total only, without an entry in the breakdown.
+fn record_emission_history(user: Principal, timestamp: u64, unclassified: Co2e, amounts: Vec<ClassifiedAmount>) {
+    EMISSION_HISTORY.with(|history| {
+        let mut history_map = history.borrow_mut();
+        let key = (user, timestamp);
+        let mut point = history_map.get(&key).unwrap_or(EmissionHistoryPoint {
+            timestamp,
+            amount: 0.0,
+            breakdown: Some(Vec::new()),
+            amount_grams: Some(Co2e::ZERO),
+        });
+        let mut total = point.grams().saturating_add(unclassified);
+        let breakdown = point.breakdown.get_or_insert_with(Vec::new);
+        for entry in amounts {
+            total = total.saturating_add(entry.grams());
+            ghg::add_to(breakdown, entry.classification, entry.grams());
+        }
+        point.set_grams(total);
+        history_map.insert(key, point);
     });
 }
 
 // Get all data points for the current user
 #[query]
-fn get_all_data() -> Result<Vec<DataPoint>, String> {
+fn get_all_data() -> Result<Vec<DataPoint>, GreenGaugeError> {
     let caller = caller();
     
     DATA_POINTS.with(|points| {
-        let all_points = points.borrow();
-        let user_points = all_points.iter()
-            .filter(|point| point.user_id == caller)
-            .cloned()
+        let user_points = points.borrow()
+            .values_range(user_range(caller))
             .collect::<Vec<DataPoint>>();
         
         Ok(user_points)
     })
 }
 
-// Get emission history for a specific time range
+// Get emission history for a specific time range. With a scope, every
+// point only counts the emissions of that scope and points without any are
+// left out.
 #[query]
-fn get_emission_history(from_timestamp: u64, to_timestamp: u64) -> Result<Vec<EmissionHistoryPoint>, String> {
+fn get_emission_history(from_timestamp: u64, to_timestamp: u64, scope: Option<EmissionScope>) -> Result<Vec<EmissionHistoryPoint>, GreenGaugeError> {
     let caller = caller();
     
+    if from_timestamp > to_timestamp {
+        return Ok(Vec::new());
+    }
+    
     EMISSION_HISTORY.with(|history| {
         let history_map = history.borrow();
-        match history_map.get(&caller) {
-            Some(points) => {
-                let filtered_points = points.iter()
-                    .filter(|point| point.timestamp >= from_timestamp && point.timestamp <= to_timestamp)
-                    .cloned()
-                    .collect::<Vec<EmissionHistoryPoint>>();
-                
-                Ok(filtered_points)
-            },
-            None => Ok(Vec::new()),
-        }
+        let points = history_map.values_range((caller, from_timestamp)..=(caller, to_timestamp));
+        
+        let Some(scope) = scope else {
+            return Ok(points.collect());
+        };
+        
+        let filtered_points = points
+            .filter_map(|mut point| {
+                let breakdown = point.breakdown.as_mut()?;
+                breakdown.retain(|entry| entry.classification.scope() == scope);
+                if breakdown.is_empty() {
+                    return None;
+                }
+                let amount = ghg::scope_amount(breakdown, scope);
+                point.set_grams(amount);
+                Some(point)
+            })
+            .collect::<Vec<EmissionHistoryPoint>>();
+        
+        Ok(filtered_points)
     })
 }
 
 // Get token balance history for a specific time range
 #[query]
-fn get_token_balance_history(from_timestamp: u64, to_timestamp: u64) -> Result<Vec<TokenBalancePoint>, String> {
+fn get_token_balance_history(from_timestamp: u64, to_timestamp: u64) -> Result<Vec<TokenBalancePoint>, GreenGaugeError> {
     let caller = caller();
     
-    TOKEN_BALANCE_HISTORY.with(|history| {
-        let history_map = history.borrow();
-        match history_map.get(&caller) {
-            Some(points) => {
-                let filtered_points = points.iter()
-                    .filter(|point| point.timestamp >= from_timestamp && point.timestamp <= to_timestamp)
-                    .cloned()
-                    .collect::<Vec<TokenBalancePoint>>();
-                
-                Ok(filtered_points)
-            },
-            None => Ok(Vec::new()),
-        }
-    })
+    if from_timestamp > to_timestamp {
+        return Ok(Vec::new());
+    }
+    
+    Ok(journal::token_balance_history(caller, from_timestamp, to_timestamp))
 }
 
 // Get user's alerts
 #[query]
-fn get_alerts() -> Result<Vec<Alert>, String> {
+fn get_alerts() -> Result<Vec<Alert>, GreenGaugeError> {
     let caller = caller();
+    ensure_registered(caller)?;
     
     let alerts = ALERTS.with(|alerts| {
         alerts.borrow()
-            .iter()
-            .filter(|alert| alert.user_id == caller)
-            .cloned()
+            .values_range(user_range(caller))
             .collect::<Vec<Alert>>()
     });
     
-    // Always return mock alerts even if none exist for this user
-    if alerts.is_empty() {
-        // Create fallback mock data
-        let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-        let now = ic_cdk::api::time();
-        
-        return Ok(vec![
-            Alert {
-                id: 1,
-                user_id: mock_user_principal,
-                message: "Your carbon emission is approaching your monthly limit".to_string(),
-                timestamp: now - 45 * 60 * 1_000_000_000, // 45 minutes ago
-                severity: "medium".to_string(),
-                status: "new".to_string()
-            },
-            Alert {
-                id: 2,
-                user_id: mock_user_principal,
-                message: "New carbon trading opportunity available".to_string(),
-                timestamp: now - 3 * 60 * 60 * 1_000_000_000, // 3 hours ago
-                severity: "low".to_string(),
-                status: "new".to_string()
-            }
-        ]);
-    }
-    
     Ok(alerts)
 }
 
 // Get latest unresolved alerts
 #[query]
-fn get_latest_alerts() -> Result<Vec<Alert>, String> {
+fn get_latest_alerts() -> Result<Vec<Alert>, GreenGaugeError> {
     let caller = caller();
+    ensure_registered(caller)?;
     
     let alerts = ALERTS.with(|alerts| {
         alerts.borrow()
-            .iter()
-            .filter(|alert| alert.user_id == caller && alert.status != "resolved")
-            .cloned()
+            .values_range(user_range(caller))
+            .filter(|alert| alert.status != "resolved")
             .collect::<Vec<Alert>>()
     });
     
-    // Always return mock alerts even if none exist for this user
-    if alerts.is_empty() {
-        // Create fallback mock data
-        let mock_user_principal = Principal::from_text("2vxsx-fae").unwrap_or(Principal::anonymous());
-        let now = ic_cdk::api::time();
-        
-        return Ok(vec![
-            Alert {
-                id: 1,
-                user_id: mock_user_principal,
-                message: "Your carbon emission is approaching your monthly limit".to_string(),
-                timestamp: now - 45 * 60 * 1_000_000_000, // 45 minutes ago
-                severity: "medium".to_string(),
-                status: "new".to_string()
-            },
-            Alert {
-                id: 2,
-                user_id: mock_user_principal,
-                message: "New carbon trading opportunity available".to_string(),
-                timestamp: now - 3 * 60 * 60 * 1_000_000_000, // 3 hours ago
-                severity: "low".to_string(),
-                status: "new".to_string()
-            }
-        ]);
-    }
-    
     Ok(alerts)
 }
 
 // Update alert status
 #[update]
-fn update_alert_status(alert_id: u64, status: String) -> Result<u64, String> {
+fn update_alert_status(alert_id: u64, status: String) -> Result<u64, GreenGaugeError> {
     let caller = caller();
     
     // Validate status
     if status != "read" && status != "resolved" {
-        return Err("Invalid status. Use 'read' or 'resolved'".to_string());
+        return Err(GreenGaugeError::invalid_input("status", "use 'read' or 'resolved'"));
     }
     
     ALERTS.with(|alerts| {
-        let mut alerts_vec = alerts.borrow_mut();
-        for alert in alerts_vec.iter_mut() {
-            if alert.id == alert_id && alert.user_id == caller {
+        let mut alerts_map = alerts.borrow_mut();
+        match alerts_map.get(&(caller, alert_id)) {
+            Some(mut alert) => {
                 alert.status = status;
-                return Ok(alert_id);
-            }
+                alerts_map.insert((caller, alert_id), alert);
+                Ok(alert_id)
+            },
+            None => Err(GreenGaugeError::not_found("alert", alert_id)),
         }
-        Err("Alert not found or you don't have permission to update it".to_string())
     })
 }
 
 // Remove an alert
 #[update]
-fn remove_alert(alert_id: u64) -> Result<u64, String> {
+fn remove_alert(alert_id: u64) -> Result<u64, GreenGaugeError> {
     let caller = caller();
     
     ALERTS.with(|alerts| {
-        let mut alerts_vec = alerts.borrow_mut();
-        if let Some(index) = alerts_vec.iter().position(|alert| alert.id == alert_id && alert.user_id == caller) {
-            alerts_vec.remove(index);
+        if alerts.borrow_mut().remove(&(caller, alert_id)).is_some() {
             Ok(alert_id)
         } else {
-            Err("Alert not found or you don't have permission to remove it".to_string())
+            Err(GreenGaugeError::not_found("alert", alert_id))
         }
     })
 }
 
 // Get efficiency metrics
 #[query]
-fn get_efficiency_metrics(_days: f64) -> Result<Vec<EfficiencyMetric>, String> {
+fn get_efficiency_metrics(_days: f64) -> Result<Vec<EfficiencyMetric>, GreenGaugeError> {
     let caller = caller();
+    ensure_registered(caller)?;
     
     let metrics = EFFICIENCY_METRICS.with(|metrics| {
-        let metrics_map = metrics.borrow();
-        
-        if let Some(user_metrics) = metrics_map.get(&caller) {
-            // If user has metrics, return them
-            user_metrics.clone()
-        } else {
-            // Return empty vector if no metrics for this user
-            Vec::new()
-        }
+        metrics.borrow()
+            .values_range(user_range(caller))
+            .collect::<Vec<EfficiencyMetric>>()
     });
     
-    // Always return mock metrics even if none exist for this user
-    if metrics.is_empty() {
-        // Create fallback mock data
-        let now = ic_cdk::api::time();
-        let mock_metrics: Vec<EfficiencyMetric> = (0..7).map(|i| {
-            let days_ago = 7 - i;
-            let date_timestamp = now - days_ago * 24 * 60 * 60 * 1_000_000_000;
-            let date = format!("{}", date_timestamp / (24 * 60 * 60 * 1_000_000_000));
-            
-            EfficiencyMetric {
-                date,
-                consumption: 200.0 + (rand() as f32 * 100.0),
-                carbon_emitted: 70.0 + (rand() as f32 * 50.0),
-                efficiency_score: 60.0 + (rand() as f32 * 30.0)
-            }
-        }).collect();
-        
-        return Ok(mock_metrics);
-    }
-    
     Ok(metrics)
 }
 
-// Generate alerts based on recent data
-#[update]
-fn generate_alerts() -> u64 {
-    let now = ic_cdk::api::time();
-    let one_day_ago = now - (24 * 60 * 60 * 1_000_000_000);
-    
+// Aggregate the participants' readings of the last full day (UTC) into an
+// efficiency metric. Returns the number of participants with readings.
+//
+// The score falls with carbon intensity: 100 for carbon-free energy, 0 at
+// 1 kg CO2e per kWh or more.
+fn aggregate_efficiency(now: u64, participants: &[Principal]) -> Result<u64, GreenGaugeError> {
+    let day = (now / NANOS_PER_DAY).saturating_sub(1);
+    let day_range = day * NANOS_PER_DAY..(day + 1) * NANOS_PER_DAY;
+
+    let mut aggregated = 0;
+    for &participant in participants {
+        let Some(totals) = reading_totals(participant, &day_range)? else {
+            continue;
+        };
+
+        let metric = EfficiencyMetric {
+            date: day.to_string(),
+            consumption: totals.milli_units as f32 / 1000.0,
+            carbon_emitted: None,
+            efficiency_score: totals.efficiency_score_bps() as f32 / 100.0,
+            carbon_emitted_grams: Some(totals.emitted),
+        };
+        EFFICIENCY_METRICS.with(|metrics| metrics.borrow_mut().insert((participant, day), metric));
+        aggregated += 1;
+    }
+
+    Ok(aggregated)
+}
+
+// Consumption and emissions summed over a participant's readings
+struct ReadingTotals {
+    // Thousandths of the meter units
+    milli_units: u64,
+    emitted: Co2e,
+}
+
+impl ReadingTotals {
+    // 100% less the carbon intensity in kg CO2e per unit, in basis points
+    fn efficiency_score_bps(&self) -> u64 {
+        let full = Rate::BASIS_POINTS;
+        // Emissions in grams per thousandth of a unit are kg per unit
+        let intensity_bps = match amount::mul_div(self.emitted.grams(), full, self.milli_units, Rounding::Up) {
+            Some(bps) => bps,
+            // Emissions without any consumption score zero
+            None if self.emitted > Co2e::ZERO => full,
+            None => 0,
+        };
+        full.saturating_sub(intensity_bps)
+    }
+}
+
+// Totals of a participant's readings ingested within `range`, or None if
+// there are none
+fn reading_totals(
+    participant: Principal,
+    range: &std::ops::Range<u64>,
+) -> Result<Option<ReadingTotals>, GreenGaugeError> {
+    let points: Vec<DataPoint> = DATA_POINTS.with(|points| {
+        points
+            .borrow()
+            .values_range(user_range(participant))
+            .filter(|point| range.contains(&point.timestamp))
+            .collect()
+    });
+    if points.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(ReadingTotals {
+        milli_units: amount::sum("energy_consumption", points.iter().map(DataPoint::milli_units))?,
+        emitted: Co2e::checked_sum(points.iter().map(DataPoint::emitted))
+            .ok_or_else(|| GreenGaugeError::overflow("carbon_emitted"))?,
+    }))
+}
+
+// Generate alerts based on recent data (admin only). Alerts are also
+// evaluated on schedule. The evaluation works through the participants in
+// the background; the job's last run shows how many alerts it raised.
+#[update(guard = "caller_is_admin")]
+fn generate_alerts() -> Result<Job, GreenGaugeError> {
+    jobs::run_now(JobKind::AlertEvaluation)
+}
+
+// Raise alerts for the participants whose readings of the past day exceed
+// the daily thresholds. Returns the number of alerts raised.
+fn evaluate_alerts(now: u64, participants: &[Principal]) -> Result<u64, GreenGaugeError> {
+    // Example thresholds for daily consumption, in thousandths of a kWh, and
+    // daily emissions
+    const DAILY_CONSUMPTION_THRESHOLD: u64 = 50_000;
+    const DAILY_EMISSION_THRESHOLD: Co2e = Co2e::from_grams(5_000);
+
+    let day_range = now.saturating_sub(NANOS_PER_DAY)..u64::MAX;
     let mut alert_count = 0;
     
-    // For each user, analyze their recent data points
-    USERS.with(|users| {
-        let users_map = users.borrow();
+    for &user_principal in participants {
+        let Some(totals) = reading_totals(user_principal, &day_range)? else {
+            continue;
+        };
         
-        for (&user_principal, _) in users_map.iter() {
-            // Get recent data points for this user
-            let recent_points = DATA_POINTS.with(|points| {
-                points.borrow().iter()
-                    .filter(|point| point.user_id == user_principal && point.timestamp >= one_day_ago)
-                    .cloned()
-                    .collect::<Vec<DataPoint>>()
-            });
-            
-            if recent_points.is_empty() {
-                continue;
-            }
-            
-            // Calculate total consumption and emissions
-            let total_consumption: f32 = recent_points.iter().map(|p| p.energy_consumption).sum();
-            let total_emissions: f32 = recent_points.iter().map(|p| p.carbon_emitted).sum();
-            
-            // Example threshold for daily consumption
-            const DAILY_CONSUMPTION_THRESHOLD: f32 = 50.0;
-            const DAILY_EMISSION_THRESHOLD: f32 = 5.0;
+        if totals.milli_units > DAILY_CONSUMPTION_THRESHOLD {
+            let message = format!("Your daily energy consumption of {:.2} kWh exceeds the recommended threshold of {:.2} kWh", 
+                totals.milli_units as f64 / 1000.0, DAILY_CONSUMPTION_THRESHOLD as f64 / 1000.0);
             
-            if total_consumption > DAILY_CONSUMPTION_THRESHOLD {
-                // Create an alert for high consumption
-                let message = format!("Your daily energy consumption of {:.2} kWh exceeds the recommended threshold of {:.2} kWh", 
-                    total_consumption, DAILY_CONSUMPTION_THRESHOLD);
-                
-                create_alert(user_principal, message, "medium");
-                alert_count += 1;
-            }
+            create_alert(user_principal, message, "medium");
+            alert_count += 1;
+        }
+        
+        if totals.emitted > DAILY_EMISSION_THRESHOLD {
+            let message = format!("Your daily carbon emission of {:.2} kg exceeds the recommended threshold of {:.2} kg", 
+                totals.emitted.as_kg_f64(), DAILY_EMISSION_THRESHOLD.as_kg_f64());
             
-            if total_emissions > DAILY_EMISSION_THRESHOLD {
-                // Create an alert for high emissions
-                let message = format!("Your daily carbon emission of {:.2} kg exceeds the recommended threshold of {:.2} kg", 
-                    total_emissions, DAILY_EMISSION_THRESHOLD);
-                
-                create_alert(user_principal, message, "high");
-                alert_count += 1;
-            }
+            create_alert(user_principal, message, "high");
+            alert_count += 1;
         }
-    });
+    }
     
-    alert_count
+    Ok(alert_count)
 }
 
 // Helper to create an alert
 fn create_alert(user: Principal, message: String, severity: &str) {
-    let alert_id = ALERT_ID_COUNTER.with(|counter| {
-        let id = *counter.borrow();
-        *counter.borrow_mut() = id + 1;
-        id
-    });
+    create_alert_at(user, message, severity, ic_cdk::api::time());
+}
+
+fn create_alert_at(user: Principal, message: String, severity: &str, timestamp: u64) {
+    let alert_id = next_id(&ALERT_ID_COUNTER);
     
     let alert = Alert {
         id: alert_id,
         user_id: user,
         message,
-        timestamp: ic_cdk::api::time(),
+        timestamp,
         severity: severity.to_string(),
         status: "new".to_string(),
     };
     
     ALERTS.with(|alerts| {
-        alerts.borrow_mut().push(alert);
+        alerts.borrow_mut().insert((user, alert_id), alert);
     });
 }
 
 // Filter alerts by status
 #[query]
-fn filter_alerts(status: String) -> Result<Vec<Alert>, String> {
+fn filter_alerts(status: String) -> Result<Vec<Alert>, GreenGaugeError> {
     let caller = caller();
     
     ALERTS.with(|alerts| {
-        let all_alerts = alerts.borrow();
-        let filtered_alerts = all_alerts.iter()
-            .filter(|alert| alert.user_id == caller && alert.status == status)
-                    .cloned()
+        let filtered_alerts = alerts.borrow()
+            .values_range(user_range(caller))
+            .filter(|alert| alert.status == status)
             .collect::<Vec<Alert>>();
         
         Ok(filtered_alerts)
@@ -1515,7 +1282,7 @@ struct UserProfileUpdateRequest {
 }
 
 #[update]
-fn update_user_profile(request: UserProfileUpdateRequest) -> Result<u64, String> {
+fn update_user_profile(request: UserProfileUpdateRequest) -> Result<u64, GreenGaugeError> {
     let caller = caller();
     
     USERS.with(|users| {
@@ -1523,7 +1290,7 @@ fn update_user_profile(request: UserProfileUpdateRequest) -> Result<u64, String>
         
         match users_map.get(&caller) {
             Some(profile) => {
-                let mut updated_profile = profile.clone();
+                let mut updated_profile = profile;
                 
                 if let Some(username) = request.username {
                     updated_profile.username = Some(username);
@@ -1546,7 +1313,7 @@ fn update_user_profile(request: UserProfileUpdateRequest) -> Result<u64, String>
                 
                 Ok(1) // Return 1 to indicate success
             },
-            None => Err("User profile not found. Please register first.".to_string()),
+            None => Err(GreenGaugeError::NotRegistered),
         }
     })
 }
@@ -1559,3 +1326,150 @@ fn user_exists() -> bool {
         users.borrow().contains_key(&caller)
     })
 }
+
+// Serve the canister's full Candid interface, so that tools such as dfx and
+// the Candid UI can discover every method
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid_interface() -> String {
+    __export_service()
+}
+
+// Export Candid interface. This must stay at the end of the file: methods
+// defined after it are missing from the generated interface.
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::{__export_service, GreenGaugeError, UserProfile};
+    use candid::Principal;
+    use candid_parser::utils::{service_compatible, CandidSource};
+    use proptest::prelude::*;
+    use std::path::Path;
+
+    // The interface the code exports must be a subtype of the checked-in .did,
+    // so that clients built against the file keep working. Regenerate the file
+    // with script.sh after changing the API.
+    #[test]
+    fn checked_in_candid_is_compatible() {
+        let exported = __export_service();
+        let checked_in = Path::new(env!("CARGO_MANIFEST_DIR")).join("green_gauge_backend.did");
+
+        service_compatible(CandidSource::Text(&exported), CandidSource::File(&checked_in))
+            .expect("the exported interface is not compatible with green_gauge_backend.did");
+    }
+
+    fn profile(carbon_allowance: u64, carbon_emitted: u64, carbon_locked: u64) -> UserProfile {
+        UserProfile {
+            principal: Principal::anonymous(),
+            carbon_allowance,
+            carbon_emitted,
+            tokens: 0,
+            has_subcontract: false,
+            username: None,
+            email: None,
+            full_name: None,
+            location: None,
+            join_date: 0,
+            last_activity: 0,
+            carbon_locked: Some(carbon_locked),
+            carbon_available: None,
+            carbon_emitted_remainder: None,
+        }
+    }
+
+    // Emissions are recorded without checking the allowance, so any
+    // combination of the three can occur
+    fn any_profile() -> impl Strategy<Value = UserProfile> {
+        (any::<u64>(), any::<u64>(), any::<u64>())
+            .prop_filter("held allowance fits in a u64", |(allowance, _, locked)| {
+                allowance.checked_add(*locked).is_some()
+            })
+            .prop_map(|(allowance, emitted, locked)| profile(allowance, emitted, locked))
+    }
+
+    proptest! {
+        #[test]
+        fn lock_moves_available_allowance_into_escrow(mut user in any_profile(), amount: u64) {
+            let before = user.clone();
+            match user.lock_allowance(amount) {
+                Ok(()) => {
+                    prop_assert!(amount <= before.available_allowance());
+                    prop_assert_eq!(user.carbon_allowance, before.carbon_allowance - amount);
+                    prop_assert_eq!(user.locked_allowance(), before.locked_allowance() + amount);
+                    prop_assert_eq!(user.held_allowance(), before.held_allowance());
+                }
+                Err(error) => {
+                    prop_assert_eq!(error, GreenGaugeError::InsufficientAllowance {
+                        required: amount,
+                        available: before.available_allowance(),
+                    });
+                    prop_assert_eq!(user.carbon_allowance, before.carbon_allowance);
+                    prop_assert_eq!(user.locked_allowance(), before.locked_allowance());
+                }
+            }
+        }
+
+        #[test]
+        fn unlock_returns_at_most_the_escrow(mut user in any_profile(), amount: u64) {
+            let before = user.clone();
+            user.unlock_allowance(amount);
+            let released = amount.min(before.locked_allowance());
+            prop_assert_eq!(user.locked_allowance(), before.locked_allowance() - released);
+            prop_assert_eq!(user.carbon_allowance, before.carbon_allowance + released);
+            prop_assert_eq!(user.held_allowance(), before.held_allowance());
+        }
+
+        #[test]
+        fn credit_fails_exactly_when_holdings_overflow(mut user in any_profile(), amount: u64) {
+            let held = user.held_allowance().unwrap();
+            let before = user.carbon_allowance;
+            match user.credit_allowance(amount) {
+                Ok(()) => {
+                    prop_assert_eq!(user.carbon_allowance, before + amount);
+                    prop_assert_eq!(user.held_allowance(), Ok(held + amount));
+                }
+                Err(error) => {
+                    prop_assert!(held.checked_add(amount).is_none());
+                    prop_assert_eq!(error, GreenGaugeError::overflow("carbon_allowance"));
+                    prop_assert_eq!(user.carbon_allowance, before);
+                }
+            }
+        }
+
+        #[test]
+        fn debit_never_goes_below_zero(mut user in any_profile(), amount: u64) {
+            let before = user.carbon_allowance;
+            match user.debit_allowance(amount) {
+                Ok(()) => prop_assert_eq!(user.carbon_allowance, before - amount),
+                Err(_) => {
+                    prop_assert!(amount > before);
+                    prop_assert_eq!(user.carbon_allowance, before);
+                }
+            }
+        }
+
+        #[test]
+        fn recording_emissions_fails_exactly_on_overflow(mut user in any_profile(), amount: u64) {
+            let before = user.carbon_emitted;
+            match user.record_emitted(amount) {
+                Ok(()) => prop_assert_eq!(user.carbon_emitted, before + amount),
+                Err(error) => {
+                    prop_assert!(before.checked_add(amount).is_none());
+                    prop_assert_eq!(error, GreenGaugeError::overflow("carbon_emitted"));
+                    prop_assert_eq!(user.carbon_emitted, before);
+                }
+            }
+        }
+
+        // Emissions above the allowance leave nothing available rather than
+        // underflowing
+        #[test]
+        fn available_allowance_never_underflows(user in any_profile()) {
+            let available = user.available_allowance();
+            prop_assert!(available <= user.carbon_allowance);
+            if user.carbon_emitted >= user.carbon_allowance {
+                prop_assert_eq!(available, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,730 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::amount::{to_fixed, Price, Rounding};
+use crate::certified;
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::settlement::{settle, Settlement, SettlementReceipt};
+use crate::{
+    ensure_registered, get_memory, impl_storable, init_counter, next_id, CarbonCredit,
+    CarbonTrade, IdCounter, Memory, CARBON_CREDITS, LEGACY_LISTING_IDS_MEMORY_ID, LISTINGS_MEMORY_ID,
+    LISTING_EXPIRY_MEMORY_ID, LISTING_ID_MEMORY_ID, TRADES, USERS,
+};
+
+pub const VALID_CREDIT_TYPES: [&str; 4] = ["renewable", "forestry", "methane", "efficiency"];
+pub const VALID_CERTIFICATIONS: [&str; 4] = ["gold", "verra", "american", "climate"];
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingStatus {
+    Active,
+    SoldOut,
+    Cancelled,
+    Expired,
+}
+
+// Project behind a voluntary offset listing
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OffsetDetails {
+    credit_type: String,   // renewable, forestry, methane, efficiency
+    certification: String, // gold, verra, american, climate
+    project_name: String,
+    vintage_year: u32,
+    description: String,
+}
+
+// What a listing sells. Both kinds settle as units of carbon allowance.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ListingKind {
+    // Compliance allowance, formerly a `CarbonTrade`
+    Allowance,
+    // Voluntary offsets from a certified project, formerly a `CarbonCredit`
+    Offset(OffsetDetails),
+}
+
+// Record a listing was migrated from
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyListing {
+    Trade(u64),
+    Credit(u64),
+}
+
+impl LegacyListing {
+    // Trade offer and carbon credit ids came from separate counters and
+    // overlap, so the key tells them apart
+    fn key(self) -> (u8, u64) {
+        match self {
+            LegacyListing::Trade(id) => (0, id),
+            LegacyListing::Credit(id) => (1, id),
+        }
+    }
+}
+
+// Allowance offered for sale at a fixed price. The units still for sale are
+// held in the seller's escrow until the listing sells out, is cancelled or
+// expires.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Listing {
+    pub id: u64,
+    pub seller: Principal,
+    kind: ListingKind,
+    // Units still for sale
+    amount: u64,
+    price_per_unit: Price,
+    status: ListingStatus,
+    pub created_at: u64,
+    updated_at: u64,
+    // The listing is withdrawn at this time (nanoseconds since epoch)
+    expires_at: Option<u64>,
+    migrated_from: Option<LegacyListing>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ListingInput {
+    kind: ListingKind,
+    amount: u64,
+    price_per_unit: Price,
+    expires_at: Option<u64>,
+}
+
+impl_storable!(Listing);
+
+thread_local! {
+    static LISTINGS: RefCell<StableBTreeMap<u64, Listing, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LISTINGS_MEMORY_ID)));
+    static LISTING_ID: IdCounter = init_counter(LISTING_ID_MEMORY_ID, 1);
+
+    // Active listings with an expiry, keyed by (expires_at, listing id)
+    static LISTING_EXPIRY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LISTING_EXPIRY_MEMORY_ID)));
+
+    // Listing ids of migrated trade offers and carbon credits, keyed by
+    // `LegacyListing::key`
+    static LEGACY_IDS: RefCell<StableBTreeMap<(u8, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LEGACY_LISTING_IDS_MEMORY_ID)));
+}
+
+impl OffsetDetails {
+    pub fn new(
+        credit_type: String,
+        certification: String,
+        project_name: String,
+        vintage_year: u32,
+        description: String,
+    ) -> Self {
+        OffsetDetails {
+            credit_type,
+            certification,
+            project_name,
+            vintage_year,
+            description,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), GreenGaugeError> {
+        if !VALID_CREDIT_TYPES.contains(&self.credit_type.as_str()) {
+            return Err(GreenGaugeError::invalid_input(
+                "credit_type",
+                format!("must be one of: {}", VALID_CREDIT_TYPES.join(", ")),
+            ));
+        }
+        if !VALID_CERTIFICATIONS.contains(&self.certification.as_str()) {
+            return Err(GreenGaugeError::invalid_input(
+                "certification",
+                format!("must be one of: {}", VALID_CERTIFICATIONS.join(", ")),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ListingKind {
+    pub fn is_allowance(&self) -> bool {
+        matches!(self, ListingKind::Allowance)
+    }
+
+    pub fn is_offset(&self) -> bool {
+        matches!(self, ListingKind::Offset(_))
+    }
+}
+
+impl Listing {
+    fn is_open(&self, now: u64) -> bool {
+        self.status == ListingStatus::Active && !is_expired(self.expires_at, now)
+    }
+
+    // The listing in the shape of the trade offer API
+    pub fn as_trade(&self) -> Option<CarbonTrade> {
+        self.kind.is_allowance().then_some(CarbonTrade {
+            id: self.id,
+            seller: self.seller,
+            amount: self.amount,
+            price_per_unit: self.price_per_unit.tokens_per_unit(),
+            expires_at: self.expires_at,
+            created_at: Some(self.created_at),
+        })
+    }
+
+    // The listing in the shape of the carbon credit API
+    pub fn as_credit(&self) -> Option<CarbonCredit> {
+        let ListingKind::Offset(offset) = &self.kind else {
+            return None;
+        };
+        Some(CarbonCredit {
+            id: self.id,
+            seller: self.seller,
+            amount: self.amount as f64,
+            price_per_unit: self.price_per_unit.tokens_per_unit() as f64,
+            credit_type: offset.credit_type.clone(),
+            certification: offset.certification.clone(),
+            project_name: offset.project_name.clone(),
+            vintage_year: offset.vintage_year,
+            description: offset.description.clone(),
+            creation_time: self.created_at,
+            is_active: self.status == ListingStatus::Active,
+            expires_at: self.expires_at,
+        })
+    }
+
+    // Name recorded on the transactions of this listing
+    fn project_name(&self) -> String {
+        match &self.kind {
+            ListingKind::Allowance => format!("Carbon trade #{}", self.id),
+            ListingKind::Offset(offset) => offset.project_name.clone(),
+        }
+    }
+}
+
+pub fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+pub fn validate_expiry(expires_at: Option<u64>) -> Result<(), GreenGaugeError> {
+    if is_expired(expires_at, ic_cdk::api::time()) {
+        return Err(GreenGaugeError::invalid_input("expires_at", "must be in the future"));
+    }
+    Ok(())
+}
+
+// Store a listing and keep the expiry index in step with its status
+pub fn save(listing: &Listing) {
+    if let Some(expires_at) = listing.expires_at {
+        LISTING_EXPIRY.with(|expiry| {
+            let mut expiry = expiry.borrow_mut();
+            if listing.status == ListingStatus::Active {
+                expiry.insert((expires_at, listing.id), ());
+            } else {
+                expiry.remove(&(expires_at, listing.id));
+            }
+        });
+    }
+    LISTINGS.with(|listings| listings.borrow_mut().insert(listing.id, listing.clone()));
+    certified::certify_listing(listing);
+}
+
+// A listing in any state
+pub fn get(listing_id: u64) -> Option<Listing> {
+    LISTINGS.with(|listings| listings.borrow().get(&listing_id))
+}
+
+// Every listing in any state, oldest first
+pub fn all() -> Vec<Listing> {
+    LISTINGS.with(|listings| listings.borrow().values().collect())
+}
+
+// Return escrowed allowance of a withdrawn or shrunk listing to the seller
+fn release(listing_id: u64, seller: Principal, units: u64) {
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        if let Some(mut profile) = users_map.get(&seller) {
+            let released = units.min(profile.locked_allowance());
+            profile.unlock_allowance(released);
+            users_map.insert(seller, profile);
+            journal::transfer(
+                Cause::new(Reason::ListingRelease, Some(Reference::Listing(listing_id))),
+                JournalAccount::LockedAllowance(seller),
+                JournalAccount::Allowance(seller),
+                released,
+            );
+        }
+    });
+}
+
+fn lock(listing_id: u64, seller: Principal, units: u64) -> Result<(), GreenGaugeError> {
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        let mut profile = users_map
+            .get(&seller)
+            .ok_or(GreenGaugeError::NotRegistered)?;
+        profile.lock_allowance(units)?;
+        users_map.insert(seller, profile);
+        journal::transfer(
+            Cause::new(Reason::ListingEscrow, Some(Reference::Listing(listing_id))),
+            JournalAccount::Allowance(seller),
+            JournalAccount::LockedAllowance(seller),
+            units,
+        );
+        Ok(())
+    })
+}
+
+// Create a listing and move the offered allowance into the seller's escrow
+pub fn create(
+    seller: Principal,
+    kind: ListingKind,
+    amount: u64,
+    price_per_unit: Price,
+    expires_at: Option<u64>,
+) -> Result<Listing, GreenGaugeError> {
+    if amount == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
+    }
+    if price_per_unit == Price::ZERO {
+        return Err(GreenGaugeError::invalid_input("price_per_unit", "must be greater than zero"));
+    }
+    validate_expiry(expires_at)?;
+    if let ListingKind::Offset(offset) = &kind {
+        offset.validate()?;
+    }
+    let id = next_id(&LISTING_ID);
+    lock(id, seller, amount)?;
+
+    let now = ic_cdk::api::time();
+    let listing = Listing {
+        id,
+        seller,
+        kind,
+        amount,
+        price_per_unit,
+        status: ListingStatus::Active,
+        created_at: now,
+        updated_at: now,
+        expires_at,
+        migrated_from: None,
+    };
+    save(&listing);
+    Ok(listing)
+}
+
+// An active, unexpired listing of the kind `matches` accepts. `noun` names
+// the listing in the error.
+pub fn open(
+    listing_id: u64,
+    noun: &str,
+    matches: fn(&ListingKind) -> bool,
+) -> Result<Listing, GreenGaugeError> {
+    LISTINGS
+        .with(|listings| listings.borrow().get(&listing_id))
+        .filter(|listing| matches(&listing.kind) && listing.is_open(ic_cdk::api::time()))
+        .ok_or_else(|| GreenGaugeError::not_found(noun, listing_id))
+}
+
+// The listing a trade offer or carbon credit id names. Ids of migrated
+// offers and credits map to their listing; any other id is a listing id
+// already, since listings are numbered above every legacy id.
+pub fn resolve_legacy(legacy: LegacyListing) -> u64 {
+    LEGACY_IDS
+        .with(|ids| ids.borrow().get(&legacy.key()))
+        .unwrap_or(legacy.key().1)
+}
+
+// One of the caller's listings of the kind `matches` accepts, whatever its status
+pub fn own(
+    listing_id: u64,
+    noun: &str,
+    matches: fn(&ListingKind) -> bool,
+) -> Result<Listing, GreenGaugeError> {
+    let listing = LISTINGS
+        .with(|listings| listings.borrow().get(&listing_id))
+        .filter(|listing| matches(&listing.kind))
+        .ok_or_else(|| GreenGaugeError::not_found(noun, listing_id))?;
+    if listing.seller != caller() {
+        return Err(GreenGaugeError::Unauthorized);
+    }
+    Ok(listing)
+}
+
+// Active listings of the kinds `matches` accepts, oldest first
+pub fn open_listings(matches: fn(&ListingKind) -> bool) -> Vec<Listing> {
+    let now = ic_cdk::api::time();
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .values()
+            .filter(|listing| matches(&listing.kind) && listing.is_open(now))
+            .collect()
+    })
+}
+
+// Buy `amount` units of an open listing through the shared settlement
+pub fn buy(buyer: Principal, mut listing: Listing, amount: u64) -> Result<SettlementReceipt, GreenGaugeError> {
+    if amount == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
+    }
+    if amount > listing.amount {
+        return Err(GreenGaugeError::invalid_input(
+            "amount",
+            format!("exceeds the {} units available in this listing", listing.amount),
+        ));
+    }
+    let total_cost = listing.price_per_unit.cost_of(amount)?;
+
+    let receipt = settle(Settlement {
+        listing_id: listing.id,
+        buyer,
+        seller: listing.seller,
+        units: amount,
+        price_per_unit: listing.price_per_unit,
+        total_cost,
+        project_name: listing.project_name(),
+    })?;
+
+    listing.amount -= amount;
+    listing.updated_at = ic_cdk::api::time();
+    if listing.amount == 0 {
+        listing.status = ListingStatus::SoldOut;
+    }
+    save(&listing);
+
+    Ok(receipt)
+}
+
+// Withdraw an active listing and release its escrow
+pub fn cancel(mut listing: Listing) -> Result<Listing, GreenGaugeError> {
+    if listing.status != ListingStatus::Active {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Listing {} is {:?}",
+            listing.id, listing.status
+        )));
+    }
+
+    release(listing.id, listing.seller, listing.amount);
+    listing.status = ListingStatus::Cancelled;
+    listing.updated_at = ic_cdk::api::time();
+    save(&listing);
+    Ok(listing)
+}
+
+// Change the amount and price of an open listing. Raising the amount escrows
+// the extra allowance, lowering it releases the rest.
+pub fn update(mut listing: Listing, amount: u64, price_per_unit: Price) -> Result<Listing, GreenGaugeError> {
+    if amount == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
+    }
+    if price_per_unit == Price::ZERO {
+        return Err(GreenGaugeError::invalid_input("price_per_unit", "must be greater than zero"));
+    }
+    let now = ic_cdk::api::time();
+    if !listing.is_open(now) {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Listing {} is no longer open",
+            listing.id
+        )));
+    }
+
+    if amount > listing.amount {
+        lock(listing.id, listing.seller, amount - listing.amount)?;
+    } else {
+        release(listing.id, listing.seller, listing.amount - amount);
+    }
+
+    listing.amount = amount;
+    listing.price_per_unit = price_per_unit;
+    listing.updated_at = now;
+    save(&listing);
+    Ok(listing)
+}
+
+// Create a listing of compliance allowance or voluntary offsets
+#[update]
+fn create_listing(input: ListingInput) -> Result<Listing, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    create(caller, input.kind, input.amount, input.price_per_unit, input.expires_at)
+}
+
+// List every open listing, oldest first
+#[query]
+fn get_listings() -> Vec<Listing> {
+    open_listings(|_| true)
+}
+
+// List the caller's listings, including closed ones
+#[query]
+fn get_my_listings() -> Vec<Listing> {
+    let caller = caller();
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .values()
+            .filter(|listing| listing.seller == caller)
+            .collect()
+    })
+}
+
+// Buy units of any open listing
+#[update]
+fn buy_listing(listing_id: u64, amount: u64) -> Result<SettlementReceipt, GreenGaugeError> {
+    let listing = open(listing_id, "listing", |_| true)?;
+    buy(caller(), listing, amount)
+}
+
+// Withdraw one of the caller's listings and release its escrow
+#[update]
+fn cancel_listing(listing_id: u64) -> Result<Listing, GreenGaugeError> {
+    cancel(own(listing_id, "listing", |_| true)?)
+}
+
+// Change the amount and price of one of the caller's listings
+#[update]
+fn update_listing(listing_id: u64, amount: u64, price_per_unit: Price) -> Result<Listing, GreenGaugeError> {
+    update(own(listing_id, "listing", |_| true)?, amount, price_per_unit)
+}
+
+// Withdraw one of the caller's trade offers and release its escrow
+#[update]
+fn cancel_trade_offer(trade_id: u64) -> Result<CarbonTrade, GreenGaugeError> {
+    let trade_id = resolve_legacy(LegacyListing::Trade(trade_id));
+    let listing = cancel(own(trade_id, "trade offer", ListingKind::is_allowance)?)?;
+    Ok(listing.as_trade().expect("listing is a trade offer"))
+}
+
+// Change the amount and price of one of the caller's trade offers
+#[update]
+fn update_trade_offer(
+    trade_id: u64,
+    amount: u64,
+    price_per_unit: u64,
+) -> Result<CarbonTrade, GreenGaugeError> {
+    let trade_id = resolve_legacy(LegacyListing::Trade(trade_id));
+    let listing = own(trade_id, "trade offer", ListingKind::is_allowance)?;
+    let listing = update(listing, amount, Price::new(price_per_unit))?;
+    Ok(listing.as_trade().expect("listing is a trade offer"))
+}
+
+// Withdraw one of the caller's credit listings and release its escrow
+#[update]
+fn deactivate_carbon_credit(credit_id: u64) -> Result<CarbonCredit, GreenGaugeError> {
+    let credit_id = resolve_legacy(LegacyListing::Credit(credit_id));
+    let listing = cancel(own(credit_id, "carbon credit", ListingKind::is_offset)?)?;
+    Ok(listing.as_credit().expect("listing is a carbon credit"))
+}
+
+// Withdraw up to `limit` listings whose expiry has passed and release their
+// escrow. Every key handled leaves the expiry index, so the next call picks
+// up where this one stopped. Returns the number of listings withdrawn and
+// whether expired listings are left.
+pub fn sweep_expired(now: u64, limit: usize) -> (u64, bool) {
+    let expired: Vec<(u64, u64)> = LISTING_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .range(..(now.saturating_add(1), 0))
+            .take(limit.saturating_add(1))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    let more = expired.len() > limit;
+
+    for &(expires_at, listing_id) in expired.iter().take(limit) {
+        match LISTINGS.with(|listings| listings.borrow().get(&listing_id)) {
+            Some(mut listing) if listing.status == ListingStatus::Active => {
+                release(listing.id, listing.seller, listing.amount);
+                listing.status = ListingStatus::Expired;
+                listing.updated_at = now;
+                save(&listing);
+            }
+            _ => {
+                LISTING_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(expires_at, listing_id)));
+            }
+        }
+    }
+
+    (expired.len().min(limit) as u64, more)
+}
+
+// Remove every listing of a seller. Returns the number removed.
+pub fn remove_all_of(seller: Principal) -> u64 {
+    let owned: Vec<Listing> = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .values()
+            .filter(|listing| listing.seller == seller)
+            .collect()
+    });
+
+    for listing in &owned {
+        if let Some(expires_at) = listing.expires_at {
+            LISTING_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(expires_at, listing.id)));
+        }
+        LISTINGS.with(|listings| listings.borrow_mut().remove(&listing.id));
+        certified::uncertify_listing(listing.id);
+    }
+
+    owned.len() as u64
+}
+
+// Move trade offers and carbon credits from the stores of the two former
+// marketplaces into listings, oldest first. Listings take fresh ids and
+// record the id they had before, which the legacy endpoints resolve through.
+// Fractional credit prices are rounded up to whole tokens, and inactive
+// credits, which don't record whether they sold out or were withdrawn, are
+// migrated as cancelled with nothing left.
+pub fn migrate_legacy_listings() {
+    let trades: Vec<CarbonTrade> = TRADES.with(|trades| trades.borrow().values().collect());
+    let credits: Vec<CarbonCredit> = CARBON_CREDITS.with(|credits| credits.borrow().values().collect());
+    if trades.is_empty() && credits.is_empty() {
+        return;
+    }
+
+    for listing in convert_legacy(&trades, &credits, ic_cdk::api::time()) {
+        save(&listing);
+    }
+    for trade in &trades {
+        TRADES.with(|store| store.borrow_mut().remove(&trade.id));
+    }
+    for credit in &credits {
+        CARBON_CREDITS.with(|store| store.borrow_mut().remove(&credit.id));
+    }
+
+    ic_cdk::println!(
+        "Migrated {} trade offers and {} carbon credits to listings",
+        trades.len(),
+        credits.len()
+    );
+}
+
+// Turn legacy offers and credits into listings and remember which listing
+// each became
+fn convert_legacy(trades: &[CarbonTrade], credits: &[CarbonCredit], now: u64) -> Vec<Listing> {
+    // Listings are numbered above every legacy id, so that an id a client kept
+    // from before the migration never names a different listing
+    let first_free = trades
+        .iter()
+        .map(|trade| trade.id)
+        .chain(credits.iter().map(|credit| credit.id))
+        .max()
+        .map_or(0, |last| last + 1);
+    LISTING_ID.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        if *counter.get() < first_free {
+            counter.set(first_free).expect("failed to persist id counter");
+        }
+    });
+
+    let mut listings = Vec::with_capacity(trades.len() + credits.len());
+    for trade in trades {
+        // Legacy offers never recorded when they were made; the seller's
+        // join date is the earliest they can have been
+        let created_at = trade.created_at.unwrap_or_else(|| {
+            USERS
+                .with(|users| users.borrow().get(&trade.seller))
+                .map_or(now, |profile| profile.join_date)
+        });
+        listings.push(Listing {
+            id: next_id(&LISTING_ID),
+            seller: trade.seller,
+            kind: ListingKind::Allowance,
+            amount: trade.amount,
+            price_per_unit: Price::new(trade.price_per_unit),
+            status: ListingStatus::Active,
+            created_at,
+            updated_at: now,
+            expires_at: trade.expires_at,
+            migrated_from: Some(LegacyListing::Trade(trade.id)),
+        });
+    }
+
+    for credit in credits {
+        let (amount, status) = if credit.is_active {
+            (to_fixed(credit.amount, 1, Rounding::Down).unwrap_or(0), ListingStatus::Active)
+        } else {
+            (0, ListingStatus::Cancelled)
+        };
+        listings.push(Listing {
+            id: next_id(&LISTING_ID),
+            seller: credit.seller,
+            kind: ListingKind::Offset(OffsetDetails::new(
+                credit.credit_type.clone(),
+                credit.certification.clone(),
+                credit.project_name.clone(),
+                credit.vintage_year,
+                credit.description.clone(),
+            )),
+            amount,
+            price_per_unit: Price::new(
+                to_fixed(credit.price_per_unit, 1, Rounding::Up).unwrap_or(u64::MAX),
+            ),
+            status,
+            created_at: credit.creation_time,
+            updated_at: now,
+            expires_at: credit.expires_at,
+            migrated_from: Some(LegacyListing::Credit(credit.id)),
+        });
+    }
+
+    LEGACY_IDS.with(|ids| {
+        let mut ids = ids.borrow_mut();
+        for listing in &listings {
+            if let Some(legacy) = listing.migrated_from {
+                ids.insert(legacy.key(), listing.id);
+            }
+        }
+    });
+    listings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(id: u64) -> CarbonTrade {
+        CarbonTrade {
+            id,
+            seller: Principal::anonymous(),
+            amount: 10,
+            price_per_unit: 5,
+            expires_at: None,
+            created_at: Some(0),
+        }
+    }
+
+    fn credit(id: u64) -> CarbonCredit {
+        CarbonCredit {
+            id,
+            seller: Principal::anonymous(),
+            amount: 10.0,
+            price_per_unit: 5.0,
+            credit_type: "forestry".to_string(),
+            certification: "verra".to_string(),
+            project_name: "test".to_string(),
+            vintage_year: 2024,
+            description: String::new(),
+            creation_time: 0,
+            is_active: true,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn overlapping_legacy_ids_resolve_to_their_own_listing() {
+        let listings = convert_legacy(&[trade(1), trade(2)], &[credit(1), credit(2), credit(3)], 0);
+
+        let ids: Vec<u64> = listings.iter().map(|listing| listing.id).collect();
+        // Numbered above every legacy id
+        assert_eq!(ids, vec![4, 5, 6, 7, 8]);
+        assert_eq!(resolve_legacy(LegacyListing::Trade(1)), 4);
+        assert_eq!(resolve_legacy(LegacyListing::Trade(2)), 5);
+        assert_eq!(resolve_legacy(LegacyListing::Credit(1)), 6);
+        assert_eq!(resolve_legacy(LegacyListing::Credit(3)), 8);
+        // Trade 3 never existed, and no listing takes its id
+        assert_eq!(resolve_legacy(LegacyListing::Trade(3)), 3);
+        assert!(!ids.contains(&3));
+        // Listings made after the migration keep their own ids
+        assert_eq!(resolve_legacy(LegacyListing::Trade(9)), 9);
+        assert_eq!(next_id(&LISTING_ID), 9);
+    }
+}
@@ -0,0 +1,666 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::thread::LocalKey;
+
+use crate::amount::{self, Price, Rounding};
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::ledger::{self, Account};
+use crate::{
+    ensure_registered, get_memory, impl_storable, init_counter, next_id, IdCounter, Memory,
+    BUY_BOOK_MEMORY_ID, COMMISSION_RATE, FILLS_MEMORY_ID, FILL_ID_MEMORY_ID,
+    ORDERS_MEMORY_ID, ORDER_ID_MEMORY_ID, SELL_BOOK_MEMORY_ID, USERS,
+};
+
+pub const MAX_BOOK_DEPTH: u32 = 100;
+pub const MAX_FILLS_QUERY: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+// A limit order for allowance units, priced in GG tokens per unit. Funds are
+// escrowed when the order is placed: a buy order locks `remaining * price`
+// tokens, a sell order locks `remaining` units of the seller's allowance.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Order {
+    id: u64,
+    owner: Principal,
+    side: Side,
+    price: Price,
+    quantity: u64,
+    remaining: u64,
+    status: OrderStatus,
+    created_at: u64,
+    updated_at: u64,
+    // The order this one replaced, if any
+    replaces: Option<u64>,
+}
+
+// A match between a buy and a sell order. Trades execute at the price of the
+// order that was resting on the book.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Fill {
+    id: u64,
+    buy_order_id: u64,
+    sell_order_id: u64,
+    buyer: Principal,
+    seller: Principal,
+    price: Price,
+    quantity: u64,
+    commission: u64,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PlacedOrder {
+    order: Order,
+    fills: Vec<Fill>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PriceLevel {
+    price: Price,
+    quantity: u64,
+    orders: u32,
+}
+
+// Aggregated resting orders, best price first on both sides
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OrderBook {
+    bids: Vec<PriceLevel>,
+    asks: Vec<PriceLevel>,
+    last_price: Option<Price>,
+}
+
+impl_storable!(Order, Fill);
+
+type Book = RefCell<StableBTreeMap<(u64, u64), (), Memory>>;
+
+thread_local! {
+    static ORDERS: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORDERS_MEMORY_ID)));
+    static ORDER_ID: IdCounter = init_counter(ORDER_ID_MEMORY_ID, 1);
+
+    // Resting orders in priority order. Bids are keyed by (u64::MAX - price,
+    // order id) so that the highest price sorts first; asks by (price, order
+    // id). Order ids increase over time, which gives time priority.
+    static BUY_BOOK: Book =
+        RefCell::new(StableBTreeMap::init(get_memory(BUY_BOOK_MEMORY_ID)));
+    static SELL_BOOK: Book =
+        RefCell::new(StableBTreeMap::init(get_memory(SELL_BOOK_MEMORY_ID)));
+
+    static FILLS: RefCell<StableBTreeMap<u64, Fill, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FILLS_MEMORY_ID)));
+    static FILL_ID: IdCounter = init_counter(FILL_ID_MEMORY_ID, 0);
+}
+
+fn book_key(side: Side, price: Price, order_id: u64) -> (u64, u64) {
+    match side {
+        Side::Buy => (u64::MAX - price.tokens_per_unit(), order_id),
+        Side::Sell => (price.tokens_per_unit(), order_id),
+    }
+}
+
+fn book(side: Side) -> &'static LocalKey<Book> {
+    match side {
+        Side::Buy => &BUY_BOOK,
+        Side::Sell => &SELL_BOOK,
+    }
+}
+
+fn opposite(side: Side) -> Side {
+    match side {
+        Side::Buy => Side::Sell,
+        Side::Sell => Side::Buy,
+    }
+}
+
+fn get_order(order_id: u64) -> Result<Order, GreenGaugeError> {
+    ORDERS
+        .with(|orders| orders.borrow().get(&order_id))
+        .ok_or_else(|| GreenGaugeError::not_found("order", order_id))
+}
+
+fn save_order(order: &Order) {
+    ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+}
+
+fn rest(order: &Order) {
+    book(order.side).with(|book| {
+        book.borrow_mut()
+            .insert(book_key(order.side, order.price, order.id), ())
+    });
+}
+
+fn unrest(order: &Order) {
+    book(order.side).with(|book| {
+        book.borrow_mut()
+            .remove(&book_key(order.side, order.price, order.id))
+    });
+}
+
+// Resting order ids of one side in priority order. Each step looks up the
+// key after the previous one, so only as much of the book is read as the
+// caller takes, and no borrow of the book is held in between.
+fn resting(side: Side) -> impl Iterator<Item = u64> {
+    let mut last: Option<(u64, u64)> = None;
+    std::iter::from_fn(move || {
+        let start = last.map_or(Bound::Unbounded, Bound::Excluded);
+        let (key, _) = book(side).with(|book| book.borrow().range((start, Bound::Unbounded)).next())?;
+        last = Some(key);
+        Some(key.1)
+    })
+}
+
+fn cause(reason: Reason, order_id: u64) -> Cause {
+    Cause::new(reason, Some(Reference::Order(order_id)))
+}
+
+// Tokens a buy order holds in escrow for its remaining quantity. The full
+// order value was checked for overflow when it was placed.
+fn escrowed_value(order: &Order) -> u64 {
+    order
+        .price
+        .cost_of(order.remaining)
+        .expect("order value was checked when the order was placed")
+}
+
+// Resting orders `incoming` would trade with, best first, as (order, units)
+fn plan_matches(incoming: &Order) -> Result<Vec<(Order, u64)>, GreenGaugeError> {
+    let mut remaining = incoming.remaining;
+    let mut matches = Vec::new();
+
+    for order_id in resting(opposite(incoming.side)) {
+        if remaining == 0 {
+            break;
+        }
+
+        let resting = get_order(order_id)?;
+        let crosses = match incoming.side {
+            Side::Buy => resting.price <= incoming.price,
+            Side::Sell => resting.price >= incoming.price,
+        };
+        if !crosses {
+            break;
+        }
+
+        if resting.owner == incoming.owner {
+            return Err(GreenGaugeError::invalid_input(
+                "price",
+                format!("would trade against your own order {}", resting.id),
+            ));
+        }
+
+        let quantity = remaining.min(resting.remaining);
+        remaining -= quantity;
+        matches.push((resting, quantity));
+    }
+
+    Ok(matches)
+}
+
+// Check that every buyer can be credited the units the matches give them
+fn ensure_buyers_can_hold(incoming: &Order, matches: &[(Order, u64)]) -> Result<(), GreenGaugeError> {
+    // The matched units add up to at most the incoming order's quantity
+    let mut received: BTreeMap<Principal, u64> = BTreeMap::new();
+    for (resting, quantity) in matches {
+        let buyer = match incoming.side {
+            Side::Buy => incoming.owner,
+            Side::Sell => resting.owner,
+        };
+        *received.entry(buyer).or_default() += quantity;
+    }
+
+    USERS.with(|users| {
+        let users_map = users.borrow();
+        for (buyer, quantity) in received {
+            if let Some(mut profile) = users_map.get(&buyer) {
+                profile.credit_allowance(quantity)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Execute one match. The buyer's tokens and the seller's units are already in
+// escrow: the seller is paid from escrow minus the platform commission, and
+// the buyer is refunded any difference to their own limit price.
+fn execute(incoming: &Order, resting: &mut Order, quantity: u64, now: u64) -> Fill {
+    let (buy, sell) = match incoming.side {
+        Side::Buy => (incoming, &*resting),
+        Side::Sell => (&*resting, incoming),
+    };
+    let price = resting.price;
+    let value = price
+        .cost_of(quantity)
+        .expect("order value was checked when the order was placed");
+    let commission = COMMISSION_RATE.apply(value, Rounding::Down);
+
+    ledger::release_escrow(Account::of(sell.owner), value - commission, cause(Reason::OrderFill, sell.id));
+    if commission > 0 {
+        ledger::release_escrow(ledger::treasury_account(), commission, cause(Reason::OrderFill, sell.id));
+    }
+    // The buy order's limit is at least the resting price, and its value at
+    // that limit fits in a u64
+    let improvement = buy
+        .price
+        .checked_sub(price)
+        .and_then(|difference| difference.cost_of(quantity).ok())
+        .expect("buy limit covers the execution price");
+    if improvement > 0 {
+        ledger::release_escrow(Account::of(buy.owner), improvement, cause(Reason::OrderFill, buy.id));
+    }
+
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        if let Some(mut profile) = users_map.get(&sell.owner) {
+            profile.spend_locked_allowance(quantity);
+            users_map.insert(sell.owner, profile);
+        }
+        if let Some(mut profile) = users_map.get(&buy.owner) {
+            profile
+                .credit_allowance(quantity)
+                .expect("buyers were checked to hold their matches before execution");
+            profile.last_activity = now;
+            users_map.insert(buy.owner, profile);
+        }
+    });
+    journal::transfer(
+        cause(Reason::OrderFill, sell.id),
+        JournalAccount::LockedAllowance(sell.owner),
+        JournalAccount::Allowance(buy.owner),
+        quantity,
+    );
+
+    let fill = Fill {
+        id: next_id(&FILL_ID),
+        buy_order_id: buy.id,
+        sell_order_id: sell.id,
+        buyer: buy.owner,
+        seller: sell.owner,
+        price,
+        quantity,
+        commission,
+        timestamp: now,
+    };
+    FILLS.with(|fills| fills.borrow_mut().insert(fill.id, fill.clone()));
+
+    resting.remaining -= quantity;
+    resting.updated_at = now;
+    if resting.remaining == 0 {
+        resting.status = OrderStatus::Filled;
+        unrest(resting);
+    }
+    save_order(resting);
+
+    fill
+}
+
+// Check that `owner` can escrow an order, counting what a replaced order
+// would release
+fn ensure_can_escrow(
+    owner: Principal,
+    side: Side,
+    price: Price,
+    quantity: u64,
+    released: Option<&Order>,
+) -> Result<(), GreenGaugeError> {
+    let released = released.filter(|order| order.side == side).map_or(0, |order| match side {
+        Side::Buy => escrowed_value(order),
+        Side::Sell => order.remaining,
+    });
+
+    match side {
+        Side::Buy => {
+            let required = price.cost_of(quantity)?;
+            let available = amount::add("available", ledger::balance_of(owner), released)?;
+            if available < required {
+                return Err(GreenGaugeError::InsufficientTokens { required, available });
+            }
+        }
+        Side::Sell => {
+            let profile = USERS
+                .with(|users| users.borrow().get(&owner))
+                .ok_or(GreenGaugeError::NotRegistered)?;
+            let available = amount::add("available", profile.available_allowance(), released)?;
+            if available < quantity {
+                return Err(GreenGaugeError::InsufficientAllowance {
+                    required: quantity,
+                    available,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lock(order: &Order) {
+    match order.side {
+        Side::Buy => {
+            ledger::transfer_internal(
+                order.owner,
+                ledger::escrow_account(),
+                escrowed_value(order),
+                cause(Reason::OrderEscrow, order.id),
+            );
+        }
+        Side::Sell => USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            if let Some(mut profile) = users_map.get(&order.owner) {
+                profile
+                    .lock_allowance(order.remaining)
+                    .expect("sell order allowance was checked before locking");
+                users_map.insert(order.owner, profile);
+                journal::transfer(
+                    cause(Reason::OrderEscrow, order.id),
+                    JournalAccount::Allowance(order.owner),
+                    JournalAccount::LockedAllowance(order.owner),
+                    order.remaining,
+                );
+            }
+        }),
+    }
+}
+
+fn unlock(order: &Order) {
+    match order.side {
+        Side::Buy => {
+            if order.remaining > 0 {
+                ledger::release_escrow(
+                    Account::of(order.owner),
+                    escrowed_value(order),
+                    cause(Reason::OrderRelease, order.id),
+                );
+            }
+        }
+        Side::Sell => USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            if let Some(mut profile) = users_map.get(&order.owner) {
+                let released = order.remaining.min(profile.locked_allowance());
+                profile.unlock_allowance(released);
+                users_map.insert(order.owner, profile);
+                journal::transfer(
+                    cause(Reason::OrderRelease, order.id),
+                    JournalAccount::LockedAllowance(order.owner),
+                    JournalAccount::Allowance(order.owner),
+                    released,
+                );
+            }
+        }),
+    }
+}
+
+fn cancel(order: &mut Order, now: u64) {
+    unrest(order);
+    unlock(order);
+    order.status = OrderStatus::Cancelled;
+    order.updated_at = now;
+    save_order(order);
+}
+
+fn ensure_cancellable(order: &Order, owner: Principal) -> Result<(), GreenGaugeError> {
+    if order.owner != owner {
+        return Err(GreenGaugeError::Unauthorized);
+    }
+    if order.status != OrderStatus::Open {
+        return Err(GreenGaugeError::invalid_state(format!(
+            "Order {} is {:?}",
+            order.id, order.status
+        )));
+    }
+    Ok(())
+}
+
+// Validate, escrow and match a new order, then rest what is left on the book.
+// A replaced order is cancelled once every check has passed.
+fn place(
+    owner: Principal,
+    side: Side,
+    price: Price,
+    quantity: u64,
+    replaced: Option<Order>,
+) -> Result<PlacedOrder, GreenGaugeError> {
+    if quantity == 0 {
+        return Err(GreenGaugeError::invalid_input("quantity", "must be greater than zero"));
+    }
+    if price == Price::ZERO {
+        return Err(GreenGaugeError::invalid_input("price", "must be greater than zero"));
+    }
+    ensure_can_escrow(owner, side, price, quantity, replaced.as_ref())?;
+
+    let now = ic_cdk::api::time();
+    let mut order = Order {
+        id: 0,
+        owner,
+        side,
+        price,
+        quantity,
+        remaining: quantity,
+        status: OrderStatus::Open,
+        created_at: now,
+        updated_at: now,
+        replaces: replaced.as_ref().map(|order| order.id),
+    };
+    let matches = plan_matches(&order)?;
+    ensure_buyers_can_hold(&order, &matches)?;
+
+    // Nothing below this point can fail
+    if let Some(mut replaced) = replaced {
+        cancel(&mut replaced, now);
+    }
+    order.id = next_id(&ORDER_ID);
+    lock(&order);
+
+    let mut fills = Vec::with_capacity(matches.len());
+    for (mut resting, quantity) in matches {
+        fills.push(execute(&order, &mut resting, quantity, now));
+        order.remaining -= quantity;
+    }
+
+    if order.remaining == 0 {
+        order.status = OrderStatus::Filled;
+    } else {
+        rest(&order);
+    }
+    save_order(&order);
+
+    Ok(PlacedOrder { order, fills })
+}
+
+// Place a limit order. It trades immediately against crossing orders in
+// price-time priority and rests on the book for the remaining quantity.
+#[update]
+fn place_order(side: Side, price: Price, quantity: u64) -> Result<PlacedOrder, GreenGaugeError> {
+    let caller = caller();
+    ensure_registered(caller)?;
+
+    place(caller, side, price, quantity, None)
+}
+
+// Cancel one of the caller's open orders and release its escrow
+#[update]
+fn cancel_order(order_id: u64) -> Result<Order, GreenGaugeError> {
+    let mut order = get_order(order_id)?;
+    ensure_cancellable(&order, caller())?;
+
+    cancel(&mut order, ic_cdk::api::time());
+    Ok(order)
+}
+
+// Replace one of the caller's open orders with a new price and quantity.
+// The new order loses the time priority of the old one.
+#[update]
+fn replace_order(order_id: u64, price: Price, quantity: u64) -> Result<PlacedOrder, GreenGaugeError> {
+    let caller = caller();
+    let order = get_order(order_id)?;
+    ensure_cancellable(&order, caller)?;
+
+    place(caller, order.side, price, quantity, Some(order))
+}
+
+// Allowance `owner` has escrowed in open sell orders
+pub fn locked_in_orders(owner: Principal) -> Result<u64, GreenGaugeError> {
+    ORDERS.with(|orders| {
+        amount::sum(
+            "locked",
+            orders
+                .borrow()
+                .values()
+                .filter(|order| {
+                    order.owner == owner && order.side == Side::Sell && order.status == OrderStatus::Open
+                })
+                .map(|order| order.remaining),
+        )
+    })
+}
+
+// Aggregate the best `depth` price levels of each side
+#[query]
+fn get_order_book(depth: u32) -> Result<OrderBook, GreenGaugeError> {
+    if depth == 0 || depth > MAX_BOOK_DEPTH {
+        return Err(GreenGaugeError::invalid_input(
+            "depth",
+            format!("must be between 1 and {}", MAX_BOOK_DEPTH),
+        ));
+    }
+
+    let levels = |side: Side| {
+        let mut levels: Vec<PriceLevel> = Vec::new();
+        for order_id in resting(side) {
+            let Ok(order) = get_order(order_id) else {
+                continue;
+            };
+            if let Some(level) = levels.last_mut().filter(|level| level.price == order.price) {
+                level.quantity = amount::add("quantity", level.quantity, order.remaining)?;
+                level.orders += 1;
+                continue;
+            }
+            if levels.len() == depth as usize {
+                break;
+            }
+            levels.push(PriceLevel {
+                price: order.price,
+                quantity: order.remaining,
+                orders: 1,
+            });
+        }
+        Ok::<_, GreenGaugeError>(levels)
+    };
+
+    Ok(OrderBook {
+        bids: levels(Side::Buy)?,
+        asks: levels(Side::Sell)?,
+        last_price: FILLS.with(|fills| fills.borrow().last_key_value().map(|(_, fill)| fill.price)),
+    })
+}
+
+// List the caller's orders, newest first
+#[query]
+fn get_my_orders() -> Vec<Order> {
+    let caller = caller();
+    ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, order)| order)
+            .filter(|order| order.owner == caller)
+            .collect()
+    })
+}
+
+// List the most recent fills, newest first
+#[query]
+fn get_recent_fills(limit: u32) -> Vec<Fill> {
+    FILLS.with(|fills| {
+        fills
+            .borrow()
+            .iter()
+            .rev()
+            .take(limit.min(MAX_FILLS_QUERY) as usize)
+            .map(|(_, fill)| fill)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, owner: u8, side: Side, price: u64, quantity: u64) -> Order {
+        Order {
+            id,
+            owner: Principal::from_slice(&[owner]),
+            side,
+            price: Price::new(price),
+            quantity,
+            remaining: quantity,
+            status: OrderStatus::Open,
+            created_at: id,
+            updated_at: id,
+            replaces: None,
+        }
+    }
+
+    fn resting_order(id: u64, owner: u8, side: Side, price: u64, quantity: u64) {
+        let order = order(id, owner, side, price, quantity);
+        save_order(&order);
+        rest(&order);
+    }
+
+    fn planned(incoming: &Order) -> Vec<(u64, u64)> {
+        plan_matches(incoming)
+            .unwrap()
+            .into_iter()
+            .map(|(resting, quantity)| (resting.id, quantity))
+            .collect()
+    }
+
+    #[test]
+    fn matches_follow_price_then_time_priority() {
+        resting_order(1, 1, Side::Sell, 12, 10);
+        resting_order(2, 2, Side::Sell, 10, 10);
+        resting_order(3, 3, Side::Sell, 10, 10);
+        resting_order(4, 4, Side::Sell, 15, 10);
+
+        // Stops within the order at 12 and never reaches the one at 15
+        assert_eq!(planned(&order(9, 9, Side::Buy, 12, 25)), vec![(2, 10), (3, 10), (1, 5)]);
+        assert_eq!(planned(&order(9, 9, Side::Buy, 20, 100)).len(), 4);
+        assert!(planned(&order(9, 9, Side::Buy, 9, 10)).is_empty());
+    }
+
+    #[test]
+    fn sell_orders_take_the_highest_bids() {
+        resting_order(1, 1, Side::Buy, 9, 10);
+        resting_order(2, 2, Side::Buy, 11, 3);
+        resting_order(3, 3, Side::Buy, 10, 10);
+
+        assert_eq!(planned(&order(9, 9, Side::Sell, 10, 5)), vec![(2, 3), (3, 2)]);
+        assert!(planned(&order(9, 9, Side::Sell, 12, 5)).is_empty());
+    }
+
+    #[test]
+    fn crossing_your_own_order_is_rejected() {
+        resting_order(1, 1, Side::Sell, 10, 10);
+        resting_order(2, 2, Side::Sell, 11, 10);
+
+        assert!(plan_matches(&order(9, 2, Side::Buy, 11, 15)).is_err());
+        // An order of your own that would not be reached does not matter
+        assert_eq!(planned(&order(9, 2, Side::Buy, 11, 5)), vec![(1, 5)]);
+    }
+}
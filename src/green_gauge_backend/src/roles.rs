@@ -0,0 +1,183 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, is_controller};
+use ic_cdk_macros::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::error::GreenGaugeError;
+use crate::{get_memory, impl_storable, Memory, ROLES_MEMORY_ID};
+
+// Roles that can be granted to a principal. Canister controllers always hold
+// `Admin`, which is how the first admin is bootstrapped on a fresh install.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Auditor,
+    // Inspects the readings participants have submitted
+    Verifier,
+    // Gives devices principals of their own to submit readings with
+    DeviceOperator,
+    // Runs the cap-and-trade scheme: compliance periods, caps and allocations
+    Regulator,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RoleAssignment {
+    principal: Principal,
+    roles: Vec<Role>,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+impl_storable!(RoleAssignment);
+
+thread_local! {
+    static ROLES: RefCell<StableBTreeMap<Principal, RoleAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ROLES_MEMORY_ID)));
+}
+
+// Check whether a principal holds a role, either granted or as a controller
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    if role == Role::Admin && is_controller(principal) {
+        return true;
+    }
+
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .get(principal)
+            .is_some_and(|assignment| assignment.roles.contains(&role))
+    })
+}
+
+// Admins may act in every other role, so each guard also accepts them
+fn require_role(role: Role) -> Result<(), String> {
+    let caller = caller();
+    if has_role(&caller, Role::Admin) || has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(format!("Caller does not hold the {:?} role", role))
+    }
+}
+
+// Guards for endpoints restricted to a role
+pub fn caller_is_admin() -> Result<(), String> {
+    require_role(Role::Admin)
+}
+
+pub fn caller_is_auditor() -> Result<(), String> {
+    require_role(Role::Auditor)
+}
+
+pub fn caller_is_regulator() -> Result<(), String> {
+    require_role(Role::Regulator)
+}
+
+pub fn caller_is_verifier() -> Result<(), String> {
+    require_role(Role::Verifier)
+}
+
+pub fn caller_is_device_operator() -> Result<(), String> {
+    require_role(Role::DeviceOperator)
+}
+
+// Grant a role to a principal
+#[update(guard = "caller_is_admin")]
+fn grant_role(principal: Principal, role: Role) -> Result<(), GreenGaugeError> {
+    if principal == Principal::anonymous() {
+        return Err(GreenGaugeError::invalid_input(
+            "principal",
+            "roles cannot be granted to the anonymous principal",
+        ));
+    }
+
+    ROLES.with(|roles| {
+        let mut roles_map = roles.borrow_mut();
+        let mut assignment = roles_map.get(&principal).unwrap_or(RoleAssignment {
+            principal,
+            roles: Vec::new(),
+            updated_by: caller(),
+            updated_at: 0,
+        });
+
+        if assignment.roles.contains(&role) {
+            return Err(GreenGaugeError::already_exists(
+                "role assignment",
+                format!("{} {:?}", principal, role),
+            ));
+        }
+
+        assignment.roles.push(role);
+        assignment.updated_by = caller();
+        assignment.updated_at = ic_cdk::api::time();
+        roles_map.insert(principal, assignment);
+
+        Ok(())
+    })
+}
+
+// Revoke a role from a principal
+#[update(guard = "caller_is_admin")]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), GreenGaugeError> {
+    if role == Role::Admin && is_controller(&principal) {
+        return Err(GreenGaugeError::invalid_input(
+            "principal",
+            "canister controllers always hold the Admin role",
+        ));
+    }
+
+    ROLES.with(|roles| {
+        let mut roles_map = roles.borrow_mut();
+        let mut assignment = match roles_map.get(&principal) {
+            Some(assignment) if assignment.roles.contains(&role) => assignment,
+            _ => {
+                return Err(GreenGaugeError::not_found(
+                    "role assignment",
+                    format!("{} {:?}", principal, role),
+                ))
+            }
+        };
+
+        assignment.roles.retain(|r| *r != role);
+        if assignment.roles.is_empty() {
+            roles_map.remove(&principal);
+        } else {
+            assignment.updated_by = caller();
+            assignment.updated_at = ic_cdk::api::time();
+            roles_map.insert(principal, assignment);
+        }
+
+        Ok(())
+    })
+}
+
+// List every explicit role assignment
+#[query(guard = "caller_is_auditor")]
+fn list_roles() -> Vec<RoleAssignment> {
+    ROLES.with(|roles| roles.borrow().values().collect())
+}
+
+// Get the roles held by the caller, including the implicit controller admin
+#[query]
+fn get_my_roles() -> Vec<Role> {
+    let caller = caller();
+    let mut held = ROLES.with(|roles| {
+        roles
+            .borrow()
+            .get(&caller)
+            .map(|assignment| assignment.roles)
+            .unwrap_or_default()
+    });
+
+    if is_controller(&caller) && !held.contains(&Role::Admin) {
+        held.push(Role::Admin);
+    }
+
+    held
+}
+
+// Check whether the caller is an admin
+#[query]
+fn is_admin() -> bool {
+    has_role(&caller(), Role::Admin)
+}
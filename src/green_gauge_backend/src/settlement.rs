@@ -0,0 +1,228 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::amount::{self, Co2e, Price, Rounding};
+use crate::certified;
+use crate::error::GreenGaugeError;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::ledger::{self, Account};
+use crate::{next_id, Transaction, COMMISSION_RATE, TRANSACTIONS, TRANSACTION_ID_COUNTER, USERS};
+
+// A purchase of `units` of carbon allowance from a marketplace listing
+pub struct Settlement {
+    pub listing_id: u64,
+    pub buyer: Principal,
+    pub seller: Principal,
+    pub units: u64,
+    pub price_per_unit: Price,
+    pub total_cost: u64,
+    pub project_name: String,
+}
+
+// Returned to the buyer once a purchase has been settled
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SettlementReceipt {
+    transaction_id: u64,
+    listing_id: u64,
+    buyer: Principal,
+    seller: Principal,
+    units: u64,
+    total_cost: u64,
+    commission: u64,
+    seller_proceeds: u64,
+    buyer_token_balance: u64,
+    buyer_carbon_allowance: u64,
+    settled_at: u64,
+}
+
+// Settle a purchase: the buyer pays `total_cost` in GG tokens, the seller
+// receives it minus the platform commission, which goes to the treasury
+// account, and `units` of allowance move from the seller's escrow to the
+// buyer.
+//
+// Every check runs before the first write, so an error leaves all balances,
+// history and the transaction log untouched. Callers must validate the listing
+// beforehand and only update it once this returns `Ok`.
+pub fn settle(order: Settlement) -> Result<SettlementReceipt, GreenGaugeError> {
+    if order.buyer == order.seller {
+        return Err(GreenGaugeError::invalid_input("listing_id", "you cannot buy your own listing"));
+    }
+
+    if order.units == 0 {
+        return Err(GreenGaugeError::invalid_input("amount", "must be greater than zero"));
+    }
+
+    let (mut buyer, mut seller) = USERS.with(|users| {
+        let users_map = users.borrow();
+        let buyer = users_map
+            .get(&order.buyer)
+            .ok_or(GreenGaugeError::NotRegistered)?;
+        let seller = users_map
+            .get(&order.seller)
+            .ok_or_else(|| GreenGaugeError::not_found("user", order.seller))?;
+        Ok::<_, GreenGaugeError>((buyer, seller))
+    })?;
+
+    let buyer_tokens = ledger::balance_of(order.buyer);
+    if buyer_tokens < order.total_cost {
+        return Err(GreenGaugeError::InsufficientTokens {
+            required: order.total_cost,
+            available: buyer_tokens,
+        });
+    }
+
+    // Listings lock their allowance when created, so this only fails if the
+    // escrow is out of step with the listing
+    let seller_locked = seller.locked_allowance();
+    if seller_locked < order.units {
+        return Err(GreenGaugeError::InsufficientAllowance {
+            required: order.units,
+            available: seller_locked,
+        });
+    }
+
+    // Calculate the seller's earnings (minus commission)
+    let commission = COMMISSION_RATE.apply(order.total_cost, Rounding::Down);
+    let seller_proceeds = amount::sub("seller_proceeds", order.total_cost, commission)?;
+
+    buyer.credit_allowance(order.units)?;
+    let quantity = Co2e::from_kg(order.units).ok_or_else(|| GreenGaugeError::overflow("quantity"))?;
+
+    // Nothing below this point can fail
+    let now = ic_cdk::api::time();
+    let cause = Cause::new(Reason::ListingPurchase, Some(Reference::Listing(order.listing_id)));
+
+    ledger::transfer_internal(order.buyer, Account::of(order.seller), seller_proceeds, cause);
+    if commission > 0 {
+        ledger::transfer_internal(order.buyer, ledger::treasury_account(), commission, cause);
+    }
+    journal::transfer(
+        cause,
+        JournalAccount::LockedAllowance(order.seller),
+        JournalAccount::Allowance(order.buyer),
+        order.units,
+    );
+
+    buyer.last_activity = now;
+    seller.spend_locked_allowance(order.units);
+
+    let receipt_buyer_allowance = buyer.carbon_allowance;
+
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        users_map.insert(order.buyer, buyer);
+        users_map.insert(order.seller, seller);
+    });
+
+    let transaction_id = next_id(&TRANSACTION_ID_COUNTER);
+    let transaction = Transaction {
+        id: transaction_id,
+        buyer: order.buyer,
+        seller: order.seller,
+        credit_id: order.listing_id,
+        amount: order.units as f64,
+        price_per_unit: order.price_per_unit.tokens_per_unit() as f64,
+        project_name: order.project_name,
+        transaction_type: "purchase".to_string(),
+        transaction_time: now,
+        quantity: Some(quantity),
+        price: Some(order.price_per_unit),
+        total_cost: Some(order.total_cost),
+    };
+
+    certified::certify_transaction(&transaction);
+    TRANSACTIONS.with(|transactions| {
+        transactions.borrow_mut().insert(transaction_id, transaction);
+    });
+
+    Ok(SettlementReceipt {
+        transaction_id,
+        listing_id: order.listing_id,
+        buyer: order.buyer,
+        seller: order.seller,
+        units: order.units,
+        total_cost: order.total_cost,
+        commission,
+        seller_proceeds,
+        buyer_token_balance: ledger::balance_of(order.buyer),
+        buyer_carbon_allowance: receipt_buyer_allowance,
+        settled_at: now,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserProfile;
+
+    fn register(principal: Principal, carbon_allowance: u64, carbon_locked: u64) {
+        let profile = UserProfile {
+            principal,
+            carbon_allowance,
+            carbon_emitted: 0,
+            tokens: 0,
+            has_subcontract: false,
+            username: None,
+            email: None,
+            full_name: None,
+            location: None,
+            join_date: 0,
+            last_activity: 0,
+            carbon_locked: Some(carbon_locked),
+            carbon_available: None,
+            carbon_emitted_remainder: None,
+        };
+        USERS.with(|users| users.borrow_mut().insert(principal, profile));
+    }
+
+    fn purchase(buyer: u8, seller: u8, units: u64, price: u64) -> Settlement {
+        Settlement {
+            listing_id: 1,
+            buyer: Principal::from_slice(&[buyer]),
+            seller: Principal::from_slice(&[seller]),
+            units,
+            price_per_unit: Price::new(price),
+            total_cost: units * price,
+            project_name: "test".to_string(),
+        }
+    }
+
+    fn allowance_of(principal: u8) -> (u64, u64) {
+        let profile = USERS
+            .with(|users| users.borrow().get(&Principal::from_slice(&[principal])))
+            .unwrap();
+        (profile.carbon_allowance, profile.locked_allowance())
+    }
+
+    #[test]
+    fn invalid_purchases_are_rejected() {
+        register(Principal::from_slice(&[1]), 0, 0);
+        register(Principal::from_slice(&[2]), 0, 10);
+
+        assert!(settle(purchase(2, 2, 5, 1)).is_err());
+        assert!(settle(purchase(1, 2, 0, 1)).is_err());
+        assert_eq!(settle(purchase(3, 2, 5, 1)).unwrap_err(), GreenGaugeError::NotRegistered);
+        assert!(settle(purchase(1, 3, 5, 1)).is_err());
+    }
+
+    #[test]
+    fn rejected_purchases_leave_balances_untouched() {
+        register(Principal::from_slice(&[1]), u64::MAX - 3, 0);
+        register(Principal::from_slice(&[2]), 0, 10);
+
+        assert_eq!(
+            settle(purchase(1, 2, 5, 1)).unwrap_err(),
+            GreenGaugeError::InsufficientTokens { required: 5, available: 0 }
+        );
+        // Free listings only need the seller's escrow
+        assert_eq!(
+            settle(purchase(1, 2, 11, 0)).unwrap_err(),
+            GreenGaugeError::InsufficientAllowance { required: 11, available: 10 }
+        );
+        // The buyer cannot hold more allowance than fits in a u64
+        assert!(settle(purchase(1, 2, 5, 0)).is_err());
+
+        assert_eq!(allowance_of(1), (u64::MAX - 3, 0));
+        assert_eq!(allowance_of(2), (0, 10));
+        assert!(TRANSACTIONS.with(|transactions| transactions.borrow().is_empty()));
+    }
+}
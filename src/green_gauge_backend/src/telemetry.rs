@@ -0,0 +1,285 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+use ic_cdk_macros::{query, update};
+use std::collections::BTreeMap;
+
+use crate::amount::{Co2e, Rounding};
+use crate::devices::{self, Device};
+use crate::error::GreenGaugeError;
+use crate::gases::{self, GasQuantity};
+use crate::ghg;
+use crate::journal::{self, Cause, JournalAccount, Reason, Reference};
+use crate::roles::caller_is_verifier;
+use crate::{
+    check_and_generate_alerts, ensure_registered, next_id, record_emission_history, user_range, DataPoint,
+    DATA_POINTS, DATA_POINT_ID_COUNTER, USERS,
+};
+
+// An encoded reading takes well under 200 bytes, so a full batch stays far
+// below the 2 MiB ingress message limit and the instruction limit of one call
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+// How far a device clock may run ahead of the canister's
+const MAX_CLOCK_DRIFT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DataPointInput {
+    device_id: String,
+    // In the device's meter unit
+    energy_consumption: f32,
+    // When the device took the reading, by its own clock
+    recorded_at: Option<u64>,
+    // Required when a device principal submits the reading, ignored otherwise
+    sequence: Option<u64>,
+    // Greenhouse gases measured directly, converted with the active GWP set
+    // and added to the emissions computed from `energy_consumption`
+    gases: Option<Vec<GasQuantity>>,
+}
+
+impl DataPointInput {
+    pub fn new(device_id: String, energy_consumption: f32, sequence: Option<u64>) -> Self {
+        DataPointInput {
+            device_id,
+            energy_consumption,
+            recorded_at: None,
+            sequence,
+            gases: None,
+        }
+    }
+
+    pub fn with_gases(self, gases: Option<Vec<GasQuantity>>) -> Self {
+        DataPointInput { gases, ..self }
+    }
+}
+
+// Who is submitting readings: the owner of the devices, or a device using its
+// delegated principal on the owner's behalf
+#[derive(Clone, Copy)]
+pub enum Submitter {
+    Owner(Principal),
+    Device(Principal),
+}
+
+fn validate_input(input: &DataPointInput, now: u64) -> Result<(), GreenGaugeError> {
+    if !input.energy_consumption.is_finite() || input.energy_consumption < 0.0 {
+        return Err(GreenGaugeError::invalid_input("energy_consumption", "must be a non-negative number"));
+    }
+
+    if let Some(gases) = &input.gases {
+        gases::validate_gases(gases)?;
+    }
+
+    if input.recorded_at.is_some_and(|recorded_at| recorded_at > now + MAX_CLOCK_DRIFT_NANOS) {
+        return Err(GreenGaugeError::invalid_input("recorded_at", "is in the future"));
+    }
+
+    Ok(())
+}
+
+// Store a batch of readings and return one result per input, in order.
+// Emissions are computed with the device's emission factor for the year the
+// reading was taken.
+//
+// Each device in the batch is charged one submission against its rate limit.
+// The owner's emissions, emission history and threshold alerts are updated
+// once for all accepted readings.
+pub fn ingest(
+    submitter: Submitter,
+    inputs: Vec<DataPointInput>,
+) -> Result<Vec<Result<u64, GreenGaugeError>>, GreenGaugeError> {
+    if inputs.len() > MAX_BATCH_SIZE {
+        return Err(GreenGaugeError::invalid_input(
+            "readings",
+            format!("at most {} readings fit into one batch", MAX_BATCH_SIZE),
+        ));
+    }
+
+    let (owner, device_principal, bound_device) = match submitter {
+        Submitter::Owner(owner) => (owner, None, None),
+        Submitter::Device(principal) => {
+            let (owner, device_id) =
+                devices::resolve_principal(principal).ok_or(GreenGaugeError::Unauthorized)?;
+            (owner, Some(principal), Some(device_id))
+        }
+    };
+    ensure_registered(owner)?;
+
+    let now = ic_cdk::api::time();
+    let gwp_set = gases::active_gwp_set();
+    let mut submissions: BTreeMap<String, Result<Device, GreenGaugeError>> = BTreeMap::new();
+    let mut accepted = Vec::new();
+
+    let results: Vec<Result<u64, GreenGaugeError>> = inputs
+        .into_iter()
+        .map(|input| {
+            validate_input(&input, now)?;
+
+            // Sequence numbers only order the readings a device signs itself
+            let sequence = bound_device.as_ref().and(input.sequence);
+            if let Some(bound_device) = &bound_device {
+                if input.device_id != *bound_device {
+                    return Err(GreenGaugeError::invalid_input(
+                        "device_id",
+                        "a device principal may only submit readings for its own device",
+                    ));
+                }
+                if sequence.is_none() {
+                    return Err(GreenGaugeError::invalid_input(
+                        "sequence",
+                        "is required for readings signed by a device",
+                    ));
+                }
+            }
+
+            let device = submissions
+                .entry(input.device_id.clone())
+                .or_insert_with(|| devices::start_submission(owner, &input.device_id, now))
+                .as_mut()
+                .map_err(|error| error.clone())?;
+            devices::check_recorded_at(device, input.recorded_at)?;
+            let emission_factor = devices::emission_factor(device, input.recorded_at.unwrap_or(now))?;
+            let classification = devices::classification(device)?;
+
+            let gases = input
+                .gases
+                .filter(|gases| !gases.is_empty())
+                .map(|gases| gases::to_co2e(&gases, gwp_set))
+                .transpose()?;
+            let carbon_emitted = emission_factor
+                .apply(input.energy_consumption)
+                .and_then(|energy| {
+                    Co2e::checked_sum(gases.iter().flatten().map(|gas| gas.co2e()))?.checked_add(energy)
+                })
+                .ok_or_else(|| {
+                    GreenGaugeError::invalid_input("energy_consumption", "emissions are too large to record")
+                })?;
+            devices::accept_sequence(device, sequence)?;
+
+            let data_point_id = next_id(&DATA_POINT_ID_COUNTER);
+            accepted.push(DataPoint {
+                id: data_point_id,
+                user_id: owner,
+                device_id: input.device_id,
+                energy_consumption: input.energy_consumption,
+                carbon_emitted: None,
+                timestamp: now,
+                device_principal,
+                sequence,
+                recorded_at: input.recorded_at,
+                emission_factor: Some(emission_factor),
+                classification: Some(classification),
+                gwp_set: gases.as_ref().map(|_| gwp_set),
+                gases,
+                carbon_emitted_grams: Some(carbon_emitted),
+            });
+            Ok(data_point_id)
+        })
+        .collect();
+
+    let mut total_emitted = Co2e::ZERO;
+    let mut emitted_by_class = Vec::new();
+    for point in &accepted {
+        let emitted = point.carbon_emitted_grams.unwrap_or(Co2e::ZERO);
+        total_emitted = total_emitted
+            .checked_add(emitted)
+            .ok_or_else(|| GreenGaugeError::overflow("carbon_emitted"))?;
+        if let Some(classification) = point.classification {
+            ghg::add_to(&mut emitted_by_class, classification, emitted);
+        }
+    }
+
+    // Allowance is counted in whole kg; the grams below that carry over to
+    // the next reading
+    let mut profile = USERS
+        .with(|users| users.borrow().get(&owner))
+        .ok_or(GreenGaugeError::NotRegistered)?;
+    let emitted = profile
+        .carbon_emitted_remainder
+        .unwrap_or(Co2e::ZERO)
+        .checked_add(total_emitted)
+        .ok_or_else(|| GreenGaugeError::overflow("carbon_emitted"))?;
+    let whole_kg = emitted.whole_kg(Rounding::Down);
+    let counted = Co2e::from_kg(whole_kg).expect("whole kg of a mass in grams fits");
+    profile.record_emitted(whole_kg)?;
+    profile.carbon_emitted_remainder = emitted.checked_sub(counted);
+    profile.last_activity = now;
+
+    // Nothing below this point can fail
+    for device in submissions.values().flatten() {
+        devices::finish_submission(device);
+    }
+
+    if accepted.is_empty() {
+        return Ok(results);
+    }
+    let peak_consumption = accepted.iter().map(|point| point.energy_consumption).fold(0.0, f32::max);
+    let peak_emission = accepted.iter().map(DataPoint::emitted).max().unwrap_or(Co2e::ZERO);
+
+    DATA_POINTS.with(|points| {
+        let mut points_map = points.borrow_mut();
+        for point in accepted {
+            journal::transfer(
+                Cause::new(Reason::EmissionRecorded, Some(Reference::DataPoint(point.id))),
+                JournalAccount::EmissionSource,
+                JournalAccount::Emissions(owner),
+                point.carbon_emitted_grams.unwrap_or(Co2e::ZERO).grams(),
+            );
+            points_map.insert((owner, point.id), point);
+        }
+    });
+
+    // Update user's carbon emission in profile
+    USERS.with(|users| users.borrow_mut().insert(owner, profile));
+
+    record_emission_history(owner, now, Co2e::ZERO, emitted_by_class);
+
+    // Thresholds apply to single readings, so the batch is judged by its peaks
+    check_and_generate_alerts(owner, peak_consumption, peak_emission);
+
+    Ok(results)
+}
+
+// Registered participants submit their own readings; any other caller must
+// be a device principal
+fn submitter_of(caller: Principal) -> Submitter {
+    let registered = USERS.with(|users| users.borrow().contains_key(&caller));
+    if !registered && devices::resolve_principal(caller).is_some() {
+        Submitter::Device(caller)
+    } else {
+        Submitter::Owner(caller)
+    }
+}
+
+// Store a single reading
+pub fn ingest_one(submitter: Submitter, input: DataPointInput) -> Result<u64, GreenGaugeError> {
+    ingest(submitter, vec![input])?
+        .pop()
+        .expect("ingest returns one result per reading")
+}
+
+// Add many readings in one call, from the devices' owner or from a device
+// principal. Returns the data point id or the rejection of every reading.
+// A registered participant always submits as an owner, even if its principal
+// is also bound to a device; owners' sequence numbers are ignored.
+#[update]
+fn add_data_points_batch(
+    readings: Vec<DataPointInput>,
+) -> Result<Vec<Result<u64, GreenGaugeError>>, GreenGaugeError> {
+    let caller = caller();
+    let submitter = submitter_of(caller);
+
+    ingest(submitter, readings)
+}
+
+// The readings a participant has submitted, optionally of one device only,
+// for verifiers checking them against the emissions reported (verifier only)
+#[query(guard = "caller_is_verifier")]
+fn get_participant_readings(participant: Principal, device_id: Option<String>) -> Vec<DataPoint> {
+    DATA_POINTS.with(|points| {
+        points
+            .borrow()
+            .values_range(user_range(participant))
+            .filter(|point| device_id.as_ref().is_none_or(|device_id| point.device_id == *device_id))
+            .collect()
+    })
+}
@@ -408,7 +408,11 @@ const CarbonDashboard = () => {
                   </div>
                   <div className="flex justify-between mb-4 py-2 border-b">
                     <span>Available Carbon:</span>
-                    <span className="font-semibold">{Number(userProfile.carbon_allowance) - Number(userProfile.carbon_emitted)} units</span>
+                    <span className="font-semibold">{Number(userProfile.carbon_available?.[0] ?? 0)} units</span>
+                  </div>
+                  <div className="flex justify-between mb-4 py-2 border-b">
+                    <span>Locked in Listings:</span>
+                    <span className="font-semibold">{Number(userProfile.carbon_locked?.[0] ?? 0)} units</span>
                   </div>
                   <div className="flex justify-between mb-4 py-2 border-b">
                     <span>Carbon Tokens:</span>
@@ -219,7 +219,8 @@ const Dashboard = ({ isAuthenticated, setIsAuthenticated }) => {
             <div>
               <p className="mb-2"><span className="text-gray-600">Carbon Allowance:</span> {Number(userProfile.carbon_allowance)} units</p>
               <p className="mb-2"><span className="text-gray-600">Carbon Emitted:</span> {Number(userProfile.carbon_emitted)} units</p>
-              <p className="mb-2"><span className="text-gray-600">Available Carbon:</span> {Number(userProfile.carbon_allowance) - Number(userProfile.carbon_emitted)} units</p>
+              <p className="mb-2"><span className="text-gray-600">Available Carbon:</span> {Number(userProfile.carbon_available?.[0] ?? 0)} units</p>
+              <p className="mb-2"><span className="text-gray-600">Locked in Listings:</span> {Number(userProfile.carbon_locked?.[0] ?? 0)} units</p>
               <p className="mb-2"><span className="text-gray-600">Carbon Tokens:</span> {Number(userProfile.tokens)}</p>
               <button 
                 onClick={() => navigate('/carbon')}
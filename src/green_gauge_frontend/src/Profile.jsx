@@ -279,7 +279,8 @@ function Profile({ isAuthenticated, setIsAuthenticated }) {
                     <h3 className="font-semibold text-blue-800 mb-2">Carbon Stats</h3>
                     <p className="mb-2"><span className="text-gray-600 font-semibold">Carbon Allowance:</span> {Number(userProfile.carbon_allowance)} units</p>
                     <p className="mb-2"><span className="text-gray-600 font-semibold">Carbon Emitted:</span> {Number(userProfile.carbon_emitted)} units</p>
-                    <p className="mb-2"><span className="text-gray-600 font-semibold">Available Carbon:</span> {Number(userProfile.carbon_allowance) - Number(userProfile.carbon_emitted)} units</p>
+                    <p className="mb-2"><span className="text-gray-600 font-semibold">Available Carbon:</span> {Number(userProfile.carbon_available?.[0] ?? 0)} units</p>
+                    <p className="mb-2"><span className="text-gray-600 font-semibold">Locked in Listings:</span> {Number(userProfile.carbon_locked?.[0] ?? 0)} units</p>
                   </div>
                 </div>
                 
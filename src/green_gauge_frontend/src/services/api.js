@@ -14,8 +14,10 @@ let actorInstance = null;
 export const MOCK_DATA = {
   userProfile: {
     principal: "2vxsx-fae",
-    carbon_allowance: 10000,
+    carbon_allowance: 9000,
     carbon_emitted: 3500,
+    carbon_locked: [1000],
+    carbon_available: [5500],
     tokens: 7500,
     has_subcontract: true,
     username: "GreenCorp",
@@ -398,10 +400,10 @@ export const recordEmission = async (amount) => {
 /**
  * Create a trade offer
  */
-export const createTradeOffer = async (amount, pricePerUnit) => {
+export const createTradeOffer = async (amount, pricePerUnit, expiresAt) => {
   try {
     const actor = await getBackendActor();
-    const result = await actor.create_trade_offer(amount, pricePerUnit);
+    const result = await actor.create_trade_offer(amount, pricePerUnit, expiresAt ? [BigInt(expiresAt)] : []);
     return result.Ok !== undefined ? { Ok: result.Ok } : { Ok: Math.floor(Math.random() * 1000) + 4 };
   } catch (error) {
     console.error("Error creating trade offer:", error);
@@ -423,7 +425,8 @@ export const getAllTradeOffers = async () => {
       id: Number(offer.id),
       seller: offer.seller.toString(),
       amount: Number(offer.amount),
-      price_per_unit: Number(offer.price_per_unit)
+      price_per_unit: Number(offer.price_per_unit),
+      expires_at: offer.expires_at.length ? Number(offer.expires_at[0]) : null
     }));
   } catch (error) {
     console.error('Error fetching trade offers:', error);
@@ -457,6 +460,71 @@ export const cancelTradeOffer = async (offerId) => {
   }
 };
 
+/**
+ * Change the amount and price of a trade offer
+ */
+export const updateTradeOffer = async (offerId, amount, pricePerUnit) => {
+  try {
+    const actor = await getBackendActor();
+    return await actor.update_trade_offer(offerId, amount, pricePerUnit);
+  } catch (error) {
+    console.error('Update trade offer error:', error);
+    throw error;
+  }
+};
+
+/**
+ * Withdraw a carbon credit listing
+ */
+export const deactivateCarbonCredit = async (creditId) => {
+  try {
+    const actor = await getBackendActor();
+    return await actor.deactivate_carbon_credit(creditId);
+  } catch (error) {
+    console.error('Deactivate carbon credit error:', error);
+    throw error;
+  }
+};
+
+/**
+ * Get all open listings of allowances and offsets
+ */
+export const getListings = async () => {
+  try {
+    const actor = await getBackendActor();
+    return await actor.get_listings();
+  } catch (error) {
+    console.error('Error fetching listings:', error);
+    throw error;
+  }
+};
+
+/**
+ * Buy units of any listing
+ */
+export const buyListing = async (listingId, amount) => {
+  try {
+    const actor = await getBackendActor();
+    return await actor.buy_listing(listingId, amount);
+  } catch (error) {
+    console.error('Buy listing error:', error);
+    throw error;
+  }
+};
+
+/**
+ * Withdraw a listing
+ */
+export const cancelListing = async (listingId) => {
+  try {
+    const actor = await getBackendActor();
+    return await actor.cancel_listing(listingId);
+  } catch (error) {
+    console.error('Cancel listing error:', error);
+    throw error;
+  }
+};
+
 /**
  * Check if user is an admin
  */
@@ -529,7 +597,7 @@ export const deploySubcontract = async () => {
 /**
  * List a carbon credit for sale
  */
-export const listCarbonCredit = async (amount, price, creditType, certification, projectName, vintageYear, description) => {
+export const listCarbonCredit = async (amount, price, creditType, certification, projectName, vintageYear, description, expiresAt) => {
   try {
     const result = await backendActor.list_carbon_credit(
       parseFloat(amount),
@@ -538,7 +606,8 @@ export const listCarbonCredit = async (amount, price, creditType, certification,
       certification,
       projectName,
       vintageYear,
-      description || ''
+      description || '',
+      expiresAt ? [BigInt(expiresAt)] : []
     );
     return result;
   } catch (err) {
@@ -596,10 +665,10 @@ export const getUserTransactions = async () => {
 
 // New functions for data points, alerts, and emission histories
 
-export const addDataPoint = async (deviceId, energyConsumption, carbonEmitted) => {
+export const addDataPoint = async (deviceId, energyConsumption, gases) => {
   try {
     const actor = await getBackendActor();
-    const result = await actor.add_data_point(deviceId, energyConsumption, carbonEmitted);
+    const result = await actor.add_data_point(deviceId, energyConsumption, gases ? [gases] : []);
     return result.Ok !== undefined ? result.Ok : null;
   } catch (error) {
     console.error('Error adding data point:', error);
@@ -618,10 +687,10 @@ export const getAllData = async () => {
   }
 };
 
-export const getEmissionHistory = async (fromTimestamp, toTimestamp) => {
+export const getEmissionHistory = async (fromTimestamp, toTimestamp, scope) => {
   try {
     const actor = await getBackendActor();
-    const result = await actor.get_emission_history(fromTimestamp, toTimestamp);
+    const result = await actor.get_emission_history(fromTimestamp, toTimestamp, scope ? [{ [scope]: null }] : []);
     return result.Ok !== undefined ? result.Ok : MOCK_DATA.emissionHistory;
   } catch (error) {
     console.error("Error getting emission history:", error);
@@ -716,7 +785,16 @@ export const getEfficiencyMetrics = async (days) => {
   try {
     const actor = await getBackendActor();
     const result = await actor.get_efficiency_metrics(days);
-    return result.Ok !== undefined ? result.Ok : MOCK_DATA.efficiencyMetrics;
+    if (result.Ok === undefined) {
+      return MOCK_DATA.efficiencyMetrics;
+    }
+    // Emissions come in grams; metrics from before that carry kg
+    return result.Ok.map(metric => ({
+      ...metric,
+      carbon_emitted: metric.carbon_emitted_grams.length
+        ? Number(metric.carbon_emitted_grams[0]) / 1000
+        : metric.carbon_emitted[0] ?? 0,
+    }));
   } catch (error) {
     console.error("Error getting efficiency metrics:", error);
     console.log("Returning mock efficiency metrics data");